@@ -0,0 +1,75 @@
+//Time per frame marching one ray at a time with Scene::march, and in packets with Scene::march_rays.
+//Run with cargo bench --bench packet_march
+
+extern crate terminal_raymarcher;
+use terminal_raymarcher::engine::{
+    distance_field::SDF,
+    scene::Scene,
+    packet,
+};
+
+use std::time::{Duration, Instant};
+
+const TERM_SIZE: (u16, u16) = (160, 48);
+
+//A few rows of the primitives that have a packet version, standing on a plane
+fn build_scene(count: usize) -> Scene {
+    let mut scene = Scene::new();
+    scene.push_sdf(SDF::new_plane(-1.0, [255, 255, 255]));
+    for i in 0..count {
+        let position = [(i % 6) as f32 * 1.5 - 3.75, -0.4, (i / 6) as f32 * 1.5 + 3.0];
+        let rotation = [i as f32 * 20.0, i as f32 * 35.0, 0.0];
+        scene.push_sdf(match i % 4 {
+            0 => SDF::new_sphere(position, 0.5, [255, 0, 0]),
+            1 => SDF::new_round_box(position, 0.4, 0.4, 0.1, [0, 255, 0], rotation),
+            2 => SDF::new_torus(position, [0.4, 0.15], [0, 0, 255], rotation),
+            _ => SDF::new_capsule(position, 0.2, 0.3, [255, 255, 0], rotation),
+        });
+    }
+    scene
+}
+
+fn rays(scene: &Scene) -> Vec<terminal_raymarcher::rendering::raymarching::Ray> {
+    (0..TERM_SIZE.0 as usize * TERM_SIZE.1 as usize)
+        .map(|i| scene.generate_ray(TERM_SIZE, (i % TERM_SIZE.0 as usize) as u16, (i / TERM_SIZE.0 as usize) as u16))
+        .collect()
+}
+
+fn scalar(scene: &Scene) -> Duration {
+    let start = Instant::now();
+    for ray in rays(scene) {
+        std::hint::black_box(scene.march(ray));
+    }
+    start.elapsed()
+}
+
+fn packets(scene: &Scene) -> Duration {
+    let start = Instant::now();
+    std::hint::black_box(scene.march_rays(&rays(scene)));
+    start.elapsed()
+}
+
+fn best_of(runs: usize, f: impl Fn() -> Duration) -> Duration {
+    (0..runs).map(|_| f()).min().unwrap()
+}
+
+fn main() {
+    println!("{} lanes", packet::lane_count());
+    println!("{:>8} {:>10} {:>12} {:>12} {:>8}", "objects", "shading", "scalar ms", "packet ms", "speedup");
+    for &count in &[6, 24] {
+        for &shaded in &[false, true] {
+            let mut scene = build_scene(count);
+            //Without ambient occlusion and bounces nearly all the time goes to the primary rays
+            if !shaded {
+                scene.ao_samples = 0;
+                scene.max_bounces = 0;
+            }
+
+            let scalar = best_of(3, || scalar(&scene));
+            let packets = best_of(3, || packets(&scene));
+            println!("{:>8} {:>10} {:>12.2} {:>12.2} {:>7.2}x", count, if shaded { "full" } else { "primary" },
+                scalar.as_secs_f64() * 1000.0, packets.as_secs_f64() * 1000.0,
+                scalar.as_secs_f64() / packets.as_secs_f64());
+        }
+    }
+}
@@ -0,0 +1,56 @@
+//Time per frame with and without the BVH, for scenes from 3 to 10,000 objects.
+//Run with cargo bench --bench scene_scaling
+
+extern crate terminal_raymarcher;
+use terminal_raymarcher::engine::{
+    distance_field::SDF,
+    scene::Scene,
+};
+
+use std::time::{Duration, Instant};
+
+const TERM_SIZE: (u16, u16) = (80, 24);
+
+//Spheres on a grid in front of the camera, filling a square as wide as the count allows
+fn build_scene(count: usize) -> Scene {
+    let mut scene = Scene::new();
+    scene.ao_samples = 0;
+    scene.max_bounces = 0;
+    scene.push_sdf(SDF::new_plane(-1.0, [255, 255, 255]));
+
+    let side = ((count - 1) as f32).sqrt().ceil() as usize;
+    let spacing = 40.0 / side as f32;
+    for i in 0..count - 1 {
+        let x = (i % side) as f32 * spacing - 20.0;
+        let z = (i / side) as f32 * spacing + 3.0;
+        scene.push_sdf(SDF::new_sphere([x, 0.0, z], spacing * 0.3, [255, 0, 0]));
+    }
+    scene
+}
+
+fn render(scene: &Scene) -> Duration {
+    let start = Instant::now();
+    for y in 0..TERM_SIZE.1 {
+        for x in 0..TERM_SIZE.0 {
+            let ray = scene.generate_ray(TERM_SIZE, x, y);
+            std::hint::black_box(scene.march(ray));
+        }
+    }
+    start.elapsed()
+}
+
+fn main() {
+    println!("{:>8} {:>12} {:>12} {:>8}", "objects", "linear ms", "bvh ms", "speedup");
+    for &count in &[3, 10, 100, 1_000, 10_000] {
+        let mut scene = build_scene(count);
+        let linear = render(&scene);
+
+        scene.build_bvh();
+        //Best of a few frames, the BVH frames are short enough to be noisy
+        let bvh = (0..5).map(|_| render(&scene)).min().unwrap();
+
+        println!("{:>8} {:>12.2} {:>12.2} {:>7.1}x", count,
+            linear.as_secs_f64() * 1000.0, bvh.as_secs_f64() * 1000.0,
+            linear.as_secs_f64() / bvh.as_secs_f64());
+    }
+}
@@ -0,0 +1,346 @@
+extern crate vecmath as vmath;
+use vmath::{
+    Vector3,
+};
+
+use super::distance_field::SDF;
+use super::packet::{self, Lanes, Lanes3, Mask};
+
+//Smallest and largest corner of an axis aligned box
+pub type Bounds = (Vector3<f32>, Vector3<f32>);
+
+const LEAF_SIZE: usize = 4;
+//Points further than this outside a box only get the distance to the box. It's a lower bound,
+//so the marcher stays correct, and it skips evaluating what's inside while the ray is far away.
+pub const FAR_BOUNDS_DISTANCE: f32 = 1.0;
+
+pub fn vec_min(a: Vector3<f32>, b: Vector3<f32>) -> Vector3<f32> {
+    [a[0].min(b[0]), a[1].min(b[1]), a[2].min(b[2])]
+}
+
+pub fn vec_max(a: Vector3<f32>, b: Vector3<f32>) -> Vector3<f32> {
+    [a[0].max(b[0]), a[1].max(b[1]), a[2].max(b[2])]
+}
+
+//Distance from p to an axis aligned box, 0 inside it
+fn box_distance(min: Vector3<f32>, max: Vector3<f32>, p: Vector3<f32>) -> f32 {
+    let mut d = 0.0;
+    for i in 0..3 {
+        let v = (min[i] - p[i]).max(0.0).max(p[i] - max[i]);
+        d += v * v;
+    }
+    d.sqrt()
+}
+
+#[inline(always)]
+fn box_distance_packet<const N: usize>(min: Vector3<f32>, max: Vector3<f32>, p: &Lanes3<N>) -> Lanes<N> {
+    let zero = Lanes::splat(0.0);
+    let v: [Lanes<N>; 3] = std::array::from_fn(|i| (Lanes::splat(min[i]) - p[i]).max(zero).max(p[i] - max[i]));
+    (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt()
+}
+
+#[derive(Clone)]
+pub struct BvhNode {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+    //Leaves point at a range of items, inner nodes at their two children
+    pub left: usize,
+    pub right: usize,
+    pub first: usize,
+    pub count: usize,
+}
+
+//Median split tree over items and their boxes, used for the objects of a scene and the triangles
+//of a mesh. Reorders items so every leaf covers a contiguous range of them. The root comes first,
+//and children always come after their parent.
+pub fn build_tree(items: &mut [(usize, Bounds)]) -> Vec<BvhNode> {
+    let mut nodes = Vec::new();
+    if !items.is_empty() {
+        let count = items.len();
+        build_node(items, &mut nodes, 0, count);
+    }
+    nodes
+}
+
+//Returns the index of the node it created
+fn build_node(items: &mut [(usize, Bounds)], nodes: &mut Vec<BvhNode>, first: usize, count: usize) -> usize {
+    let slice = &mut items[first..first + count];
+    let mut min = [f32::MAX; 3];
+    let mut max = [f32::MIN; 3];
+    for (_, (lo, hi)) in slice.iter() {
+        min = vec_min(min, *lo);
+        max = vec_max(max, *hi);
+    }
+
+    let idx = nodes.len();
+    nodes.push(BvhNode { min, max, left: 0, right: 0, first, count });
+    if count <= LEAF_SIZE {
+        return idx;
+    }
+
+    //Median split along the longest axis
+    let extent = vmath::vec3_sub(max, min);
+    let axis = if extent[0] > extent[1] && extent[0] > extent[2] { 0 } else if extent[1] > extent[2] { 1 } else { 2 };
+    let centre = |b: &Bounds| b.0[axis] + b.1[axis];
+    slice.sort_by(|a, b| centre(&a.1).partial_cmp(&centre(&b.1)).unwrap_or(std::cmp::Ordering::Equal));
+
+    let half = count / 2;
+    let left = build_node(items, nodes, first, half);
+    let right = build_node(items, nodes, first + half, count - half);
+    nodes[idx].left = left;
+    nodes[idx].right = right;
+    nodes[idx].count = 0;
+    idx
+}
+
+//Bounding volume hierarchy over the objects of a scene, so distance queries only
+//evaluate the objects close to the point instead of all of them
+#[derive(Clone)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    //Object indices and their boxes, ordered so every leaf covers a contiguous range
+    objects: Vec<(usize, Bounds)>,
+    //Objects without bounds, like planes, are always evaluated
+    unbounded: Vec<usize>,
+    //How many objects the scene had when this was built
+    pub object_count: usize,
+}
+
+impl Bvh {
+    pub fn new(distance_fields: &[SDF]) -> Bvh {
+        let mut bounded = Vec::new();
+        let mut unbounded = Vec::new();
+        for (i, sdf) in distance_fields.iter().enumerate() {
+            match sdf.get_bounds() {
+                Some(bounds) => bounded.push((i, bounds)),
+                None => unbounded.push(i),
+            }
+        }
+
+        Bvh {
+            nodes: build_tree(&mut bounded),
+            objects: bounded,
+            unbounded,
+            object_count: distance_fields.len(),
+        }
+    }
+
+    //Updates the boxes after objects moved, rotated or got scaled, without changing the tree.
+    //Much cheaper than building a new one, but the tree gets less effective if objects move far.
+    //Returns false if an object lost its bounds and the tree has to be built again.
+    pub fn refit(&mut self, distance_fields: &[SDF]) -> bool {
+        //Children are always added after their parent, so going backwards handles them first
+        for idx in (0..self.nodes.len()).rev() {
+            let node = &self.nodes[idx];
+            let (mut min, mut max) = ([f32::MAX; 3], [f32::MIN; 3]);
+            if node.count > 0 {
+                let (first, count) = (node.first, node.count);
+                for (object, bounds) in &mut self.objects[first..first + count] {
+                    match distance_fields[*object].get_bounds() {
+                        Some((lo, hi)) => {
+                            *bounds = (lo, hi);
+                            min = vec_min(min, lo);
+                            max = vec_max(max, hi);
+                        },
+                        None => return false,
+                    }
+                }
+            } else {
+                let (l, r) = (&self.nodes[node.left], &self.nodes[node.right]);
+                min = vec_min(l.min, r.min);
+                max = vec_max(l.max, r.max);
+            }
+            self.nodes[idx].min = min;
+            self.nodes[idx].max = max;
+        }
+        true
+    }
+
+    //Same as the linear search in Scene::get_distance, but skips every object whose box is
+    //further away than the closest distance found so far
+    pub fn get_distance(&self, distance_fields: &[SDF], position: Vector3<f32>) -> (f32, i32) {
+        let mut closest_distance = 4096.0;
+        let mut closest_idx = -1;
+        for &i in &self.unbounded {
+            let dist = distance_fields[i].get_distance(position);
+            if dist < closest_distance {
+                closest_distance = dist;
+                closest_idx = i as i32;
+            }
+        }
+
+        if self.nodes.is_empty() {
+            return (closest_distance, closest_idx);
+        }
+
+        //Runs for every step of every ray, so avoid allocating. Each level adds at most one
+        //node to the stack, and a median split tree of usize objects can't get 64 levels deep
+        let mut stack = [0usize; 64];
+        let mut stack_len = 1;
+        while stack_len > 0 {
+            stack_len -= 1;
+            let node = &self.nodes[stack[stack_len]];
+            if box_distance(node.min, node.max, position) >= closest_distance {
+                continue;
+            }
+            if node.count > 0 {
+                for &(i, (lo, hi)) in &self.objects[node.first..node.first + node.count] {
+                    let bounds_distance = box_distance(lo, hi, position);
+                    let dist = if bounds_distance > FAR_BOUNDS_DISTANCE { bounds_distance } else { distance_fields[i].get_distance(position) };
+                    if dist < closest_distance {
+                        closest_distance = dist;
+                        closest_idx = i as i32;
+                    }
+                }
+            } else {
+                //Visit the closer child first, so the far one is more likely to get skipped
+                let (l, r) = (&self.nodes[node.left], &self.nodes[node.right]);
+                let (near, far) = if box_distance(l.min, l.max, position) < box_distance(r.min, r.max, position) {
+                    (node.left, node.right)
+                } else {
+                    (node.right, node.left)
+                };
+                stack[stack_len] = far;
+                stack[stack_len + 1] = near;
+                stack_len += 2;
+            }
+        }
+
+        (closest_distance, closest_idx)
+    }
+
+    //get_distance for a packet of points. Nodes and objects are only skipped when no active lane
+    //could get closer, so the packet goes as deep as its most demanding lane
+    #[inline(always)]
+    pub fn get_distance_packet<const N: usize>(&self, distance_fields: &[SDF], positions: &Lanes3<N>, active: Mask<N>) -> (Lanes<N>, [i32; N]) {
+        let mut closest_distance = Lanes::splat(4096.0);
+        let mut closest_idx = [-1; N];
+        for &i in &self.unbounded {
+            let dist = distance_fields[i].get_distance_packet(positions, active);
+            packet::keep_closest(&mut closest_distance, &mut closest_idx, dist, i);
+        }
+
+        if self.nodes.is_empty() {
+            return (closest_distance, closest_idx);
+        }
+
+        let far_bounds = Lanes::splat(FAR_BOUNDS_DISTANCE);
+        let mut stack = [0usize; 64];
+        let mut stack_len = 1;
+        while stack_len > 0 {
+            stack_len -= 1;
+            let node = &self.nodes[stack[stack_len]];
+            if !box_distance_packet(node.min, node.max, positions).lt(closest_distance).and(active).any() {
+                continue;
+            }
+            if node.count > 0 {
+                for &(i, (lo, hi)) in &self.objects[node.first..node.first + node.count] {
+                    let bounds_distance = box_distance_packet(lo, hi, positions);
+                    if !bounds_distance.lt(closest_distance).and(active).any() {
+                        continue;
+                    }
+                    let far = far_bounds.lt(bounds_distance);
+                    let near = (!far).and(active);
+                    let dist = if near.any() {
+                        far.select(bounds_distance, distance_fields[i].get_distance_packet(positions, near))
+                    } else {
+                        bounds_distance
+                    };
+                    packet::keep_closest(&mut closest_distance, &mut closest_idx, dist, i);
+                }
+            } else {
+                let (l, r) = (&self.nodes[node.left], &self.nodes[node.right]);
+                let (near, far) = if box_distance_packet(l.min, l.max, positions).reduce_min() < box_distance_packet(r.min, r.max, positions).reduce_min() {
+                    (node.left, node.right)
+                } else {
+                    (node.right, node.left)
+                };
+                stack[stack_len] = far;
+                stack[stack_len + 1] = near;
+                stack_len += 2;
+            }
+        }
+
+        (closest_distance, closest_idx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::modifier::Modifier;
+
+    //Spheres and boxes spread over a grid, with a plane under them
+    fn objects(twist: bool) -> Vec<SDF> {
+        let mut objects = vec![SDF::new_plane(-1.0, [255, 255, 255])];
+        for i in 0..200 {
+            let position = [(i % 10) as f32 * 3.0, (i / 100) as f32 * 3.0, ((i / 10) % 10) as f32 * 3.0];
+            if i % 2 == 0 {
+                objects.push(SDF::new_sphere(position, 1.0, [255, 0, 0]));
+            } else {
+                let mut sdf = SDF::new_round_box(position, 1.0, 0.5, 0.1, [0, 255, 0], [i as f32 * 7.0, i as f32 * 13.0, 0.0]);
+                if twist {
+                    sdf.add_modifier(Modifier::Twist { amount: 0.5 });
+                }
+                objects.push(sdf);
+            }
+        }
+        objects
+    }
+
+    fn linear(objects: &[SDF], p: Vector3<f32>) -> (f32, i32) {
+        let mut best = (4096.0, -1);
+        for (i, sdf) in objects.iter().enumerate() {
+            let d = sdf.get_distance(p);
+            if d < best.0 {
+                best = (d, i as i32);
+            }
+        }
+        best
+    }
+
+    #[test]
+    fn matches_linear_search() {
+        //Only exact distances here, twisting underestimates and the box distance can be closer to the truth
+        let objects = objects(false);
+        let bvh = Bvh::new(&objects);
+        for i in 0..2000 {
+            let t = i as f32;
+            let p = [(t * 0.37).sin() * 16.0 + 14.0, (t * 0.53).cos() * 4.0 + 2.0, (t * 0.71).sin() * 16.0 + 14.0];
+            let (expected, expected_idx) = linear(&objects, p);
+            let (d, idx) = bvh.get_distance(&objects, p);
+            //Never further than the real closest object, and exact close to surfaces where it matters
+            assert!(d <= expected + 1e-5, "{} > {} at {:?}", d, expected, p);
+            if expected < FAR_BOUNDS_DISTANCE {
+                assert_eq!((d, idx), (expected, expected_idx));
+            }
+        }
+    }
+
+    #[test]
+    fn bounds_contain_the_surface() {
+        for sdf in objects(true).iter().skip(1) {
+            let (lo, hi) = sdf.get_bounds().unwrap();
+            //Points just outside the box are never inside the object
+            for corner in 0..8 {
+                let p = [
+                    if corner & 1 == 0 { lo[0] - 0.01 } else { hi[0] + 0.01 },
+                    if corner & 2 == 0 { lo[1] - 0.01 } else { hi[1] + 0.01 },
+                    (lo[2] + hi[2]) * 0.5,
+                ];
+                assert!(sdf.get_distance(p) > 0.0);
+            }
+            assert!(sdf.get_distance(sdf.position) < 0.0);
+        }
+        assert!(SDF::new_plane(0.0, [0, 0, 0]).get_bounds().is_none());
+    }
+
+    #[test]
+    fn refit() {
+        let mut objects = objects(false);
+        let mut bvh = Bvh::new(&objects);
+        objects[1].position = [100.0, 0.0, 0.0];
+        assert!(bvh.refit(&objects));
+        assert_eq!(bvh.get_distance(&objects, [101.5, 0.0, 0.0]), (0.5, 1));
+    }
+}
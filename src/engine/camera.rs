@@ -3,7 +3,7 @@ use vmath::{
     Vector3,
 };
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub struct Camera {
     pub eye: Vector3<f32>,
     pub yaw: f32,
@@ -18,4 +18,27 @@ impl Camera {
             roll: roll,
         }
     }
+
+    pub fn yaw_radians(&self) -> f32 {
+        self.yaw.to_radians()
+    }
+
+    //Where a world position ends up on a screen of term_size, the inverse of Scene::generate_ray_at.
+    //None for positions behind the camera.
+    pub fn project(&self, term_size: (u16, u16), position: Vector3<f32>) -> Option<(f32, f32)> {
+        let v = vmath::vec3_sub(position, self.eye);
+        //Undo the yaw
+        let r = self.yaw_radians();
+        let (a, b) = (-v[0], v[2]);
+        let d = [a * r.cos() + b * r.sin(), v[1], b * r.cos() - a * r.sin()];
+        if d[2] <= 1e-6 {
+            return None;
+        }
+
+        //Rays leave the camera through a plane at z = 2, with x squashed to half the width
+        let k = 2.0 / d[2];
+        let p = (d[0] * k * 2.0, d[1] * k);
+        let (w, h) = (term_size.0 as f32, term_size.1 as f32);
+        Some((w - (p.0 * h + w) * 0.5, h - (p.1 * h + h) * 0.5))
+    }
 }
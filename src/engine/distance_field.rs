@@ -1,9 +1,20 @@
 extern crate vecmath as vmath;
-use crate::engine::rotation::get_rotation_matrix;
+use crate::engine::rotation::{get_rotation_matrix, get_rotation_angles};
+use crate::engine::material::Material;
+use crate::engine::texture::TextureSpace;
+use crate::engine::modifier::Modifier;
+use crate::engine::mesh::Mesh;
+use crate::engine::volume::Volume;
+use crate::engine::heightfield::Heightfield;
+use crate::engine::fractal::{self, Quality};
+use crate::engine::expression::{Expression, ExpressionError};
+use crate::engine::packet::{self, Lanes, Lanes3, Mask};
 use vmath::{
     Vector2, Vector3, Matrix3,
 };
 
+use std::sync::Arc;
+
 fn abs(x: f32) -> f32 {
     if x >= 0.0 {
         return x;
@@ -22,23 +33,137 @@ fn max(x: f32, a: f32) -> f32 {
     return x;
 }
 
-#[derive(Copy, Clone)]
+fn sign(x: f32) -> f32 {
+    if x < 0.0 { -1.0 } else { 1.0 }
+}
+
+fn clamp(x: f32, a: f32, b: f32) -> f32 {
+    min(max(x, a), b)
+}
+
+fn dot2(a: Vector2<f32>, b: Vector2<f32>) -> f32 {
+    a[0] * b[0] + a[1] * b[1]
+}
+
+//Scales are clamped to at least this, a zero, negative or nan scale would break the distance
+const MIN_SCALE: f32 = 0.001;
+
+//NaN fails the comparison, so it ends up at the minimum too
+fn positive_scale(scale: f32) -> f32 {
+    if scale >= MIN_SCALE { scale } else { MIN_SCALE }
+}
+
+//Same conversion update_rotation uses, so constructors and updates agree
+fn rotation_from_degrees(rotation: Vector3<f32>) -> Matrix3<f32> {
+    let to_radians = std::f32::consts::PI / 180.0;
+    get_rotation_matrix([rotation[0] * to_radians, rotation[1] * to_radians, rotation[2] * to_radians])
+}
+
+//Most of the distance functions are adapted from https://iquilezles.org/articles/distfunctions/
+//What SDF::size holds depends on the type, see the constructors.
+#[allow(non_camel_case_types)]
+#[derive(Clone)]
 pub enum SDF_Type {
     SDF_Sphere,
     SDF_Box,
+    SDF_RoundBox,
     SDF_Torus,
+    SDF_CappedTorus,
+    SDF_Link,
     SDF_Plane,
+    SDF_Capsule,
+    SDF_Cylinder,
+    SDF_CappedCylinder,
+    SDF_Cone,
+    SDF_Ellipsoid,
+    SDF_Octahedron,
+    SDF_HexPrism,
+    SDF_TriPrism,
+    SDF_Pyramid,
+    //Shared, so cloning the scene for every render thread doesn't copy the triangles
+    SDF_Mesh(Arc<Mesh>),
+    SDF_Volume(Arc<Volume>),
+    SDF_Heightfield(Arc<Heightfield>),
+    //Iteration counts come from the quality setting, see set_quality
+    SDF_Mandelbulb { power: f32, iterations: u32 },
+    SDF_MengerSponge { iterations: u32 },
+    SDF_Sierpinski { iterations: u32 },
+    //Anything outside this crate, see DistanceField
+    SDF_Custom(Arc<dyn DistanceField>),
 }
 
-#[derive(Copy, Clone)]
+impl SDF_Type {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SDF_Type::SDF_Sphere => "sphere",
+            SDF_Type::SDF_Box => "box",
+            SDF_Type::SDF_RoundBox => "round box",
+            SDF_Type::SDF_Torus => "torus",
+            SDF_Type::SDF_CappedTorus => "capped torus",
+            SDF_Type::SDF_Link => "link",
+            SDF_Type::SDF_Plane => "plane",
+            SDF_Type::SDF_Capsule => "capsule",
+            SDF_Type::SDF_Cylinder => "cylinder",
+            SDF_Type::SDF_CappedCylinder => "capped cylinder",
+            SDF_Type::SDF_Cone => "cone",
+            SDF_Type::SDF_Ellipsoid => "ellipsoid",
+            SDF_Type::SDF_Octahedron => "octahedron",
+            SDF_Type::SDF_HexPrism => "hex prism",
+            SDF_Type::SDF_TriPrism => "tri prism",
+            SDF_Type::SDF_Pyramid => "pyramid",
+            SDF_Type::SDF_Mesh(_) => "mesh",
+            SDF_Type::SDF_Volume(_) => "volume",
+            SDF_Type::SDF_Heightfield(_) => "heightfield",
+            SDF_Type::SDF_Mandelbulb { .. } => "mandelbulb",
+            SDF_Type::SDF_MengerSponge { .. } => "menger sponge",
+            SDF_Type::SDF_Sierpinski { .. } => "sierpinski",
+            SDF_Type::SDF_Custom(_) => "custom",
+        }
+    }
+}
+
+//Implement this to add shapes of your own. Everything works in the local space of the SDF
+//holding it, so position, rotation, scale and modifiers still apply on top.
+//Send + Sync because the scene is shared between the render threads.
+pub trait DistanceField: Send + Sync {
+    //Should never be larger than the real distance to the surface, negative inside
+    fn distance(&self, p: Vector3<f32>) -> f32;
+
+    //Smallest and largest corner of a box around the shape, None if it's unbounded
+    fn bounds(&self) -> Option<(Vector3<f32>, Vector3<f32>)> {
+        None
+    }
+
+    //Material at a point on the surface, None uses the material of the SDF
+    fn material(&self, _p: Vector3<f32>) -> Option<Material> {
+        None
+    }
+
+    //The expression the field was parsed from, so scene files can save it
+    fn expression(&self) -> Option<&Expression> {
+        None
+    }
+}
+
+//Lets a plain closure be used as a shape
+impl<F: Fn(Vector3<f32>) -> f32 + Send + Sync> DistanceField for F {
+    fn distance(&self, p: Vector3<f32>) -> f32 {
+        self(p)
+    }
+}
+
+#[derive(Clone)]
 pub struct SDF {
     pub position: Vector3<f32>,
     pub size: Vector3<f32>,
     pub rotation: Option<Matrix3<f32>>,
+    pub scale: Vector3<f32>, //Applied after the shape is built from size, keep every axis above 0
 
     pub sdf_type: SDF_Type,
+    pub modifiers: Vec<Modifier>,
 
     pub colour: Vector3<u8>,
+    pub material: Material,
 }
 
 impl SDF {
@@ -48,17 +173,24 @@ impl SDF {
             size: [radius, radius, radius],
             sdf_type: SDF_Type::SDF_Sphere,
             colour: colour,
-            rotation: None
+            material: Material::new_diffuse(),
+            modifiers: Vec::new(),
+            rotation: None,
+            scale: [1.0, 1.0, 1.0],
         }
     }
 
+    //Rotation in radians, unlike the constructors further down
     pub fn new_cube(position: Vector3<f32>, size: Vector3<f32>, colour: Vector3<u8>, rotation: Vector3<f32>) -> SDF {
         SDF {
             position: position,
             size: size,
             sdf_type: SDF_Type::SDF_Box,
             colour: colour,
+            material: Material::new_diffuse(),
+            modifiers: Vec::new(),
             rotation: Some(get_rotation_matrix(rotation)),
+            scale: [1.0, 1.0, 1.0],
         }
     }
 
@@ -69,31 +201,447 @@ impl SDF {
             size: [t[0], t[1], 0.0],
             sdf_type: SDF_Type::SDF_Torus,
             colour: colour,
-            rotation: Some(get_rotation_matrix([rotation[0] / 180.0 * 3.14, rotation[1] / 180.0 * 3.14, rotation[2] / 180.0 * 3.14])),
+            material: Material::new_diffuse(),
+            modifiers: Vec::new(),
+            rotation: Some(rotation_from_degrees(rotation)),
+            scale: [1.0, 1.0, 1.0],
         }
     }
 
     pub fn new_plane(height: f32, colour: Vector3<u8>) -> SDF {
         SDF {
             position: [0.0, height, 0.0],
-            size: [0.0, 0.0, 0.0],
+            size: [0.0, 1.0, 0.0],
             sdf_type: SDF_Type::SDF_Plane,
             colour: colour,
+            material: Material::new_diffuse(),
+            modifiers: Vec::new(),
             rotation: None,
+            scale: [1.0, 1.0, 1.0],
+        }
+    }
+
+    //Plane through point, facing along normal
+    pub fn new_plane_normal(point: Vector3<f32>, normal: Vector3<f32>, colour: Vector3<u8>) -> SDF {
+        SDF::new(point, vmath::vec3_normalized(normal), SDF_Type::SDF_Plane, colour, None)
+    }
+
+    //All constructors below take their rotation in degrees, like update_rotation
+
+    //Box with a square footprint, half_width along x and z. radius rounds off the edges without
+    //growing the box, set_scale stretches it to other proportions.
+    pub fn new_round_box(position: Vector3<f32>, half_width: f32, half_height: f32, radius: f32, colour: Vector3<u8>, rotation: Vector3<f32>) -> SDF {
+        SDF::new(position, [half_width, half_height, radius], SDF_Type::SDF_RoundBox, colour, Some(rotation))
+    }
+
+    //Vertical capsule, half_height is the distance from the centre to the centre of either cap
+    pub fn new_capsule(position: Vector3<f32>, radius: f32, half_height: f32, colour: Vector3<u8>, rotation: Vector3<f32>) -> SDF {
+        SDF::new(position, [radius, half_height, 0.0], SDF_Type::SDF_Capsule, colour, Some(rotation))
+    }
+
+    //Infinitely long cylinder along the y axis
+    pub fn new_cylinder(position: Vector3<f32>, radius: f32, colour: Vector3<u8>, rotation: Vector3<f32>) -> SDF {
+        SDF::new(position, [radius, 0.0, 0.0], SDF_Type::SDF_Cylinder, colour, Some(rotation))
+    }
+
+    pub fn new_capped_cylinder(position: Vector3<f32>, radius: f32, half_height: f32, colour: Vector3<u8>, rotation: Vector3<f32>) -> SDF {
+        SDF::new(position, [radius, half_height, 0.0], SDF_Type::SDF_CappedCylinder, colour, Some(rotation))
+    }
+
+    //Cone pointing up along y, centred halfway between the tip and the base
+    pub fn new_cone(position: Vector3<f32>, radius: f32, height: f32, colour: Vector3<u8>, rotation: Vector3<f32>) -> SDF {
+        SDF::new(position, [radius, height, 0.0], SDF_Type::SDF_Cone, colour, Some(rotation))
+    }
+
+    //NOTE: The ellipsoid distance is a bound, not exact, but it's good enough for marching
+    pub fn new_ellipsoid(position: Vector3<f32>, radii: Vector3<f32>, colour: Vector3<u8>, rotation: Vector3<f32>) -> SDF {
+        SDF::new(position, radii, SDF_Type::SDF_Ellipsoid, colour, Some(rotation))
+    }
+
+    //size is the distance from the centre to each vertex
+    pub fn new_octahedron(position: Vector3<f32>, size: f32, colour: Vector3<u8>, rotation: Vector3<f32>) -> SDF {
+        SDF::new(position, [size, size, size], SDF_Type::SDF_Octahedron, colour, Some(rotation))
+    }
+
+    //Hexagonal prism along the z axis. radius is the inner radius of the hexagon
+    pub fn new_hex_prism(position: Vector3<f32>, radius: f32, half_length: f32, colour: Vector3<u8>, rotation: Vector3<f32>) -> SDF {
+        SDF::new(position, [radius, half_length, 0.0], SDF_Type::SDF_HexPrism, colour, Some(rotation))
+    }
+
+    //Triangular prism along the z axis, pointing up. size is the side length of the triangle
+    pub fn new_tri_prism(position: Vector3<f32>, size: f32, half_length: f32, colour: Vector3<u8>, rotation: Vector3<f32>) -> SDF {
+        SDF::new(position, [size, half_length, 0.0], SDF_Type::SDF_TriPrism, colour, Some(rotation))
+    }
+
+    //Chain link, a torus stretched by half_length along y
+    pub fn new_link(position: Vector3<f32>, half_length: f32, radius: f32, thickness: f32, colour: Vector3<u8>, rotation: Vector3<f32>) -> SDF {
+        SDF::new(position, [half_length, radius, thickness], SDF_Type::SDF_Link, colour, Some(rotation))
+    }
+
+    //Torus cut off at angle degrees on both sides of the y axis, 180 gives a full torus
+    pub fn new_capped_torus(position: Vector3<f32>, angle: f32, t: Vector2<f32>, colour: Vector3<u8>, rotation: Vector3<f32>) -> SDF {
+        SDF::new(position, [angle, t[0], t[1]], SDF_Type::SDF_CappedTorus, colour, Some(rotation))
+    }
+
+    //Square pyramid standing on the xz plane, base is the width of the base
+    pub fn new_pyramid(position: Vector3<f32>, base: f32, height: f32, colour: Vector3<u8>, rotation: Vector3<f32>) -> SDF {
+        SDF::new(position, [base, height, 0.0], SDF_Type::SDF_Pyramid, colour, Some(rotation))
+    }
+
+    //Mesh in its own units, use set_scale to resize it
+    pub fn new_mesh(position: Vector3<f32>, mesh: Arc<Mesh>, colour: Vector3<u8>, rotation: Vector3<f32>) -> SDF {
+        SDF::new(position, [1.0, 1.0, 1.0], SDF_Type::SDF_Mesh(mesh), colour, Some(rotation))
+    }
+
+    //Baked grid of distances, placed relative to the space it was baked in
+    pub fn new_volume(position: Vector3<f32>, volume: Arc<Volume>, colour: Vector3<u8>, rotation: Vector3<f32>) -> SDF {
+        SDF::new(position, [1.0, 1.0, 1.0], SDF_Type::SDF_Volume(volume), colour, Some(rotation))
+    }
+
+    //Terrain centred on position, with its lowest possible point at position
+    pub fn new_heightfield(position: Vector3<f32>, heightfield: Arc<Heightfield>, colour: Vector3<u8>) -> SDF {
+        SDF::new(position, heightfield.size, SDF_Type::SDF_Heightfield(heightfield), colour, None)
+    }
+
+    //Fractals are coloured by how their orbit behaves instead of by a flat colour, which only tints them.
+    //radius is roughly the size of the whole fractal.
+    pub fn new_mandelbulb(position: Vector3<f32>, radius: f32, power: f32, colour: Vector3<u8>, rotation: Vector3<f32>) -> SDF {
+        let iterations = Quality::Medium.mandelbulb_iterations();
+        SDF::new(position, [radius, radius, radius], SDF_Type::SDF_Mandelbulb { power, iterations }, colour, Some(rotation))
+    }
+
+    pub fn new_menger_sponge(position: Vector3<f32>, half_size: f32, colour: Vector3<u8>, rotation: Vector3<f32>) -> SDF {
+        let iterations = Quality::Medium.menger_iterations();
+        SDF::new(position, [half_size, half_size, half_size], SDF_Type::SDF_MengerSponge { iterations }, colour, Some(rotation))
+    }
+
+    pub fn new_sierpinski(position: Vector3<f32>, radius: f32, colour: Vector3<u8>, rotation: Vector3<f32>) -> SDF {
+        let iterations = Quality::Medium.sierpinski_iterations();
+        SDF::new(position, [radius, radius, radius], SDF_Type::SDF_Sierpinski { iterations }, colour, Some(rotation))
+    }
+
+    pub fn new_custom(position: Vector3<f32>, field: Arc<dyn DistanceField>, colour: Vector3<u8>, rotation: Vector3<f32>) -> SDF {
+        SDF::new(position, [1.0, 1.0, 1.0], SDF_Type::SDF_Custom(field), colour, Some(rotation))
+    }
+
+    //Shape written in the expression language, with p as the local position
+    pub fn new_expression(position: Vector3<f32>, source: &str, colour: Vector3<u8>, rotation: Vector3<f32>) -> Result<SDF, ExpressionError> {
+        let expression = Expression::parse(source, &[])?;
+        Ok(SDF::new_custom(position, Arc::new(expression), colour, rotation))
+    }
+
+    //Any type, with size holding what its constructor would put there and rotation in degrees.
+    //None for types that can't rotate.
+    pub fn new(position: Vector3<f32>, size: Vector3<f32>, sdf_type: SDF_Type, colour: Vector3<u8>, rotation: Option<Vector3<f32>>) -> SDF {
+        SDF {
+            position,
+            size,
+            sdf_type,
+            colour,
+            material: Material::new_diffuse(),
+            modifiers: Vec::new(),
+            rotation: rotation.map(rotation_from_degrees),
+            scale: [1.0, 1.0, 1.0],
         }
     }
 
     pub fn update_rotation(&mut self, new_rotation: Vector3<f32>) {
         if self.rotation.is_some() {
-            self.rotation = Some(get_rotation_matrix([new_rotation[0] / 180.0 * 3.14, new_rotation[1] / 180.0 * 3.14, new_rotation[2] / 180.0 * 3.14]));
+            self.rotation = Some(rotation_from_degrees(new_rotation));
+        }
+    }
+
+    //In degrees like update_rotation, 0 for objects that can't rotate
+    pub fn get_rotation(&self) -> Vector3<f32> {
+        match self.rotation {
+            Some(rotation) => {
+                let angles = get_rotation_angles(rotation);
+                let to_degrees = 180.0 / std::f32::consts::PI;
+                [angles[0] * to_degrees, angles[1] * to_degrees, angles[2] * to_degrees]
+            },
+            None => [0.0; 3],
+        }
+    }
+
+    pub fn set_scale(&mut self, scale: Vector3<f32>) {
+        self.scale = [positive_scale(scale[0]), positive_scale(scale[1]), positive_scale(scale[2])];
+    }
+
+    pub fn set_uniform_scale(&mut self, scale: f32) {
+        self.set_scale([scale, scale, scale]);
+    }
+
+    //Scaling stretches distances by a different amount along each axis. Using the smallest scale
+    //means we never step further than the real distance, at the cost of smaller steps near
+    //strongly squashed objects.
+    fn get_distance_scale(&self) -> f32 {
+        min(self.scale[0], min(self.scale[1], self.scale[2]))
+    }
+
+    pub fn add_modifier(&mut self, modifier: Modifier) {
+        self.modifiers.push(modifier);
+    }
+
+    pub fn set_material(&mut self, material: Material) {
+        self.material = material;
+    }
+
+    //Material at a point on the surface, custom fields can vary it
+    pub fn get_material(&self, position: Vector3<f32>) -> Material {
+        if let SDF_Type::SDF_Custom(ref field) = self.sdf_type {
+            if let Some(material) = field.material(self.to_local(position)) {
+                return material;
+            }
+        }
+        self.material
+    }
+
+    //Only changes anything for fractals
+    pub fn set_quality(&mut self, quality: Quality) {
+        match self.sdf_type {
+            SDF_Type::SDF_Mandelbulb { ref mut iterations, .. } => *iterations = quality.mandelbulb_iterations(),
+            SDF_Type::SDF_MengerSponge { ref mut iterations } => *iterations = quality.menger_iterations(),
+            SDF_Type::SDF_Sierpinski { ref mut iterations } => *iterations = quality.sierpinski_iterations(),
+            _ => {},
+        }
+    }
+
+    //Distance and orbit trap of a fractal, scaled to its size
+    fn get_fractal(&self, local_pos: Vector3<f32>) -> Option<(f32, f32)> {
+        let p = vmath::vec3_scale(local_pos, 1.0 / self.size[0]);
+        let (d, trap) = match self.sdf_type {
+            SDF_Type::SDF_Mandelbulb { power, iterations } => fractal::mandelbulb(p, power, iterations),
+            SDF_Type::SDF_MengerSponge { iterations } => fractal::menger(p, iterations),
+            SDF_Type::SDF_Sierpinski { iterations } => fractal::sierpinski(p, iterations),
+            _ => return None,
+        };
+        Some((d * self.size[0], trap))
+    }
+
+    //Albedo at a point on the surface, in 0..1
+    pub fn get_albedo(&self, position: Vector3<f32>, normal: Vector3<f32>) -> Vector3<f32> {
+        let mut base = [self.colour[0] as f32 / 255.0, self.colour[1] as f32 / 255.0, self.colour[2] as f32 / 255.0];
+        if let SDF_Type::SDF_Heightfield(ref heightfield) = self.sdf_type {
+            if let Some(colour) = heightfield.get_colour(self.to_local(position)[1]) {
+                base = colour;
+            }
+        }
+        if let Some((_, trap)) = self.get_fractal(self.to_local(position)) {
+            base = vmath::vec3_add(vmath::vec3_scale(fractal::palette(trap), 0.7), vmath::vec3_scale(base, 0.3));
+        }
+
+        match self.get_material(position).texture {
+            Some(texture) => {
+                let (p, n) = match texture.space {
+                    TextureSpace::World => (position, normal),
+                    TextureSpace::Object => (self.to_local(position), self.rotate_local(normal)),
+                };
+                let t = texture.sample_triplanar(p, n);
+                let other = [texture.colour[0] as f32 / 255.0, texture.colour[1] as f32 / 255.0, texture.colour[2] as f32 / 255.0];
+                vmath::vec3_add(vmath::vec3_scale(base, 1.0 - t), vmath::vec3_scale(other, t))
+            },
+            None => base,
+        }
+    }
+
+    //Box around the shape in its own space, before modifiers. None means it has no end
+    fn get_local_bounds(&self) -> Option<(Vector3<f32>, Vector3<f32>)> {
+        let size = self.size;
+        let centred = |e: Vector3<f32>| Some(([-e[0], -e[1], -e[2]], e));
+        match self.sdf_type {
+            SDF_Type::SDF_Plane | SDF_Type::SDF_Cylinder => None,
+            SDF_Type::SDF_Sphere | SDF_Type::SDF_Box |
+            SDF_Type::SDF_Ellipsoid | SDF_Type::SDF_Octahedron | SDF_Type::SDF_MengerSponge { .. } |
+            SDF_Type::SDF_Sierpinski { .. } => centred(size),
+            SDF_Type::SDF_RoundBox => centred([size[0], size[1], size[0]]),
+            SDF_Type::SDF_Torus => centred([size[0] + size[1], size[1], size[0] + size[1]]),
+            SDF_Type::SDF_Capsule => centred([size[0], size[1] + size[0], size[0]]),
+            SDF_Type::SDF_CappedCylinder => centred([size[0], size[1], size[0]]),
+            SDF_Type::SDF_Cone => centred([size[0], size[1] * 0.5, size[0]]),
+            //The outer radius of a hexagon is its inner radius / cos(30)
+            SDF_Type::SDF_HexPrism => centred([size[0] * 1.154_700_5, size[0] * 1.154_700_5, size[1]]),
+            SDF_Type::SDF_TriPrism => centred([size[0], size[0], size[1]]),
+            SDF_Type::SDF_Link => centred([size[1] + size[2], size[0] + size[1] + size[2], size[2]]),
+            SDF_Type::SDF_CappedTorus => centred([size[1] + size[2], size[1] + size[2], size[2]]),
+            SDF_Type::SDF_Pyramid => Some(([-size[0] * 0.5, 0.0, -size[0] * 0.5], [size[0] * 0.5, size[1], size[0] * 0.5])),
+            SDF_Type::SDF_Mesh(ref mesh) => Some(mesh.bounds()),
+            SDF_Type::SDF_Volume(ref volume) => Some((volume.min, volume.max)),
+            SDF_Type::SDF_Heightfield(ref heightfield) => {
+                let s = heightfield.size;
+                Some(([-s[0] * 0.5, 0.0, -s[2] * 0.5], [s[0] * 0.5, s[1], s[2] * 0.5]))
+            },
+            //Other powers spread out further than the usual power 8 bulb, up to where the orbit escapes
+            SDF_Type::SDF_Mandelbulb { .. } => centred([size[0] * 2.0; 3]),
+            SDF_Type::SDF_Custom(ref field) => field.bounds(),
+        }
+    }
+
+    //Axis aligned box around the object in world space, None if it's infinite.
+    //Conservative, rotated and modified objects get a box that is larger than they are.
+    pub fn get_bounds(&self) -> Option<(Vector3<f32>, Vector3<f32>)> {
+        let (mut lo, mut hi) = self.get_local_bounds()?;
+
+        //Modifiers move the point before the shape sees it, so the box has to cover every point
+        //that can end up inside the box of the shape
+        for modifier in &self.modifiers {
+            match *modifier {
+                Modifier::Repeat { .. } => return None,
+                Modifier::RepeatLimited { period, count } => {
+                    for i in 0..3 {
+                        if period[i] > 0.0 {
+                            lo[i] -= period[i] * count[i];
+                            hi[i] += period[i] * count[i];
+                        }
+                    }
+                },
+                Modifier::Mirror { axes } => {
+                    for i in 0..3 {
+                        if axes[i] {
+                            hi[i] = max(abs(lo[i]), abs(hi[i]));
+                            lo[i] = -hi[i];
+                        }
+                    }
+                },
+                //Both rotate around an axis, which keeps the distance to that axis the same
+                Modifier::Twist { .. } | Modifier::Bend { .. } => {
+                    let (a, b) = if let Modifier::Twist { .. } = *modifier { (0, 2) } else { (0, 1) };
+                    let ra = max(abs(lo[a]), abs(hi[a]));
+                    let rb = max(abs(lo[b]), abs(hi[b]));
+                    let r = (ra * ra + rb * rb).sqrt();
+                    lo[a] = -r;
+                    lo[b] = -r;
+                    hi[a] = r;
+                    hi[b] = r;
+                },
+                Modifier::Elongate { amount } => {
+                    lo = vmath::vec3_sub(lo, amount);
+                    hi = vmath::vec3_add(hi, amount);
+                },
+                Modifier::Round { radius: grow } | Modifier::Onion { thickness: grow } => {
+                    lo = vmath::vec3_sub(lo, [grow; 3]);
+                    hi = vmath::vec3_add(hi, [grow; 3]);
+                },
+            }
+        }
+
+        //Back to world space, through all 8 corners since rotation turns the box
+        let mut min_corner = [f32::MAX; 3];
+        let mut max_corner = [f32::MIN; 3];
+        for corner in 0..8 {
+            let local = [
+                if corner & 1 == 0 { lo[0] } else { hi[0] } * self.scale[0],
+                if corner & 2 == 0 { lo[1] } else { hi[1] } * self.scale[1],
+                if corner & 4 == 0 { lo[2] } else { hi[2] } * self.scale[2],
+            ];
+            let offset = match self.rotation {
+                //to_local rotates by the matrix, its transpose rotates back
+                Some(rotation) => vmath::row_mat3_transform(rotation, local),
+                None => local,
+            };
+            let world = vmath::vec3_add(self.position, offset);
+            for i in 0..3 {
+                min_corner[i] = min(min_corner[i], world[i]);
+                max_corner[i] = max(max_corner[i], world[i]);
+            }
+        }
+        Some((min_corner, max_corner))
+    }
+
+    //Moves a world space position into the space of this object
+    pub fn to_local(&self, position: Vector3<f32>) -> Vector3<f32> {
+        let p = self.rotate_local(vmath::vec3_sub(position, self.position));
+        [p[0] / self.scale[0], p[1] / self.scale[1], p[2] / self.scale[2]]
+    }
+
+    fn rotate_local(&self, v: Vector3<f32>) -> Vector3<f32> {
+        match self.rotation {
+            Some(rotation) => vmath::col_mat3_transform(rotation, v),
+            None => v,
         }
     }
 
     pub fn get_distance(&self, ray_position: Vector3<f32>) -> f32 {
-        let mut local_pos = vmath::vec3_sub(ray_position, self.position);
-        if self.rotation.is_some() {
-            local_pos = vmath::col_mat3_transform(self.rotation.expect("reee"), local_pos);
+        self.get_modified_distance(self.to_local(ray_position), self.modifiers.len()) * self.get_distance_scale()
+    }
+
+    //Evaluates the first count modifiers, the last of those wraps all the others
+    fn get_modified_distance(&self, local_pos: Vector3<f32>, count: usize) -> f32 {
+        if count == 0 {
+            return self.get_shape_distance(local_pos);
         }
+        let modifier = &self.modifiers[count - 1];
+        let (p, scale) = modifier.apply_domain(local_pos);
+        modifier.apply_distance(self.get_modified_distance(p, count - 1) * scale)
+    }
+
+    //get_distance for several points at once. Objects with modifiers and shapes without a packet
+    //version fall back to get_distance one lane at a time, skipping lanes that aren't active.
+    #[inline(always)]
+    pub fn get_distance_packet<const N: usize>(&self, positions: &Lanes3<N>, active: Mask<N>) -> Lanes<N> {
+        if self.modifiers.is_empty() {
+            let d = packet::sub3(*positions, self.position);
+            //Same as col_mat3_transform in to_local
+            let p = match self.rotation {
+                Some(m) => [
+                    d[0] * m[0][0] + d[1] * m[1][0] + d[2] * m[2][0],
+                    d[0] * m[0][1] + d[1] * m[1][1] + d[2] * m[2][1],
+                    d[0] * m[0][2] + d[1] * m[1][2] + d[2] * m[2][2],
+                ],
+                None => d,
+            };
+            let local = [p[0] / self.scale[0], p[1] / self.scale[1], p[2] / self.scale[2]];
+            if let Some(dist) = self.get_shape_distance_packet(&local) {
+                return dist * self.get_distance_scale();
+            }
+        }
+
+        Lanes(std::array::from_fn(|i| {
+            if active.0[i] { self.get_distance(packet::lane(positions, i)) } else { f32::MAX }
+        }))
+    }
+
+    //The simple primitives from get_shape_distance, written for packets
+    #[inline(always)]
+    fn get_shape_distance_packet<const N: usize>(&self, p: &Lanes3<N>) -> Option<Lanes<N>> {
+        let s = self.size;
+        let zero = Lanes::splat(0.0);
+        let dist = match self.sdf_type {
+            SDF_Type::SDF_Sphere => packet::len3(*p) - s[0],
+            SDF_Type::SDF_Box => {
+                let q = [p[0].abs() - s[0], p[1].abs() - s[1], p[2].abs() - s[2]];
+                packet::len3([q[0].max(zero), q[1].max(zero), q[2].max(zero)]) + q[0].max(q[1].max(q[2])).min(zero)
+            },
+            SDF_Type::SDF_Torus => packet::len2(packet::len2(p[0], p[2]) - s[0], p[1]) - s[1],
+            SDF_Type::SDF_Plane => packet::dot3(*p, s),
+            SDF_Type::SDF_RoundBox => {
+                let r = min(s[2], min(s[0], s[1]));
+                let q = [p[0].abs() - s[0] + r, p[1].abs() - s[1] + r, p[2].abs() - s[0] + r];
+                packet::len3([q[0].max(zero), q[1].max(zero), q[2].max(zero)]) + q[0].max(q[1].max(q[2])).min(zero) - r
+            },
+            SDF_Type::SDF_Capsule => {
+                let y = p[1] - p[1].clamp(Lanes::splat(-s[1]), Lanes::splat(s[1]));
+                packet::len3([p[0], y, p[2]]) - s[0]
+            },
+            SDF_Type::SDF_Cylinder => packet::len2(p[0], p[2]) - s[0],
+            SDF_Type::SDF_CappedCylinder => {
+                let d = [packet::len2(p[0], p[2]).abs() - s[0], p[1].abs() - s[1]];
+                d[0].max(d[1]).min(zero) + packet::len2(d[0].max(zero), d[1].max(zero))
+            },
+            SDF_Type::SDF_Ellipsoid => {
+                let k0 = packet::len3([p[0] / s[0], p[1] / s[1], p[2] / s[2]]);
+                let k1 = packet::len3([p[0] / (s[0] * s[0]), p[1] / (s[1] * s[1]), p[2] / (s[2] * s[2])]);
+                zero.lt(k1).select(k0 * (k0 - 1.0) / k1, Lanes::splat(-min(s[0], min(s[1], s[2]))))
+            },
+            SDF_Type::SDF_Link => {
+                let q1 = (p[1].abs() - s[0]).max(zero);
+                packet::len2(packet::len2(p[0], q1) - s[1], p[2]) - s[2]
+            },
+            _ => return None,
+        };
+        Some(dist)
+    }
+
+    fn get_shape_distance(&self, local_pos: Vector3<f32>) -> f32 {
         match self.sdf_type {
             SDF_Type::SDF_Sphere => {
                 return vmath::vec3_len(local_pos) - self.size[0];
@@ -110,11 +658,405 @@ impl SDF {
                 return vmath::vec2_len(q) - self.size[1];
             }
             SDF_Type::SDF_Plane => {
-                return local_pos[1];
+                return vmath::vec3_dot(local_pos, self.size);
             }
-            _ => {
-                return -1.0;
+            SDF_Type::SDF_RoundBox => {
+                let r = min(self.size[2], min(self.size[0], self.size[1]));
+                let q = [abs(local_pos[0]) - self.size[0] + r, abs(local_pos[1]) - self.size[1] + r, abs(local_pos[2]) - self.size[0] + r];
+                let a = vmath::vec3_len([max(q[0], 0.0), max(q[1], 0.0), max(q[2], 0.0)]);
+                let b = min(max(q[0], max(q[1], q[2])), 0.0);
+                a + b - r
+            }
+            SDF_Type::SDF_Capsule => {
+                let (radius, half_height) = (self.size[0], self.size[1]);
+                let y = local_pos[1] - clamp(local_pos[1], -half_height, half_height);
+                vmath::vec3_len([local_pos[0], y, local_pos[2]]) - radius
+            }
+            SDF_Type::SDF_Cylinder => {
+                vmath::vec2_len([local_pos[0], local_pos[2]]) - self.size[0]
+            }
+            SDF_Type::SDF_CappedCylinder => {
+                let d = [abs(vmath::vec2_len([local_pos[0], local_pos[2]])) - self.size[0], abs(local_pos[1]) - self.size[1]];
+                min(max(d[0], d[1]), 0.0) + vmath::vec2_len([max(d[0], 0.0), max(d[1], 0.0)])
+            }
+            SDF_Type::SDF_Cone => {
+                let (radius, height) = (self.size[0], self.size[1]);
+                //Move the tip to the origin
+                let q = [radius, -height];
+                let w = [vmath::vec2_len([local_pos[0], local_pos[2]]), local_pos[1] - height * 0.5];
+                let ta = clamp(dot2(w, q) / dot2(q, q), 0.0, 1.0);
+                let a = [w[0] - q[0] * ta, w[1] - q[1] * ta];
+                let tb = clamp(w[0] / q[0], 0.0, 1.0);
+                let b = [w[0] - q[0] * tb, w[1] - q[1]];
+                let k = sign(q[1]);
+                let d = min(dot2(a, a), dot2(b, b));
+                let s = max(k * (w[0] * q[1] - w[1] * q[0]), k * (w[1] - q[1]));
+                d.sqrt() * sign(s)
+            }
+            SDF_Type::SDF_Ellipsoid => {
+                let r = self.size;
+                let k0 = vmath::vec3_len([local_pos[0] / r[0], local_pos[1] / r[1], local_pos[2] / r[2]]);
+                let k1 = vmath::vec3_len([local_pos[0] / (r[0] * r[0]), local_pos[1] / (r[1] * r[1]), local_pos[2] / (r[2] * r[2])]);
+                if k1 == 0.0 {
+                    return -min(r[0], min(r[1], r[2]));
+                }
+                k0 * (k0 - 1.0) / k1
+            }
+            SDF_Type::SDF_Octahedron => {
+                let s = self.size[0];
+                let p = [abs(local_pos[0]), abs(local_pos[1]), abs(local_pos[2])];
+                let m = p[0] + p[1] + p[2] - s;
+                let q = if 3.0 * p[0] < m {
+                    p
+                } else if 3.0 * p[1] < m {
+                    [p[1], p[2], p[0]]
+                } else if 3.0 * p[2] < m {
+                    [p[2], p[0], p[1]]
+                } else {
+                    return m * 0.577_350_27;
+                };
+                let k = clamp(0.5 * (q[2] - q[1] + s), 0.0, s);
+                vmath::vec3_len([q[0], q[1] - s + k, q[2] - k])
+            }
+            SDF_Type::SDF_HexPrism => {
+                let h = [self.size[0], self.size[1]];
+                let k = [-0.866_025_4, 0.5, 0.577_350_27];
+                let mut p = [abs(local_pos[0]), abs(local_pos[1]), abs(local_pos[2])];
+                let fold = 2.0 * min(k[0] * p[0] + k[1] * p[1], 0.0);
+                p[0] -= fold * k[0];
+                p[1] -= fold * k[1];
+                let d = [
+                    vmath::vec2_len([p[0] - clamp(p[0], -k[2] * h[0], k[2] * h[0]), p[1] - h[0]]) * sign(p[1] - h[0]),
+                    p[2] - h[1],
+                ];
+                min(max(d[0], d[1]), 0.0) + vmath::vec2_len([max(d[0], 0.0), max(d[1], 0.0)])
+            }
+            SDF_Type::SDF_TriPrism => {
+                //Bound rather than exact, size is converted to the inradius based form iq uses
+                let h = [self.size[0] * 0.577_350_27, self.size[1]];
+                let q = [abs(local_pos[0]), abs(local_pos[1]), abs(local_pos[2])];
+                max(q[2] - h[1], max(q[0] * 0.866_025_4 + local_pos[1] * 0.5, -local_pos[1]) - h[0] * 0.5)
+            }
+            SDF_Type::SDF_Link => {
+                let (half_length, radius, thickness) = (self.size[0], self.size[1], self.size[2]);
+                let q = [local_pos[0], max(abs(local_pos[1]) - half_length, 0.0), local_pos[2]];
+                vmath::vec2_len([vmath::vec2_len([q[0], q[1]]) - radius, q[2]]) - thickness
+            }
+            SDF_Type::SDF_CappedTorus => {
+                let angle = self.size[0] * std::f32::consts::PI / 180.0;
+                let sc = [angle.sin(), angle.cos()];
+                let (ra, rb) = (self.size[1], self.size[2]);
+                let p = [abs(local_pos[0]), local_pos[1], local_pos[2]];
+                let k = if sc[1] * p[0] > sc[0] * p[1] {
+                    dot2([p[0], p[1]], sc)
+                } else {
+                    vmath::vec2_len([p[0], p[1]])
+                };
+                (vmath::vec3_dot(p, p) + ra * ra - 2.0 * ra * k).max(0.0).sqrt() - rb
+            }
+            SDF_Type::SDF_Pyramid => {
+                //iq's pyramid has a unit base, so scale the space to match and scale the distance back
+                let base = self.size[0];
+                let h = self.size[1] / base;
+                let p = vmath::vec3_scale(local_pos, 1.0 / base);
+
+                let (mut px, py, mut pz) = (abs(p[0]), p[1], abs(p[2]));
+                //iq's version overestimates below the base, there the base square is always the closest part
+                if py < 0.0 {
+                    return vmath::vec3_len([max(px - 0.5, 0.0), py, max(pz - 0.5, 0.0)]) * base;
+                }
+
+                let m2 = h * h + 0.25;
+                if pz > px {
+                    std::mem::swap(&mut px, &mut pz);
+                }
+                px -= 0.5;
+                pz -= 0.5;
+
+                let q = [pz, h * py - 0.5 * px, h * px + 0.5 * py];
+                let s = max(-q[0], 0.0);
+                let t = clamp((q[1] - 0.5 * pz) / (m2 + 0.25), 0.0, 1.0);
+                let a = m2 * (q[0] + s) * (q[0] + s) + q[1] * q[1];
+                let b = m2 * (q[0] + 0.5 * t) * (q[0] + 0.5 * t) + (q[1] - m2 * t) * (q[1] - m2 * t);
+                let d2 = if min(q[1], -q[0] * m2 - q[1] * 0.5) > 0.0 { 0.0 } else { min(a, b) };
+                ((d2 + q[2] * q[2]) / m2).sqrt() * sign(max(q[2], -py)) * base
+            }
+            SDF_Type::SDF_Mesh(ref mesh) => {
+                mesh.get_distance(local_pos)
+            }
+            SDF_Type::SDF_Volume(ref volume) => {
+                volume.get_distance(local_pos)
+            }
+            SDF_Type::SDF_Heightfield(ref heightfield) => {
+                heightfield.get_distance(local_pos)
             }
+            SDF_Type::SDF_Custom(ref field) => {
+                field.distance(local_pos)
+            }
+            SDF_Type::SDF_Mandelbulb { .. } | SDF_Type::SDF_MengerSponge { .. } | SDF_Type::SDF_Sierpinski { .. } => {
+                self.get_fractal(local_pos).map_or(-1.0, |(d, _)| d)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGIN: Vector3<f32> = [0.0, 0.0, 0.0];
+    const WHITE: Vector3<u8> = [255, 255, 255];
+
+    fn assert_distance(sdf: &SDF, p: Vector3<f32>, expected: f32) {
+        let d = sdf.get_distance(p);
+        assert!((d - expected).abs() < 1e-4, "distance at {:?} was {}, expected {}", p, d, expected);
+    }
+
+    fn assert_inside(sdf: &SDF, p: Vector3<f32>) {
+        assert!(sdf.get_distance(p) < 0.0, "{:?} should be inside", p);
+    }
+
+    #[test]
+    fn sphere() {
+        let sdf = SDF::new_sphere(ORIGIN, 1.0, WHITE);
+        assert_distance(&sdf, [2.0, 0.0, 0.0], 1.0);
+        assert_distance(&sdf, ORIGIN, -1.0);
+    }
+
+    #[test]
+    fn round_box() {
+        let sdf = SDF::new_round_box(ORIGIN, 1.0, 1.0, 0.25, WHITE, ORIGIN);
+        assert_distance(&sdf, [2.0, 0.0, 0.0], 1.0);
+        assert_distance(&sdf, ORIGIN, -1.0);
+        //The corners are rounded, so they are further away than on a sharp box
+        assert_distance(&sdf, [2.0, 2.0, 2.0], 1.25 * 3.0f32.sqrt() - 0.25);
+    }
+
+    #[test]
+    fn capsule() {
+        let sdf = SDF::new_capsule(ORIGIN, 0.5, 1.0, WHITE, ORIGIN);
+        assert_distance(&sdf, [0.0, 2.0, 0.0], 0.5);
+        assert_distance(&sdf, [1.0, 0.0, 0.0], 0.5);
+        assert_distance(&sdf, ORIGIN, -0.5);
+    }
+
+    #[test]
+    fn cylinders() {
+        let infinite = SDF::new_cylinder(ORIGIN, 1.0, WHITE, ORIGIN);
+        assert_distance(&infinite, [3.0, 100.0, 0.0], 2.0);
+        assert_inside(&infinite, [0.0, -100.0, 0.0]);
+
+        let capped = SDF::new_capped_cylinder(ORIGIN, 1.0, 1.0, WHITE, ORIGIN);
+        assert_distance(&capped, [0.0, 2.0, 0.0], 1.0);
+        assert_distance(&capped, [2.0, 0.0, 0.0], 1.0);
+        assert_distance(&capped, [2.0, 2.0, 0.0], 2.0f32.sqrt());
+        assert_distance(&capped, ORIGIN, -1.0);
+    }
+
+    #[test]
+    fn cone() {
+        let sdf = SDF::new_cone(ORIGIN, 1.0, 2.0, WHITE, ORIGIN);
+        assert_distance(&sdf, [0.0, 2.0, 0.0], 1.0); //Above the tip
+        assert_distance(&sdf, [0.0, -2.0, 0.0], 1.0); //Below the base
+        assert_inside(&sdf, ORIGIN);
+    }
+
+    #[test]
+    fn ellipsoid() {
+        let sdf = SDF::new_ellipsoid(ORIGIN, [2.0, 1.0, 1.0], WHITE, ORIGIN);
+        assert_distance(&sdf, [3.0, 0.0, 0.0], 1.0);
+        assert_distance(&sdf, [0.0, 2.0, 0.0], 1.0);
+        assert_inside(&sdf, ORIGIN);
+    }
+
+    #[test]
+    fn octahedron() {
+        let sdf = SDF::new_octahedron(ORIGIN, 1.0, WHITE, ORIGIN);
+        assert_distance(&sdf, [2.0, 0.0, 0.0], 1.0);
+        assert_distance(&sdf, ORIGIN, -1.0 / 3.0f32.sqrt());
+    }
+
+    #[test]
+    fn prisms() {
+        let hex = SDF::new_hex_prism(ORIGIN, 1.0, 1.0, WHITE, ORIGIN);
+        assert_distance(&hex, [0.0, 0.0, 3.0], 2.0);
+        assert_distance(&hex, [0.0, 2.0, 0.0], 1.0);
+        assert_distance(&hex, ORIGIN, -1.0);
+
+        let tri = SDF::new_tri_prism(ORIGIN, 3.0f32.sqrt(), 1.0, WHITE, ORIGIN);
+        assert_distance(&tri, [0.0, -1.5, 0.0], 1.0);
+        assert_distance(&tri, [0.0, 0.0, 3.0], 2.0);
+        assert_inside(&tri, ORIGIN);
+    }
+
+    #[test]
+    fn link() {
+        let sdf = SDF::new_link(ORIGIN, 1.0, 1.0, 0.25, WHITE, ORIGIN);
+        assert_distance(&sdf, [1.0, 0.0, 0.0], -0.25);
+        assert_distance(&sdf, [0.0, 3.0, 0.0], 0.75);
+        //The hole in the middle of the link
+        assert_distance(&sdf, ORIGIN, 0.75);
+    }
+
+    #[test]
+    fn tori() {
+        let torus = SDF::new_torus(ORIGIN, [1.0, 0.5], WHITE, ORIGIN);
+        assert_distance(&torus, [1.0, 0.0, 0.0], -0.5);
+        assert_distance(&torus, [2.0, 0.0, 0.0], 0.5);
+        assert_distance(&torus, ORIGIN, 0.5);
+
+        let full = SDF::new_capped_torus(ORIGIN, 180.0, [1.0, 0.25], WHITE, ORIGIN);
+        assert_distance(&full, [1.0, 0.0, 0.0], -0.25);
+        assert_distance(&full, [0.0, -1.0, 0.0], -0.25);
+        assert_distance(&full, ORIGIN, 0.75);
+
+        //Only the upper half of the ring is left
+        let half = SDF::new_capped_torus(ORIGIN, 90.0, [1.0, 0.25], WHITE, ORIGIN);
+        assert_distance(&half, [0.0, 1.0, 0.0], -0.25);
+        assert_distance(&half, [0.0, -1.0, 0.0], 2.0f32.sqrt() - 0.25);
+    }
+
+    #[test]
+    fn pyramid() {
+        let sdf = SDF::new_pyramid(ORIGIN, 1.0, 1.0, WHITE, ORIGIN);
+        assert_distance(&sdf, [0.0, 2.0, 0.0], 1.0);
+        assert_distance(&sdf, [0.0, -1.0, 0.0], 1.0);
+        assert_inside(&sdf, [0.0, 0.25, 0.0]);
+
+        //Scaling the base scales the whole pyramid
+        let big = SDF::new_pyramid(ORIGIN, 2.0, 2.0, WHITE, ORIGIN);
+        assert_distance(&big, [0.0, 4.0, 0.0], 2.0);
+    }
+
+    #[test]
+    fn planes() {
+        let ground = SDF::new_plane(-1.0, WHITE);
+        assert_distance(&ground, ORIGIN, 1.0);
+        assert_distance(&ground, [5.0, -3.0, 2.0], -2.0);
+
+        let wall = SDF::new_plane_normal([2.0, 0.0, 0.0], [2.0, 0.0, 0.0], WHITE);
+        assert_distance(&wall, ORIGIN, -2.0);
+        assert_distance(&wall, [3.0, 7.0, -1.0], 1.0);
+    }
+
+    #[test]
+    fn modifiers_wrap_in_order() {
+        //Rounding then repeating gives rounded copies
+        let mut sdf = SDF::new_sphere(ORIGIN, 1.0, WHITE);
+        sdf.add_modifier(Modifier::Round { radius: 0.5 });
+        sdf.add_modifier(Modifier::Repeat { period: [4.0, 0.0, 0.0] });
+        assert_distance(&sdf, [8.0, 0.0, 0.0], -1.5);
+        assert_distance(&sdf, [6.0, 0.0, 0.0], 0.5);
+        assert_distance(&sdf, [0.0, 3.0, 0.0], 1.5);
+
+        //Hollowing out a sphere leaves the centre outside
+        let mut shell = SDF::new_sphere(ORIGIN, 1.0, WHITE);
+        shell.add_modifier(Modifier::Onion { thickness: 0.1 });
+        assert_distance(&shell, ORIGIN, 0.9);
+        assert_distance(&shell, [1.0, 0.0, 0.0], -0.1);
+    }
+
+    #[test]
+    fn twist_stays_a_bound() {
+        let mut sdf = SDF::new_cube(ORIGIN, [1.0, 2.0, 0.25], WHITE, ORIGIN);
+        sdf.add_modifier(Modifier::Twist { amount: 2.0 });
+        //Sample a line and check the distance never claims more room than there is
+        for i in 0..100 {
+            let a = [i as f32 * 0.05 - 2.5, 0.7, 0.3];
+            let b = [a[0] + 0.05, a[1], a[2]];
+            let (da, db) = (sdf.get_distance(a), sdf.get_distance(b));
+            assert!((da - db).abs() <= 0.05 + 1e-4, "{} and {} are further apart than the points", da, db);
+        }
+    }
+
+    #[test]
+    fn scale() {
+        let mut sdf = SDF::new_sphere(ORIGIN, 1.0, WHITE);
+        sdf.set_uniform_scale(2.0);
+        assert_distance(&sdf, [3.0, 0.0, 0.0], 1.0);
+        assert_distance(&sdf, ORIGIN, -2.0);
+
+        //Squashed into an ellipsoid, the distance along the stretched axis is underestimated, never over
+        sdf.set_scale([2.0, 1.0, 1.0]);
+        assert_distance(&sdf, [0.0, 2.0, 0.0], 1.0);
+        let d = sdf.get_distance([3.0, 0.0, 0.0]);
+        assert!(d > 0.0 && d <= 1.0);
+        assert_inside(&sdf, [1.5, 0.0, 0.0]);
+
+        //Degenerate scales are clamped, so distances stay finite
+        sdf.set_scale([0.0, -1.0, f32::NAN]);
+        assert_eq!(sdf.scale, [MIN_SCALE; 3]);
+        sdf.set_uniform_scale(0.0);
+        assert_eq!(sdf.scale, [MIN_SCALE; 3]);
+        assert!(sdf.get_distance([3.0, 0.0, 0.0]).is_finite());
+    }
+
+    #[test]
+    fn rotation_is_in_degrees() {
+        //A box that is long along x, rotated 90 degrees around y, is long along z
+        //new_cube is older than the rest and still takes radians
+        for sdf in &[
+            SDF::new_cube(ORIGIN, [2.0, 0.5, 0.5], WHITE, [0.0, std::f32::consts::FRAC_PI_2, 0.0]),
+            SDF::new_capped_cylinder(ORIGIN, 0.5, 2.0, WHITE, [90.0, 0.0, 0.0]),
+        ] {
+            assert_inside(sdf, [0.0, 0.0, 1.5]);
+            assert_distance(sdf, [1.5, 0.0, 0.0], 1.0);
+        }
+        let round_box = SDF::new_round_box(ORIGIN, 0.5, 2.0, 0.1, WHITE, [90.0, 0.0, 0.0]);
+        assert_inside(&round_box, [0.0, 0.0, 1.5]);
+    }
+
+    #[test]
+    fn fractals() {
+        let mut sponge = SDF::new_menger_sponge(ORIGIN, 1.0, WHITE, ORIGIN);
+        assert_distance(&sponge, [2.0, 0.0, 0.0], 1.0);
+        assert_inside(&sponge, [0.95, 0.95, 0.95]);
+        //The middle is carved out
+        assert!(sponge.get_distance(ORIGIN) > 0.0);
+
+        //The corners of the tetrahedron are part of the fractal, so the distance can't be larger than to those
+        let sierpinski = SDF::new_sierpinski(ORIGIN, 1.0, WHITE, ORIGIN);
+        assert!(sierpinski.get_distance([1.0, 1.0, 1.0]) <= 0.0);
+        for p in [[3.0, 0.0, 0.0], [0.0, -2.0, 1.0], [2.0, 2.0, 2.0]] {
+            let d = sierpinski.get_distance(p);
+            let closest = vmath::vec3_len(vmath::vec3_sub(p, [1.0, 1.0, 1.0]))
+                .min(vmath::vec3_len(vmath::vec3_sub(p, [-1.0, -1.0, 1.0])))
+                .min(vmath::vec3_len(vmath::vec3_sub(p, [1.0, -1.0, -1.0])))
+                .min(vmath::vec3_len(vmath::vec3_sub(p, [-1.0, 1.0, -1.0])));
+            assert!(d > 0.0 && d <= closest, "distance at {:?} was {}", p, d);
+        }
+
+        //The bulb fits in a sphere of radius 1.2 or so, and the distance is larger further away
+        let bulb = SDF::new_mandelbulb(ORIGIN, 1.0, 8.0, WHITE, ORIGIN);
+        assert_inside(&bulb, [0.1, 0.0, 0.0]);
+        let (near, far) = (bulb.get_distance([2.0, 0.0, 0.0]), bulb.get_distance([4.0, 0.0, 0.0]));
+        assert!(near > 0.0 && near < 1.0 && far > near);
+
+        sponge.set_quality(Quality::Low);
+        assert!(matches!(sponge.sdf_type, SDF_Type::SDF_MengerSponge { iterations: 2 }));
+    }
+
+    struct Striped;
+
+    impl DistanceField for Striped {
+        fn distance(&self, p: Vector3<f32>) -> f32 {
+            vmath::vec3_len(p) - 1.0
+        }
+
+        fn material(&self, p: Vector3<f32>) -> Option<Material> {
+            if p[1] > 0.0 { Some(Material::new_glossy(1.0)) } else { None }
         }
     }
+
+    #[test]
+    fn custom() {
+        let sdf = SDF::new_custom([0.0, 2.0, 0.0], Arc::new(Striped), WHITE, ORIGIN);
+        assert_distance(&sdf, [3.0, 2.0, 0.0], 2.0);
+        assert_eq!(sdf.get_material([0.0, 3.0, 0.0]).reflectivity, 1.0);
+        assert_eq!(sdf.get_material([0.0, 1.0, 0.0]).reflectivity, 0.0);
+
+        let mut closure = SDF::new_custom(ORIGIN, Arc::new(|p: Vector3<f32>| p[1]), WHITE, ORIGIN);
+        closure.set_uniform_scale(2.0);
+        assert_distance(&closure, [5.0, 3.0, 0.0], 3.0);
+    }
 }
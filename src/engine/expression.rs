@@ -0,0 +1,781 @@
+extern crate vecmath as vmath;
+use vmath::{
+    Vector3,
+};
+
+use std::fmt;
+
+use super::distance_field::DistanceField;
+
+//A tiny language for writing SDFs as text, for example
+//    smin(sphere(p - vec3(0, 1, 0), r), box(p, vec3(1)), 0.3)
+//p is the point being evaluated, other names are parameters given when parsing.
+//The text is parsed once and compiled into a tree of typed nodes, so evaluating it
+//doesn't check types or look up names anymore.
+
+//Parentheses, calls, minus signs and operators in a row each go a level deeper. Past this the
+//parser, the compiler and evaluating would all run out of stack.
+const MAX_DEPTH: usize = 256;
+
+fn clamp(x: f32, a: f32, b: f32) -> f32 {
+    if x < a { return a };
+    if x > b { return b };
+    x
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExpressionError {
+    pub message: String,
+    pub position: usize, //Character in the source where the problem is, starting at 0
+}
+
+impl fmt::Display for ExpressionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at column {}", self.message, self.position + 1)
+    }
+}
+
+impl std::error::Error for ExpressionError {}
+
+fn error<T>(message: String, position: usize) -> Result<T, ExpressionError> {
+    Err(ExpressionError { message, position })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f32),
+    Name(String),
+    Symbol(char),
+    End,
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, ExpressionError> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() || (c == '.' && chars.get(i + 1).is_some_and(|n| n.is_ascii_digit())) {
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            //Exponent, like 1e-3
+            if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                i += 1;
+                if i < chars.len() && (chars[i] == '-' || chars[i] == '+') {
+                    i += 1;
+                }
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+            }
+            let text: String = chars[start..i].iter().collect();
+            match text.parse() {
+                Ok(value) => tokens.push((Token::Number(value), start)),
+                Err(_) => return error(format!("'{}' is not a valid number", text), start),
+            }
+        } else if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push((Token::Name(chars[start..i].iter().collect()), start));
+        } else if "+-*/(),.".contains(c) {
+            tokens.push((Token::Symbol(c), start));
+            i += 1;
+        } else {
+            return error(format!("Unexpected character '{}'", c), start);
+        }
+    }
+
+    tokens.push((Token::End, chars.len()));
+    Ok(tokens)
+}
+
+//What the parser produces, before names and types are checked
+enum Ast {
+    Number(f32),
+    Name(String),
+    Call(String, Vec<(Ast, usize)>),
+    Negate(Box<(Ast, usize)>),
+    Binary(char, Box<(Ast, usize)>, Box<(Ast, usize)>),
+    Component(Box<(Ast, usize)>, usize),
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    current: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.current].0
+    }
+
+    fn position(&self) -> usize {
+        self.tokens[self.current].1
+    }
+
+    fn next(&mut self) -> (Token, usize) {
+        let token = self.tokens[self.current].clone();
+        if token.0 != Token::End {
+            self.current += 1;
+        }
+        token
+    }
+
+    //Call before going a level deeper, and take it off depth again once that level is done
+    fn enter(&mut self, position: usize) -> Result<(), ExpressionError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return error(format!("Expression is nested more than {} levels deep", MAX_DEPTH), position);
+        }
+        Ok(())
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), ExpressionError> {
+        if *self.peek() == Token::Symbol(symbol) {
+            self.next();
+            return Ok(());
+        }
+        error(format!("Expected '{}' but found {}", symbol, describe(self.peek())), self.position())
+    }
+
+    //sum: product (('+' | '-') product)*
+    fn sum(&mut self) -> Result<(Ast, usize), ExpressionError> {
+        let depth = self.depth;
+        let mut left = self.product()?;
+        while let Token::Symbol(op @ ('+' | '-')) = *self.peek() {
+            let (_, position) = self.next();
+            self.enter(position)?;
+            let right = self.product()?;
+            left = (Ast::Binary(op, Box::new(left), Box::new(right)), position);
+        }
+        self.depth = depth;
+        Ok(left)
+    }
+
+    //product: unary (('*' | '/') unary)*
+    fn product(&mut self) -> Result<(Ast, usize), ExpressionError> {
+        let depth = self.depth;
+        let mut left = self.unary()?;
+        while let Token::Symbol(op @ ('*' | '/')) = *self.peek() {
+            let (_, position) = self.next();
+            self.enter(position)?;
+            let right = self.unary()?;
+            left = (Ast::Binary(op, Box::new(left), Box::new(right)), position);
+        }
+        self.depth = depth;
+        Ok(left)
+    }
+
+    //unary: '-' unary | postfix
+    fn unary(&mut self) -> Result<(Ast, usize), ExpressionError> {
+        if *self.peek() == Token::Symbol('-') {
+            let (_, position) = self.next();
+            self.enter(position)?;
+            let inner = self.unary()?;
+            self.depth -= 1;
+            return Ok((Ast::Negate(Box::new(inner)), position));
+        }
+        self.postfix()
+    }
+
+    //postfix: primary ('.' ('x' | 'y' | 'z'))*
+    fn postfix(&mut self) -> Result<(Ast, usize), ExpressionError> {
+        let depth = self.depth;
+        let mut value = self.primary()?;
+        while *self.peek() == Token::Symbol('.') {
+            self.next();
+            let (token, position) = self.next();
+            let component = match token {
+                Token::Name(ref name) if name == "x" => 0,
+                Token::Name(ref name) if name == "y" => 1,
+                Token::Name(ref name) if name == "z" => 2,
+                _ => return error(format!("Expected x, y or z after '.' but found {}", describe(&token)), position),
+            };
+            self.enter(position)?;
+            value = (Ast::Component(Box::new(value), component), position);
+        }
+        self.depth = depth;
+        Ok(value)
+    }
+
+    //primary: number | name | name '(' arguments ')' | '(' sum ')'
+    fn primary(&mut self) -> Result<(Ast, usize), ExpressionError> {
+        let (token, position) = self.next();
+        match token {
+            Token::Number(value) => Ok((Ast::Number(value), position)),
+            Token::Name(name) => {
+                if *self.peek() != Token::Symbol('(') {
+                    return Ok((Ast::Name(name), position));
+                }
+                self.next();
+                self.enter(position)?;
+                let mut arguments = Vec::new();
+                if *self.peek() != Token::Symbol(')') {
+                    arguments.push(self.sum()?);
+                    while *self.peek() == Token::Symbol(',') {
+                        self.next();
+                        arguments.push(self.sum()?);
+                    }
+                }
+                self.expect(')')?;
+                self.depth -= 1;
+                Ok((Ast::Call(name, arguments), position))
+            },
+            Token::Symbol('(') => {
+                self.enter(position)?;
+                let inner = self.sum()?;
+                self.expect(')')?;
+                self.depth -= 1;
+                Ok(inner)
+            },
+            _ => error(format!("Expected a value but found {}", describe(&token)), position),
+        }
+    }
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Number(value) => format!("the number {}", value),
+        Token::Name(name) => format!("'{}'", name),
+        Token::Symbol(c) => format!("'{}'", c),
+        Token::End => "the end of the expression".to_string(),
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Min,
+    Max,
+    Mod,
+    Pow,
+}
+
+impl Op {
+    fn apply(&self, a: f32, b: f32) -> f32 {
+        match self {
+            Op::Add => a + b,
+            Op::Sub => a - b,
+            Op::Mul => a * b,
+            Op::Div => a / b,
+            Op::Min => a.min(b),
+            Op::Max => a.max(b),
+            Op::Mod => a.rem_euclid(b),
+            Op::Pow => a.powf(b),
+        }
+    }
+}
+
+//Nodes that evaluate to a number
+#[derive(Clone)]
+enum Scalar {
+    Constant(f32),
+    Parameter(usize),
+    Negate(Box<Scalar>),
+    Binary(Op, Box<Scalar>, Box<Scalar>),
+    Function(fn(f32) -> f32, Box<Scalar>),
+    Clamp(Box<Scalar>, Box<Scalar>, Box<Scalar>),
+    Component(Box<Vector>, usize),
+    Length(Box<Vector>),
+    Dot(Box<Vector>, Box<Vector>),
+    SmoothMin(Box<Scalar>, Box<Scalar>, Box<Scalar>),
+    SmoothMax(Box<Scalar>, Box<Scalar>, Box<Scalar>),
+    Sphere(Box<Vector>, Box<Scalar>),
+    Box(Box<Vector>, Box<Vector>),
+    RoundBox(Box<Vector>, Box<Vector>, Box<Scalar>),
+    Torus(Box<Vector>, Box<Scalar>, Box<Scalar>),
+    Capsule(Box<Vector>, Box<Scalar>, Box<Scalar>),
+    Cylinder(Box<Vector>, Box<Scalar>, Box<Scalar>),
+}
+
+//Nodes that evaluate to a vector
+#[derive(Clone)]
+enum Vector {
+    Position,
+    Constant(Vector3<f32>),
+    Construct(Box<Scalar>, Box<Scalar>, Box<Scalar>),
+    Negate(Box<Vector>),
+    Binary(Op, Box<Vector>, Box<Vector>), //Component wise
+    Scale(Op, Box<Vector>, Box<Scalar>), //Every component with the same number
+    Function(fn(f32) -> f32, Box<Vector>),
+    Normalize(Box<Vector>),
+    Rotate(usize, Box<Vector>, Box<Scalar>), //Around an axis, in degrees
+    Repeat(Box<Vector>, Box<Vector>),
+}
+
+fn smooth_min(a: f32, b: f32, k: f32) -> f32 {
+    if k <= 0.0 {
+        return a.min(b);
+    }
+    let h = clamp(0.5 + 0.5 * (b - a) / k, 0.0, 1.0);
+    b + (a - b) * h - k * h * (1.0 - h)
+}
+
+fn sd_box(p: Vector3<f32>, b: Vector3<f32>) -> f32 {
+    let q = [p[0].abs() - b[0], p[1].abs() - b[1], p[2].abs() - b[2]];
+    vmath::vec3_len([q[0].max(0.0), q[1].max(0.0), q[2].max(0.0)]) + q[0].max(q[1].max(q[2])).min(0.0)
+}
+
+impl Scalar {
+    fn eval(&self, p: Vector3<f32>, parameters: &[f32]) -> f32 {
+        match self {
+            Scalar::Constant(value) => *value,
+            Scalar::Parameter(idx) => parameters[*idx],
+            Scalar::Negate(a) => -a.eval(p, parameters),
+            Scalar::Binary(op, a, b) => op.apply(a.eval(p, parameters), b.eval(p, parameters)),
+            Scalar::Function(f, a) => f(a.eval(p, parameters)),
+            Scalar::Clamp(x, a, b) => clamp(x.eval(p, parameters), a.eval(p, parameters), b.eval(p, parameters)),
+            Scalar::Component(v, i) => v.eval(p, parameters)[*i],
+            Scalar::Length(v) => vmath::vec3_len(v.eval(p, parameters)),
+            Scalar::Dot(a, b) => vmath::vec3_dot(a.eval(p, parameters), b.eval(p, parameters)),
+            Scalar::SmoothMin(a, b, k) => smooth_min(a.eval(p, parameters), b.eval(p, parameters), k.eval(p, parameters)),
+            Scalar::SmoothMax(a, b, k) => -smooth_min(-a.eval(p, parameters), -b.eval(p, parameters), k.eval(p, parameters)),
+            Scalar::Sphere(q, r) => vmath::vec3_len(q.eval(p, parameters)) - r.eval(p, parameters),
+            Scalar::Box(q, b) => sd_box(q.eval(p, parameters), b.eval(p, parameters)),
+            Scalar::RoundBox(q, b, r) => {
+                let r = r.eval(p, parameters);
+                let b = b.eval(p, parameters);
+                sd_box(q.eval(p, parameters), [b[0] - r, b[1] - r, b[2] - r]) - r
+            },
+            Scalar::Torus(q, major, minor) => {
+                let q = q.eval(p, parameters);
+                let x = (q[0] * q[0] + q[2] * q[2]).sqrt() - major.eval(p, parameters);
+                (x * x + q[1] * q[1]).sqrt() - minor.eval(p, parameters)
+            },
+            Scalar::Capsule(q, r, h) => {
+                let q = q.eval(p, parameters);
+                let h = h.eval(p, parameters);
+                vmath::vec3_len([q[0], q[1] - clamp(q[1], -h, h), q[2]]) - r.eval(p, parameters)
+            },
+            Scalar::Cylinder(q, r, h) => {
+                let q = q.eval(p, parameters);
+                let dx = (q[0] * q[0] + q[2] * q[2]).sqrt() - r.eval(p, parameters);
+                let dy = q[1].abs() - h.eval(p, parameters);
+                dx.max(dy).min(0.0) + (dx.max(0.0) * dx.max(0.0) + dy.max(0.0) * dy.max(0.0)).sqrt()
+            },
+        }
+    }
+}
+
+impl Vector {
+    fn eval(&self, p: Vector3<f32>, parameters: &[f32]) -> Vector3<f32> {
+        match self {
+            Vector::Position => p,
+            Vector::Constant(value) => *value,
+            Vector::Construct(x, y, z) => [x.eval(p, parameters), y.eval(p, parameters), z.eval(p, parameters)],
+            Vector::Negate(a) => vmath::vec3_neg(a.eval(p, parameters)),
+            Vector::Binary(op, a, b) => {
+                let (a, b) = (a.eval(p, parameters), b.eval(p, parameters));
+                [op.apply(a[0], b[0]), op.apply(a[1], b[1]), op.apply(a[2], b[2])]
+            },
+            Vector::Scale(op, a, s) => {
+                let (a, s) = (a.eval(p, parameters), s.eval(p, parameters));
+                [op.apply(a[0], s), op.apply(a[1], s), op.apply(a[2], s)]
+            },
+            Vector::Function(f, a) => {
+                let a = a.eval(p, parameters);
+                [f(a[0]), f(a[1]), f(a[2])]
+            },
+            Vector::Normalize(a) => vmath::vec3_normalized(a.eval(p, parameters)),
+            Vector::Rotate(axis, a, degrees) => {
+                let a = a.eval(p, parameters);
+                let (s, c) = degrees.eval(p, parameters).to_radians().sin_cos();
+                let (i, j) = ((axis + 1) % 3, (axis + 2) % 3);
+                let mut rotated = a;
+                rotated[i] = c * a[i] - s * a[j];
+                rotated[j] = s * a[i] + c * a[j];
+                rotated
+            },
+            Vector::Repeat(a, period) => {
+                let (a, period) = (a.eval(p, parameters), period.eval(p, parameters));
+                let mut q = a;
+                for i in 0..3 {
+                    if period[i] > 0.0 {
+                        q[i] = a[i] - period[i] * (a[i] / period[i]).round();
+                    }
+                }
+                q
+            },
+        }
+    }
+}
+
+enum Value {
+    Scalar(Scalar),
+    Vector(Vector),
+}
+
+impl Value {
+    fn kind(&self) -> &'static str {
+        match self {
+            Value::Scalar(_) => "a number",
+            Value::Vector(_) => "a vector",
+        }
+    }
+}
+
+struct Compiler<'a> {
+    parameters: &'a [(String, f32)],
+}
+
+impl Compiler<'_> {
+    fn compile(&self, node: &(Ast, usize)) -> Result<Value, ExpressionError> {
+        let (ast, position) = node;
+        let position = *position;
+        match ast {
+            Ast::Number(value) => Ok(Value::Scalar(Scalar::Constant(*value))),
+            Ast::Name(name) => {
+                if name == "p" {
+                    return Ok(Value::Vector(Vector::Position));
+                }
+                match self.parameters.iter().position(|(n, _)| n == name) {
+                    Some(idx) => Ok(Value::Scalar(Scalar::Parameter(idx))),
+                    None => error(format!("Unknown name '{}'", name), position),
+                }
+            },
+            Ast::Negate(inner) => Ok(match self.compile(inner)? {
+                Value::Scalar(Scalar::Constant(value)) => Value::Scalar(Scalar::Constant(-value)),
+                Value::Scalar(a) => Value::Scalar(Scalar::Negate(Box::new(a))),
+                Value::Vector(a) => Value::Vector(Vector::Negate(Box::new(a))),
+            }),
+            Ast::Component(inner, component) => match self.compile(inner)? {
+                Value::Vector(v) => Ok(Value::Scalar(Scalar::Component(Box::new(v), *component))),
+                Value::Scalar(_) => error("Only vectors have x, y and z components".to_string(), position),
+            },
+            Ast::Binary(symbol, a, b) => {
+                let op = match symbol {
+                    '+' => Op::Add,
+                    '-' => Op::Sub,
+                    '*' => Op::Mul,
+                    _ => Op::Div,
+                };
+                self.binary(op, self.compile(a)?, self.compile(b)?, position, *symbol)
+            },
+            Ast::Call(name, arguments) => self.call(name, arguments, position),
+        }
+    }
+
+    fn binary(&self, op: Op, a: Value, b: Value, position: usize, symbol: char) -> Result<Value, ExpressionError> {
+        match (a, b) {
+            (Value::Scalar(Scalar::Constant(a)), Value::Scalar(Scalar::Constant(b))) => Ok(Value::Scalar(Scalar::Constant(op.apply(a, b)))),
+            (Value::Scalar(a), Value::Scalar(b)) => Ok(Value::Scalar(Scalar::Binary(op, Box::new(a), Box::new(b)))),
+            (Value::Vector(a), Value::Vector(b)) => Ok(Value::Vector(Vector::Binary(op, Box::new(a), Box::new(b)))),
+            (Value::Vector(a), Value::Scalar(s)) if matches!(op, Op::Mul | Op::Div) => Ok(Value::Vector(Vector::Scale(op, Box::new(a), Box::new(s)))),
+            (Value::Scalar(s), Value::Vector(a)) if matches!(op, Op::Mul) => Ok(Value::Vector(Vector::Scale(op, Box::new(a), Box::new(s)))),
+            (a, b) => error(format!("Can't use '{}' on {} and {}", symbol, a.kind(), b.kind()), position),
+        }
+    }
+
+    fn call(&self, name: &str, arguments: &[(Ast, usize)], position: usize) -> Result<Value, ExpressionError> {
+        let mut values = Vec::with_capacity(arguments.len());
+        for argument in arguments {
+            values.push(self.compile(argument)?);
+        }
+
+        //Checks the number and kinds of the arguments, 's' for a number and 'v' for a vector
+        let found: Vec<&str> = values.iter().map(Value::kind).collect();
+        let signature = |kinds: &str| -> Result<(), ExpressionError> {
+            if found.len() != kinds.len() {
+                return error(format!("{} takes {} arguments but got {}", name, kinds.len(), found.len()), position);
+            }
+            for (i, (kind, value)) in kinds.chars().zip(found.iter()).enumerate() {
+                let expected = if kind == 's' { "a number" } else { "a vector" };
+                if *value != expected {
+                    return error(format!("Argument {} of {} should be {} but is {}", i + 1, name, expected, value), arguments[i].1);
+                }
+            }
+            Ok(())
+        };
+
+        let function: Option<fn(f32) -> f32> = match name {
+            "abs" => Some(f32::abs),
+            "sqrt" => Some(f32::sqrt),
+            "sin" => Some(f32::sin),
+            "cos" => Some(f32::cos),
+            "tan" => Some(f32::tan),
+            "exp" => Some(f32::exp),
+            "floor" => Some(f32::floor),
+            "fract" => Some(f32::fract),
+            _ => None,
+        };
+        if let Some(function) = function {
+            if values.len() != 1 {
+                return error(format!("{} takes 1 argument but got {}", name, values.len()), position);
+            }
+            return Ok(match values.pop().unwrap() {
+                Value::Scalar(a) => Value::Scalar(Scalar::Function(function, Box::new(a))),
+                Value::Vector(a) => Value::Vector(Vector::Function(function, Box::new(a))),
+            });
+        }
+
+        self.build(name, &mut values.into_iter(), signature, position)
+    }
+
+    //Everything that isn't a plain math function, once the arguments are compiled
+    fn build<I, F>(&self, name: &str, values: &mut I, signature: F, position: usize) -> Result<Value, ExpressionError>
+    where I: Iterator<Item = Value>, F: Fn(&str) -> Result<(), ExpressionError> {
+        match name {
+            "vec3" => {
+                if signature("s").is_ok() {
+                    return Ok(match *take_scalar(values) {
+                        Scalar::Constant(value) => Value::Vector(Vector::Constant([value, value, value])),
+                        s => Value::Vector(Vector::Construct(Box::new(s.clone()), Box::new(s.clone()), Box::new(s))),
+                    });
+                }
+                signature("sss")?;
+                let (x, y, z) = (take_scalar(values), take_scalar(values), take_scalar(values));
+                Ok(match (*x, *y, *z) {
+                    (Scalar::Constant(x), Scalar::Constant(y), Scalar::Constant(z)) => Value::Vector(Vector::Constant([x, y, z])),
+                    (x, y, z) => Value::Vector(Vector::Construct(Box::new(x), Box::new(y), Box::new(z))),
+                })
+            },
+            "min" | "max" | "union" | "intersect" => {
+                let op = if name == "min" || name == "union" { Op::Min } else { Op::Max };
+                if (name == "min" || name == "max") && signature("vv").is_ok() {
+                    return Ok(Value::Vector(Vector::Binary(op, take_vector(values), take_vector(values))));
+                }
+                signature("ss")?;
+                Ok(Value::Scalar(Scalar::Binary(op, take_scalar(values), take_scalar(values))))
+            },
+            "subtract" => {
+                //Cuts the second shape out of the first
+                signature("ss")?;
+                let (a, b) = (take_scalar(values), take_scalar(values));
+                Ok(Value::Scalar(Scalar::Binary(Op::Max, a, Box::new(Scalar::Negate(b)))))
+            },
+            "mod" | "pow" => {
+                signature("ss")?;
+                let op = if name == "mod" { Op::Mod } else { Op::Pow };
+                Ok(Value::Scalar(Scalar::Binary(op, take_scalar(values), take_scalar(values))))
+            },
+            "clamp" => {
+                signature("sss")?;
+                Ok(Value::Scalar(Scalar::Clamp(take_scalar(values), take_scalar(values), take_scalar(values))))
+            },
+            "smin" | "smax" => {
+                signature("sss")?;
+                let (a, b, k) = (take_scalar(values), take_scalar(values), take_scalar(values));
+                Ok(Value::Scalar(if name == "smin" { Scalar::SmoothMin(a, b, k) } else { Scalar::SmoothMax(a, b, k) }))
+            },
+            "length" => {
+                signature("v")?;
+                Ok(Value::Scalar(Scalar::Length(take_vector(values))))
+            },
+            "dot" => {
+                signature("vv")?;
+                Ok(Value::Scalar(Scalar::Dot(take_vector(values), take_vector(values))))
+            },
+            "normalize" => {
+                signature("v")?;
+                Ok(Value::Vector(Vector::Normalize(take_vector(values))))
+            },
+            "rotate_x" | "rotate_y" | "rotate_z" => {
+                signature("vs")?;
+                let axis = match name {
+                    "rotate_x" => 0,
+                    "rotate_y" => 1,
+                    _ => 2,
+                };
+                let v = take_vector(values);
+                Ok(Value::Vector(Vector::Rotate(axis, v, take_scalar(values))))
+            },
+            "repeat" => {
+                signature("vv")?;
+                Ok(Value::Vector(Vector::Repeat(take_vector(values), take_vector(values))))
+            },
+            "sphere" => {
+                signature("vs")?;
+                Ok(Value::Scalar(Scalar::Sphere(take_vector(values), take_scalar(values))))
+            },
+            "box" => {
+                signature("vv")?;
+                Ok(Value::Scalar(Scalar::Box(take_vector(values), take_vector(values))))
+            },
+            "round_box" => {
+                signature("vvs")?;
+                Ok(Value::Scalar(Scalar::RoundBox(take_vector(values), take_vector(values), take_scalar(values))))
+            },
+            "torus" => {
+                signature("vss")?;
+                Ok(Value::Scalar(Scalar::Torus(take_vector(values), take_scalar(values), take_scalar(values))))
+            },
+            "capsule" => {
+                signature("vss")?;
+                Ok(Value::Scalar(Scalar::Capsule(take_vector(values), take_scalar(values), take_scalar(values))))
+            },
+            "cylinder" => {
+                signature("vss")?;
+                Ok(Value::Scalar(Scalar::Cylinder(take_vector(values), take_scalar(values), take_scalar(values))))
+            },
+            _ => error(format!("Unknown function '{}'", name), position),
+        }
+    }
+}
+
+//Only used after the signature has been checked, so the kinds always match
+fn take_scalar<I: Iterator<Item = Value>>(values: &mut I) -> Box<Scalar> {
+    match values.next() {
+        Some(Value::Scalar(s)) => Box::new(s),
+        _ => unreachable!(),
+    }
+}
+
+fn take_vector<I: Iterator<Item = Value>>(values: &mut I) -> Box<Vector> {
+    match values.next() {
+        Some(Value::Vector(v)) => Box::new(v),
+        _ => unreachable!(),
+    }
+}
+
+#[derive(Clone)]
+pub struct Expression {
+    pub source: String,
+    parameters: Vec<(String, f32)>,
+    values: Vec<f32>,
+    root: Scalar,
+}
+
+impl Expression {
+    //parameters are the names, other than p, that the expression may use along with their starting values
+    pub fn parse(source: &str, parameters: &[(&str, f32)]) -> Result<Expression, ExpressionError> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            current: 0,
+            depth: 0,
+        };
+        let ast = parser.sum()?;
+        if *parser.peek() != Token::End {
+            return error(format!("Expected the expression to end but found {}", describe(parser.peek())), parser.position());
+        }
+
+        let parameters: Vec<(String, f32)> = parameters.iter().map(|(name, value)| (name.to_string(), *value)).collect();
+        let compiler = Compiler {
+            parameters: &parameters,
+        };
+        let root = match compiler.compile(&ast)? {
+            Value::Scalar(root) => root,
+            Value::Vector(_) => return error("The expression has to give a distance, not a vector".to_string(), 0),
+        };
+
+        Ok(Expression {
+            source: source.to_string(),
+            values: parameters.iter().map(|(_, value)| *value).collect(),
+            parameters,
+            root,
+        })
+    }
+
+    //Returns false if the expression has no parameter with that name
+    pub fn set_parameter(&mut self, name: &str, value: f32) -> bool {
+        match self.parameters.iter().position(|(n, _)| n == name) {
+            Some(idx) => {
+                self.values[idx] = value;
+                true
+            },
+            None => false,
+        }
+    }
+
+    //Names and current values, in the order they were given to parse
+    pub fn parameters(&self) -> Vec<(&str, f32)> {
+        self.parameters.iter().zip(self.values.iter()).map(|((name, _), value)| (name.as_str(), *value)).collect()
+    }
+
+    pub fn evaluate(&self, p: Vector3<f32>) -> f32 {
+        self.root.eval(p, &self.values)
+    }
+}
+
+impl DistanceField for Expression {
+    fn distance(&self, p: Vector3<f32>) -> f32 {
+        self.evaluate(p)
+    }
+
+    fn expression(&self) -> Option<&Expression> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(source: &str, p: Vector3<f32>) -> f32 {
+        Expression::parse(source, &[("r", 0.5)]).unwrap().evaluate(p)
+    }
+
+    fn parse_error(source: &str) -> ExpressionError {
+        match Expression::parse(source, &[]) {
+            Ok(_) => panic!("{} should not parse", source),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(eval("1 + 2 * 3 - -4 / 2", [0.0, 0.0, 0.0]), 9.0);
+        assert_eq!(eval("(1 + 2) * 3", [0.0, 0.0, 0.0]), 9.0);
+        assert_eq!(eval("p.y * 2 + r", [0.0, 3.0, 0.0]), 6.5);
+        assert_eq!(eval("length(vec3(1, 2, 2) * 2)", [0.0, 0.0, 0.0]), 6.0);
+        assert_eq!(eval("dot(abs(p), vec3(1)) + 1e-1", [-1.0, 2.0, -3.0]), 6.1);
+    }
+
+    #[test]
+    fn shapes() {
+        let source = "smin(sphere(p - vec3(0, 1, 0), 1), box(p, vec3(1)), 0.3)";
+        let mut expression = Expression::parse(source, &[]).unwrap();
+        assert!((expression.evaluate([0.0, 3.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert!((expression.evaluate([0.0, -3.0, 0.0]) - 2.0).abs() < 1e-6);
+        //Outside both shapes, but the blend pulls the surface out where they meet
+        assert!(expression.evaluate([1.0, 1.1, 0.0]) < 0.0);
+
+        assert!((eval("subtract(box(p, vec3(1)), sphere(p, r))", [0.0, 0.0, 0.0]) - 0.5).abs() < 1e-6);
+        assert!((eval("torus(rotate_x(p, 90), 1, r)", [1.0, 0.0, 0.0]) + 0.5).abs() < 1e-6);
+
+        let mut radius = Expression::parse("sphere(p, r)", &[("r", 1.0)]).unwrap();
+        assert!(radius.set_parameter("r", 2.0));
+        assert!(!radius.set_parameter("q", 2.0));
+        assert_eq!(radius.evaluate([3.0, 0.0, 0.0]), 1.0);
+        expression = radius;
+        assert_eq!(expression.source, "sphere(p, r)");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse_error("sphere(p, 1"), ExpressionError { message: "Expected ')' but found the end of the expression".to_string(), position: 11 });
+        assert_eq!(parse_error("sphere(p, q)").to_string(), "Unknown name 'q' at column 11");
+        assert_eq!(parse_error("sphere(1, p)").message, "Argument 1 of sphere should be a vector but is a number");
+        assert_eq!(parse_error("box(p)").message, "box takes 2 arguments but got 1");
+        assert_eq!(parse_error("blob(p)").message, "Unknown function 'blob'");
+        assert_eq!(parse_error("p + 1").message, "Can't use '+' on a vector and a number");
+        assert_eq!(parse_error("p").message, "The expression has to give a distance, not a vector");
+        assert_eq!(parse_error("1 $ 2").position, 2);
+        assert_eq!(parse_error("1 2").message, "Expected the expression to end but found the number 2");
+    }
+
+    #[test]
+    fn nesting() {
+        let deep = |open: &str, inner: &str, close: &str, n: usize| format!("{}{}{}", open.repeat(n), inner, close.repeat(n));
+        assert_eq!(eval(&deep("(", "1", ")", 200), [0.0; 3]), 1.0);
+        assert_eq!(eval(&deep("abs(", "-1", ")", 200), [0.0; 3]), 1.0);
+        assert_eq!(eval(&deep("", "1", " + 1", 200), [0.0; 3]), 201.0);
+
+        //Stops at the first level too many instead of overflowing the stack
+        let error = parse_error(&deep("(", "1", ")", 100000));
+        assert_eq!((error.message.as_str(), error.position), ("Expression is nested more than 256 levels deep", 256));
+        assert_eq!(parse_error(&deep("-", "1", "", 100000)).position, 256);
+        assert_eq!(parse_error(&deep("", "1", "*1", 100000)).position, 1 + 256 * 2);
+        assert_eq!(parse_error(&deep("sin(", "1", ")", 100000)).position, 256 * 4);
+        assert_eq!(parse_error(&deep("", "p", ".x", 200000)).position, 257 * 2);
+    }
+}
@@ -0,0 +1,137 @@
+extern crate vecmath as vmath;
+use vmath::{
+    Vector3,
+};
+
+//Distance estimators for the fractal SDF types. They work on a fractal of roughly unit size,
+//and return the distance along with an orbit trap in 0..1 that is used to colour the surface.
+
+fn clamp(x: f32, a: f32, b: f32) -> f32 {
+    if x < a { return a };
+    if x > b { return b };
+    x
+}
+
+//Trades detail for speed. Fractals are the only thing using it for now, through their iteration count.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Quality {
+    Low,
+    Medium,
+    High,
+}
+
+impl Quality {
+    pub fn mandelbulb_iterations(&self) -> u32 {
+        match self {
+            Quality::Low => 4,
+            Quality::Medium => 8,
+            Quality::High => 12,
+        }
+    }
+
+    pub fn menger_iterations(&self) -> u32 {
+        match self {
+            Quality::Low => 2,
+            Quality::Medium => 3,
+            Quality::High => 5,
+        }
+    }
+
+    pub fn sierpinski_iterations(&self) -> u32 {
+        match self {
+            Quality::Low => 5,
+            Quality::Medium => 8,
+            Quality::High => 12,
+        }
+    }
+}
+
+pub fn mandelbulb(p: Vector3<f32>, power: f32, iterations: u32) -> (f32, f32) {
+    let mut z = p;
+    let mut dr = 1.0;
+    let mut r = vmath::vec3_len(z);
+    let mut trap = f32::MAX;
+
+    for _ in 0..iterations {
+        if r > 2.0 {
+            break;
+        }
+        trap = trap.min(r);
+        if r < 1e-6 {
+            //The angles below are undefined at the origin
+            break;
+        }
+
+        let theta = (z[2] / r).acos() * power;
+        let phi = z[1].atan2(z[0]) * power;
+        dr = r.powf(power - 1.0) * power * dr + 1.0;
+
+        let zr = r.powf(power);
+        z = vmath::vec3_add([zr * theta.sin() * phi.cos(), zr * theta.sin() * phi.sin(), zr * theta.cos()], p);
+        r = vmath::vec3_len(z);
+    }
+
+    if r < 1e-6 {
+        return (0.0, 0.0);
+    }
+    (0.5 * r.ln() * r / dr, clamp(trap, 0.0, 1.0))
+}
+
+//Cube of size 2 with the middle of every face and the centre carved out, repeated
+pub fn menger(p: Vector3<f32>, iterations: u32) -> (f32, f32) {
+    let q = [p[0].abs() - 1.0, p[1].abs() - 1.0, p[2].abs() - 1.0];
+    let mut d = vmath::vec3_len([q[0].max(0.0), q[1].max(0.0), q[2].max(0.0)]) + q[0].max(q[1].max(q[2])).min(0.0);
+    let mut trap = 1.0;
+
+    let mut s = 1.0;
+    for m in 0..iterations {
+        let a = [
+            (p[0] * s).rem_euclid(2.0) - 1.0,
+            (p[1] * s).rem_euclid(2.0) - 1.0,
+            (p[2] * s).rem_euclid(2.0) - 1.0,
+        ];
+        s *= 3.0;
+        let r = [(1.0 - 3.0 * a[0].abs()).abs(), (1.0 - 3.0 * a[1].abs()).abs(), (1.0 - 3.0 * a[2].abs()).abs()];
+        let da = r[0].max(r[1]);
+        let db = r[1].max(r[2]);
+        let dc = r[2].max(r[0]);
+        let c = (da.min(db.min(dc)) - 1.0) / s;
+
+        //Remember how deep the hole that shaped this point is
+        if c > d {
+            d = c;
+            trap = m as f32 / iterations as f32;
+        }
+    }
+
+    (d, trap)
+}
+
+//Tetrahedron with corners at (1, 1, 1), (-1, -1, 1), (1, -1, -1) and (-1, 1, -1), folded in on itself
+pub fn sierpinski(p: Vector3<f32>, iterations: u32) -> (f32, f32) {
+    let mut z = p;
+    let mut trap = f32::MAX;
+
+    for _ in 0..iterations {
+        if z[0] + z[1] < 0.0 { z = [-z[1], -z[0], z[2]]; }
+        if z[0] + z[2] < 0.0 { z = [-z[2], z[1], -z[0]]; }
+        if z[1] + z[2] < 0.0 { z = [z[0], -z[2], -z[1]]; }
+        z = [z[0] * 2.0 - 1.0, z[1] * 2.0 - 1.0, z[2] * 2.0 - 1.0];
+        trap = trap.min(vmath::vec3_dot(z, z));
+    }
+
+    //Points on the fractal stay within sqrt(3) of the origin, anything else escapes. Every iteration
+    //doubles the scale, so the distance of the final point is scaled back down.
+    let d = (vmath::vec3_len(z) - 2.0) * 2.0f32.powi(-(iterations as i32));
+    (d, clamp(trap.sqrt() / 2.0, 0.0, 1.0))
+}
+
+//Smooth rainbow-ish gradient, from https://iquilezles.org/articles/palettes/
+pub fn palette(t: f32) -> Vector3<f32> {
+    let tau = 2.0 * std::f32::consts::PI;
+    [
+        0.5 + 0.5 * (tau * (t + 0.0)).cos(),
+        0.5 + 0.5 * (tau * (t + 0.1)).cos(),
+        0.5 + 0.5 * (tau * (t + 0.2)).cos(),
+    ]
+}
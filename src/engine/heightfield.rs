@@ -0,0 +1,259 @@
+extern crate vecmath as vmath;
+use vmath::{
+    Vector3,
+};
+
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+use super::texture::Pattern;
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+fn clamp(x: f32, a: f32, b: f32) -> f32 {
+    if x < a { return a };
+    if x > b { return b };
+    x
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+//Terrain defined by a grid of heights, centred on the origin of the SDF.
+//Everything below the surface counts as inside, like a solid column of ground.
+#[derive(Clone)]
+pub struct Heightfield {
+    pub width: usize, //Samples along x
+    pub depth: usize, //Samples along z
+    pub heights: Vec<f32>, //0..1, x changes fastest
+    pub size: Vector3<f32>, //World size along x, the height of a value of 1, and the size along z
+    //Height fraction where each colour starts, sorted from low to high. Empty uses the SDF colour
+    pub bands: Vec<(f32, Vector3<u8>)>,
+    //Vertical distance overestimates the real distance on slopes, this scales it back down
+    distance_scale: f32,
+}
+
+impl Heightfield {
+    pub fn new(width: usize, depth: usize, heights: Vec<f32>, size: Vector3<f32>) -> Result<Heightfield> {
+        if width < 2 || depth < 2 {
+            return Err(invalid("Heightfield needs at least 2x2 samples"));
+        }
+        if width.checked_mul(depth) != Some(heights.len()) {
+            return Err(invalid("Heightfield has the wrong number of samples"));
+        }
+        Ok(Heightfield::from_samples(width, depth, heights, size))
+    }
+
+    //new without the checks, for samples that are known to fit
+    fn from_samples(width: usize, depth: usize, heights: Vec<f32>, size: Vector3<f32>) -> Heightfield {
+        //The steepest slope along each axis bounds how fast the surface can rise
+        let cell = [size[0] / (width - 1) as f32, size[2] / (depth - 1) as f32];
+        let mut slope: [f32; 2] = [0.0, 0.0];
+        for z in 0..depth {
+            for x in 0..width {
+                let h = heights[x + z * width];
+                if x + 1 < width {
+                    slope[0] = slope[0].max((heights[x + 1 + z * width] - h).abs() * size[1] / cell[0]);
+                }
+                if z + 1 < depth {
+                    slope[1] = slope[1].max((heights[x + (z + 1) * width] - h).abs() * size[1] / cell[1]);
+                }
+            }
+        }
+        let gradient = (slope[0] * slope[0] + slope[1] * slope[1]).sqrt();
+
+        Heightfield {
+            width,
+            depth,
+            heights,
+            size,
+            bands: Vec::new(),
+            distance_scale: 1.0 / (1.0 + gradient * gradient).sqrt(),
+        }
+    }
+
+    //Fractal noise terrain, octaves of perlin noise from the texture patterns.
+    //frequency is how many noise features fit across the terrain.
+    pub fn from_noise(resolution: usize, size: Vector3<f32>, frequency: f32, octaves: u32) -> Heightfield {
+        let resolution = resolution.max(2);
+        let pattern = Pattern::Fbm { octaves };
+        let mut heights = Vec::with_capacity(resolution * resolution);
+        for z in 0..resolution {
+            for x in 0..resolution {
+                let u = x as f32 / (resolution - 1) as f32 * frequency;
+                let v = z as f32 / (resolution - 1) as f32 * frequency;
+                heights.push(pattern.sample([u, v]));
+            }
+        }
+        Heightfield::from_samples(resolution, resolution, heights, size)
+    }
+
+    //Greyscale PGM, both the plain (P2) and binary (P5) variants
+    pub fn from_pgm(data: &[u8], size: Vector3<f32>) -> Result<Heightfield> {
+        //The header is whitespace separated tokens, with comments starting at #
+        let mut pos = 0;
+        let next_token = |pos: &mut usize| -> Option<String> {
+            let mut token = String::new();
+            while *pos < data.len() {
+                let c = data[*pos] as char;
+                if c == '#' && token.is_empty() {
+                    while *pos < data.len() && data[*pos] != b'\n' {
+                        *pos += 1;
+                    }
+                } else if c.is_ascii_whitespace() {
+                    if !token.is_empty() {
+                        return Some(token);
+                    }
+                } else {
+                    token.push(c);
+                }
+                *pos += 1;
+            }
+            if token.is_empty() { None } else { Some(token) }
+        };
+
+        let magic = next_token(&mut pos).ok_or_else(|| invalid("Empty PGM file"))?;
+        let number = |pos: &mut usize| -> Result<usize> {
+            next_token(pos).and_then(|t| t.parse().ok()).ok_or_else(|| invalid("Bad PGM header"))
+        };
+        let width = number(&mut pos)?;
+        let depth = number(&mut pos)?;
+        let max_value = number(&mut pos)?;
+        if width < 2 || depth < 2 || max_value == 0 || max_value > 65535 {
+            return Err(invalid("Unsupported PGM dimensions"));
+        }
+
+        //Every sample takes at least a byte, so anything the rest of the file can't hold is rejected
+        //before allocating for it
+        let count = width.checked_mul(depth)
+            .filter(|&count| count <= data.len() - pos)
+            .ok_or_else(|| invalid("PGM file is truncated"))?;
+        let mut heights = Vec::new();
+        match magic.as_str() {
+            "P2" => {
+                for _ in 0..count {
+                    heights.push(number(&mut pos)? as f32 / max_value as f32);
+                }
+            },
+            "P5" => {
+                //A single whitespace character separates the header from the pixels
+                pos += 1;
+                let bytes_per_sample = if max_value > 255 { 2 } else { 1 };
+                let pixels = count.checked_mul(bytes_per_sample)
+                    .and_then(|length| data.get(pos..pos.checked_add(length)?))
+                    .ok_or_else(|| invalid("PGM file is truncated"))?;
+                heights.reserve(count);
+                for sample in pixels.chunks(bytes_per_sample) {
+                    let value = if bytes_per_sample == 2 { u16::from_be_bytes([sample[0], sample[1]]) as usize } else { sample[0] as usize };
+                    heights.push(value as f32 / max_value as f32);
+                }
+            },
+            _ => return Err(invalid("Only P2 and P5 PGM files are supported")),
+        }
+
+        Heightfield::new(width, depth, heights, size)
+    }
+
+    pub fn load_pgm(path: &Path, size: Vector3<f32>) -> Result<Heightfield> {
+        Heightfield::from_pgm(&fs::read(path)?, size)
+    }
+
+    //Water, grass, rock and snow
+    pub fn set_terrain_bands(&mut self) {
+        self.bands = vec![
+            (0.0, [30, 70, 160]),
+            (0.3, [60, 140, 50]),
+            (0.6, [110, 100, 90]),
+            (0.8, [240, 240, 245]),
+        ];
+    }
+
+    //Height of the surface above the origin of the SDF, at a local x and z
+    pub fn get_height(&self, x: f32, z: f32) -> f32 {
+        let fx = clamp((x / self.size[0] + 0.5) * (self.width - 1) as f32, 0.0, (self.width - 1) as f32);
+        let fz = clamp((z / self.size[2] + 0.5) * (self.depth - 1) as f32, 0.0, (self.depth - 1) as f32);
+        let ix = (fx.floor() as usize).min(self.width - 2);
+        let iz = (fz.floor() as usize).min(self.depth - 2);
+        let (tx, tz) = (fx - ix as f32, fz - iz as f32);
+
+        let at = |x: usize, z: usize| self.heights[x + z * self.width];
+        let a = lerp(at(ix, iz), at(ix + 1, iz), tx);
+        let b = lerp(at(ix, iz + 1), at(ix + 1, iz + 1), tx);
+        lerp(a, b, tz) * self.size[1]
+    }
+
+    //Blends between the bands around a local height
+    pub fn get_colour(&self, y: f32) -> Option<Vector3<f32>> {
+        let first = self.bands.first()?;
+        let t = y / self.size[1];
+        let to_float = |c: Vector3<u8>| [c[0] as f32 / 255.0, c[1] as f32 / 255.0, c[2] as f32 / 255.0];
+
+        let mut colour = to_float(first.1);
+        for window in self.bands.windows(2) {
+            let (start, end) = (window[0].0, window[1].0);
+            //Short blend right below each band so they don't meet with a hard line
+            let blend = clamp((t - (end - 0.05)) / 0.05, 0.0, 1.0);
+            if t >= start {
+                let (a, b) = (to_float(window[0].1), to_float(window[1].1));
+                colour = [lerp(a[0], b[0], blend), lerp(a[1], b[1], blend), lerp(a[2], b[2], blend)];
+            }
+        }
+        Some(colour)
+    }
+
+    pub fn get_distance(&self, p: Vector3<f32>) -> f32 {
+        let dy = (p[1] - self.get_height(p[0], p[2])) * self.distance_scale;
+
+        //Outside the footprint the horizontal distance to it is also a lower bound
+        let dx = (p[0].abs() - self.size[0] * 0.5).max(0.0);
+        let dz = (p[2].abs() - self.size[2] * 0.5).max(0.0);
+        let outside = (dx * dx + dz * dz).sqrt();
+        if outside > 0.0 {
+            return outside.max(dy);
+        }
+        dy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pgm() {
+        let plain = b"P2\n# a ramp\n3 2\n4\n0 2 4\n0 2 4\n";
+        let field = Heightfield::from_pgm(plain, [2.0, 1.0, 2.0]).unwrap();
+        assert_eq!(field.heights, vec![0.0, 0.5, 1.0, 0.0, 0.5, 1.0]);
+
+        let mut binary = b"P5 3 2 255\n".to_vec();
+        binary.extend_from_slice(&[0, 51, 255, 0, 51, 255]);
+        let field = Heightfield::from_pgm(&binary, [2.0, 1.0, 2.0]).unwrap();
+        assert!((field.heights[1] - 0.2).abs() < 1e-6);
+
+        assert!(Heightfield::from_pgm(b"P5 3 2 255\n\x00", [1.0, 1.0, 1.0]).is_err());
+        for header in &["P2 4294967296 4294967296 255\n", "P2 100000 100000 255\n0 0 0", "P5 4294967296 4294967297 65535\n\x00"] {
+            let error = Heightfield::from_pgm(header.as_bytes(), [1.0, 1.0, 1.0]).err().unwrap();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+        }
+        assert!(Heightfield::new(1, 2, vec![0.0; 2], [1.0, 1.0, 1.0]).is_err());
+        assert!(Heightfield::new(usize::MAX, 2, vec![0.0; 2], [1.0, 1.0, 1.0]).is_err());
+        assert!(Heightfield::new(2, 2, vec![0.0; 3], [1.0, 1.0, 1.0]).is_err());
+    }
+
+    #[test]
+    fn distance_is_a_bound() {
+        //A ramp rising 1 over 2 units
+        let field = Heightfield::new(2, 2, vec![0.0, 1.0, 0.0, 1.0], [2.0, 1.0, 2.0]).unwrap();
+        assert!((field.get_height(0.0, 0.0) - 0.5).abs() < 1e-6);
+
+        //Straight above the middle, the real distance to the slope is 1.5 / sqrt(1.25)
+        let d = field.get_distance([0.0, 2.0, 0.0]);
+        assert!(d > 0.0 && d <= 1.5 / 1.25f32.sqrt() + 1e-4);
+        assert!(field.get_distance([0.0, -1.0, 0.0]) < 0.0);
+        assert!((field.get_distance([4.0, -1.0, 0.0]) - 3.0).abs() < 1e-4);
+    }
+}
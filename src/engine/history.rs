@@ -0,0 +1,146 @@
+use crate::engine::{
+    distance_field::SDF,
+    scene::Scene,
+};
+
+//A change to the objects of a scene, with what it needs to be undone
+#[derive(Clone)]
+pub enum Edit {
+    Add { idx: usize, sdf: SDF },
+    Remove { idx: usize, sdf: SDF },
+    Replace { idx: usize, before: SDF, after: SDF },
+}
+
+impl Edit {
+    pub fn apply(&self, scene: &mut Scene) {
+        match self {
+            Edit::Add { idx, sdf } => scene.insert_sdf(*idx, sdf.clone()),
+            Edit::Remove { idx, .. } => { scene.remove_sdf(*idx); },
+            Edit::Replace { idx, after, .. } => { scene.replace_sdf(*idx, after.clone()); },
+        }
+    }
+
+    pub fn revert(&self, scene: &mut Scene) {
+        match self {
+            Edit::Add { idx, .. } => { scene.remove_sdf(*idx); },
+            Edit::Remove { idx, sdf } => scene.insert_sdf(*idx, sdf.clone()),
+            Edit::Replace { idx, before, .. } => { scene.replace_sdf(*idx, before.clone()); },
+        }
+    }
+
+    //The object that was edited, None if it's gone
+    pub fn selection(&self, applied: bool) -> Option<usize> {
+        match (self, applied) {
+            (Edit::Add { .. }, false) | (Edit::Remove { .. }, true) => None,
+            (Edit::Add { idx, .. }, true) | (Edit::Remove { idx, .. }, false) | (Edit::Replace { idx, .. }, _) => Some(*idx),
+        }
+    }
+}
+
+//Edits that were made and undone, so they can be undone and redone. Edits are recorded after
+//they were made to the scene, push doesn't apply them.
+pub struct History {
+    //Oldest edits are dropped past this
+    pub limit: usize,
+    done: Vec<Edit>,
+    undone: Vec<Edit>,
+}
+
+impl Default for History {
+    fn default() -> History {
+        History::new()
+    }
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            limit: 256,
+            done: Vec::new(),
+            undone: Vec::new(),
+        }
+    }
+
+    //Anything undone can't be redone after a new edit
+    pub fn push(&mut self, edit: Edit) {
+        self.undone.clear();
+        self.done.push(edit);
+        if self.done.len() > self.limit {
+            self.done.remove(0);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.done.is_empty() && self.undone.is_empty()
+    }
+
+    //Returns the edit that was undone
+    pub fn undo(&mut self, scene: &mut Scene) -> Option<&Edit> {
+        let edit = self.done.pop()?;
+        edit.revert(scene);
+        self.undone.push(edit);
+        self.undone.last()
+    }
+
+    pub fn redo(&mut self, scene: &mut Scene) -> Option<&Edit> {
+        let edit = self.undone.pop()?;
+        edit.apply(scene);
+        self.done.push(edit);
+        self.done.last()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rendering::raymarching::Ray;
+
+    fn colours(scene: &Scene) -> Vec<u8> {
+        scene.distance_fields.iter().map(|sdf| sdf.colour[0]).collect()
+    }
+
+    #[test]
+    fn undo_redo() {
+        let mut scene = Scene::new();
+        scene.push_sdf(SDF::new_plane(-1.0, [1, 0, 0]));
+        scene.push_sdf(SDF::new_sphere([0.0, 0.0, 5.0], 1.0, [2, 0, 0]));
+        scene.build_bvh();
+        let mut history = History::new();
+
+        let sdf = SDF::new_sphere([3.0, 0.0, 5.0], 1.0, [3, 0, 0]);
+        let idx = scene.push_sdf(sdf.clone());
+        history.push(Edit::Add { idx, sdf });
+
+        let before = scene.distance_fields[1].clone();
+        scene.update_position(1, [0.0, 0.0, 8.0]);
+        history.push(Edit::Replace { idx: 1, before, after: scene.distance_fields[1].clone() });
+
+        let sdf = scene.remove_sdf(0);
+        history.push(Edit::Remove { idx: 0, sdf });
+        assert_eq!(colours(&scene), vec![2, 3]);
+
+        //Removing rebuilt the BVH, so the sphere is still found at its new index
+        let ray = Ray::new([0.0, 0.0, 0.0], [0.0, 0.0, 1.0]);
+        assert_eq!(scene.cast(&ray).object, Some(0));
+        assert!((scene.cast(&ray).distance - 7.0).abs() < 0.05);
+
+        assert_eq!(history.undo(&mut scene).unwrap().selection(false), Some(0));
+        assert_eq!(colours(&scene), vec![1, 2, 3]);
+        history.undo(&mut scene);
+        assert_eq!(scene.distance_fields[1].position, [0.0, 0.0, 5.0]);
+        assert_eq!(history.undo(&mut scene).unwrap().selection(false), None);
+        assert_eq!(colours(&scene), vec![1, 2]);
+        assert!(history.undo(&mut scene).is_none());
+
+        let revision = scene.revision();
+        assert_eq!(history.redo(&mut scene).unwrap().selection(true), Some(2));
+        history.redo(&mut scene);
+        assert!(scene.revision() > revision);
+        assert_eq!(scene.distance_fields[1].position, [0.0, 0.0, 8.0]);
+
+        //A new edit drops what's left to redo
+        history.push(Edit::Remove { idx: 2, sdf: scene.remove_sdf(2) });
+        assert!(history.redo(&mut scene).is_none());
+        assert_eq!(colours(&scene), vec![1, 2]);
+    }
+}
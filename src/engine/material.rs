@@ -0,0 +1,46 @@
+use crate::engine::texture::Texture;
+
+//Surface properties used when a ray hits an SDF.
+//The albedo still lives in SDF::colour, this describes how light bounces off it
+//and optionally a texture that varies the albedo over the surface.
+#[derive(Copy, Clone)]
+pub struct Material {
+    pub reflectivity: f32, //0 is fully diffuse, 1 is a perfect mirror
+    pub transparency: f32, //0 is opaque, 1 only shows what's behind/through the surface
+    pub ior: f32, //Index of refraction, only used for transparent materials
+    pub texture: Option<Texture>,
+}
+
+impl Material {
+    pub fn new_diffuse() -> Material {
+        Material {
+            reflectivity: 0.0,
+            transparency: 0.0,
+            ior: 1.0,
+            texture: None,
+        }
+    }
+
+    pub fn new_glossy(reflectivity: f32) -> Material {
+        Material {
+            reflectivity,
+            transparency: 0.0,
+            ior: 1.0,
+            texture: None,
+        }
+    }
+
+    //NOTE: Glass gets its reflections from the fresnel term, so reflectivity stays 0
+    pub fn new_glass(transparency: f32, ior: f32) -> Material {
+        Material {
+            reflectivity: 0.0,
+            transparency,
+            ior,
+            texture: None,
+        }
+    }
+
+    pub fn set_texture(&mut self, texture: Texture) {
+        self.texture = Some(texture);
+    }
+}
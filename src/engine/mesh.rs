@@ -0,0 +1,377 @@
+extern crate vecmath as vmath;
+use vmath::{
+    Vector3,
+};
+
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+use super::bvh::{self, vec_min, vec_max, Bounds, BvhNode, FAR_BOUNDS_DISTANCE};
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+//Squared distance from p to an axis aligned box, 0 inside it
+fn box_distance_sq(min: Vector3<f32>, max: Vector3<f32>, p: Vector3<f32>) -> f32 {
+    let mut d = 0.0;
+    for i in 0..3 {
+        let v = (min[i] - p[i]).max(0.0).max(p[i] - max[i]);
+        d += v * v;
+    }
+    d
+}
+
+#[derive(Copy, Clone)]
+pub struct Triangle {
+    pub a: Vector3<f32>,
+    pub b: Vector3<f32>,
+    pub c: Vector3<f32>,
+}
+
+impl Triangle {
+    fn bounds(&self) -> Bounds {
+        (vec_min(self.a, vec_min(self.b, self.c)), vec_max(self.a, vec_max(self.b, self.c)))
+    }
+
+    //Closest point on the triangle, from Real-Time Collision Detection by Christer Ericson
+    fn closest_point(&self, p: Vector3<f32>) -> Vector3<f32> {
+        let (a, b, c) = (self.a, self.b, self.c);
+        let ab = vmath::vec3_sub(b, a);
+        let ac = vmath::vec3_sub(c, a);
+        let ap = vmath::vec3_sub(p, a);
+
+        let d1 = vmath::vec3_dot(ab, ap);
+        let d2 = vmath::vec3_dot(ac, ap);
+        if d1 <= 0.0 && d2 <= 0.0 { return a; }
+
+        let bp = vmath::vec3_sub(p, b);
+        let d3 = vmath::vec3_dot(ab, bp);
+        let d4 = vmath::vec3_dot(ac, bp);
+        if d3 >= 0.0 && d4 <= d3 { return b; }
+
+        let vc = d1 * d4 - d3 * d2;
+        if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+            let v = d1 / (d1 - d3);
+            return vmath::vec3_add(a, vmath::vec3_scale(ab, v));
+        }
+
+        let cp = vmath::vec3_sub(p, c);
+        let d5 = vmath::vec3_dot(ab, cp);
+        let d6 = vmath::vec3_dot(ac, cp);
+        if d6 >= 0.0 && d5 <= d6 { return c; }
+
+        let vb = d5 * d2 - d1 * d6;
+        if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+            let w = d2 / (d2 - d6);
+            return vmath::vec3_add(a, vmath::vec3_scale(ac, w));
+        }
+
+        let va = d3 * d6 - d5 * d4;
+        if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
+            let w = (d4 - d3) / ((d4 - d3) + (d5 - d6));
+            return vmath::vec3_add(b, vmath::vec3_scale(vmath::vec3_sub(c, b), w));
+        }
+
+        let denom = 1.0 / (va + vb + vc);
+        let v = vb * denom;
+        let w = vc * denom;
+        vmath::vec3_add(a, vmath::vec3_add(vmath::vec3_scale(ab, v), vmath::vec3_scale(ac, w)))
+    }
+
+    //Moller-Trumbore, only reports hits in front of the origin
+    fn intersects(&self, origin: Vector3<f32>, direction: Vector3<f32>) -> bool {
+        let e1 = vmath::vec3_sub(self.b, self.a);
+        let e2 = vmath::vec3_sub(self.c, self.a);
+        let h = vmath::vec3_cross(direction, e2);
+        let det = vmath::vec3_dot(e1, h);
+        if det.abs() < 1e-9 {
+            return false;
+        }
+        let inv_det = 1.0 / det;
+        let s = vmath::vec3_sub(origin, self.a);
+        let u = inv_det * vmath::vec3_dot(s, h);
+        if !(0.0..=1.0).contains(&u) {
+            return false;
+        }
+        let q = vmath::vec3_cross(s, e1);
+        let v = inv_det * vmath::vec3_dot(direction, q);
+        if v < 0.0 || u + v > 1.0 {
+            return false;
+        }
+        inv_det * vmath::vec3_dot(e2, q) > 0.0
+    }
+}
+
+//A closed triangle mesh, with a bounding volume hierarchy for distance queries
+#[derive(Clone)]
+pub struct Mesh {
+    pub triangles: Vec<Triangle>,
+    nodes: Vec<BvhNode>,
+}
+
+impl Mesh {
+    pub fn new(triangles: Vec<Triangle>) -> Mesh {
+        //Leaves of the tree cover contiguous ranges, so keep the triangles in its order
+        let mut items: Vec<_> = triangles.iter().enumerate().map(|(i, t)| (i, t.bounds())).collect();
+        let nodes = bvh::build_tree(&mut items);
+        Mesh {
+            triangles: items.iter().map(|&(i, _)| triangles[i]).collect(),
+            nodes,
+        }
+    }
+
+    //Picks the loader from the file extension
+    pub fn load(path: &Path) -> Result<Mesh> {
+        let extension = path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+        match extension.as_deref() {
+            Some("obj") => Mesh::from_obj(&fs::read_to_string(path)?),
+            Some("stl") => Mesh::from_stl(&fs::read(path)?),
+            _ => Err(invalid(format!("Unsupported mesh format: {}", path.display()))),
+        }
+    }
+
+    //Wavefront OBJ, only the vertices and faces are used. Polygons are triangulated as a fan.
+    pub fn from_obj(source: &str) -> Result<Mesh> {
+        let mut vertices: Vec<Vector3<f32>> = Vec::new();
+        let mut triangles = Vec::new();
+
+        for (line_idx, line) in source.lines().enumerate() {
+            let mut parts = line.split_whitespace();
+            match parts.next() {
+                Some("v") => {
+                    let mut v = [0.0; 3];
+                    for item in &mut v {
+                        *item = parts.next()
+                            .and_then(|s| s.parse().ok())
+                            .ok_or_else(|| invalid(format!("line {}: bad vertex", line_idx + 1)))?;
+                    }
+                    vertices.push(v);
+                },
+                Some("f") => {
+                    let mut face = Vec::new();
+                    for part in parts {
+                        //Faces can look like 1, 1/2, 1//3 or 1/2/3, we only need the first index
+                        let idx: i64 = part.split('/').next()
+                            .and_then(|s| s.parse().ok())
+                            .ok_or_else(|| invalid(format!("line {}: bad face index '{}'", line_idx + 1, part)))?;
+                        //Negative indices count back from the latest vertex
+                        let resolved = if idx < 0 { vertices.len() as i64 + idx } else { idx - 1 };
+                        if resolved < 0 || resolved as usize >= vertices.len() {
+                            return Err(invalid(format!("line {}: face index {} out of range", line_idx + 1, idx)));
+                        }
+                        face.push(vertices[resolved as usize]);
+                    }
+                    for i in 1..face.len().saturating_sub(1) {
+                        triangles.push(Triangle { a: face[0], b: face[i], c: face[i + 1] });
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        if triangles.is_empty() {
+            return Err(invalid("OBJ file doesn't contain any faces".to_string()));
+        }
+        Ok(Mesh::new(triangles))
+    }
+
+    //Binary or ASCII STL
+    pub fn from_stl(data: &[u8]) -> Result<Mesh> {
+        //ASCII files also start with "solid", but binary exporters sometimes do too,
+        //so check whether the size matches the triangle count in the binary header first
+        if data.len() >= 84 {
+            let count = u32::from_le_bytes([data[80], data[81], data[82], data[83]]) as usize;
+            if data.len() == 84 + count * 50 {
+                return Mesh::from_binary_stl(data, count);
+            }
+        }
+        match std::str::from_utf8(data) {
+            Ok(source) if source.trim_start().starts_with("solid") => Mesh::from_ascii_stl(source),
+            _ => Err(invalid("Not a valid STL file".to_string())),
+        }
+    }
+
+    fn from_binary_stl(data: &[u8], count: usize) -> Result<Mesh> {
+        let read = |offset: usize| f32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+        let mut triangles = Vec::with_capacity(count);
+        for i in 0..count {
+            //Every triangle is a normal, three vertices and a 2 byte attribute count
+            let base = 84 + i * 50 + 12;
+            let vertex = |v: usize| [read(base + v * 12), read(base + v * 12 + 4), read(base + v * 12 + 8)];
+            triangles.push(Triangle { a: vertex(0), b: vertex(1), c: vertex(2) });
+        }
+        if triangles.is_empty() {
+            return Err(invalid("STL file doesn't contain any triangles".to_string()));
+        }
+        Ok(Mesh::new(triangles))
+    }
+
+    fn from_ascii_stl(source: &str) -> Result<Mesh> {
+        let mut vertices = Vec::new();
+        for (line_idx, line) in source.lines().enumerate() {
+            let mut parts = line.split_whitespace();
+            if parts.next() == Some("vertex") {
+                let mut v = [0.0; 3];
+                for item in &mut v {
+                    *item = parts.next()
+                        .and_then(|s| s.parse().ok())
+                        .ok_or_else(|| invalid(format!("line {}: bad vertex", line_idx + 1)))?;
+                }
+                vertices.push(v);
+            }
+        }
+        if vertices.is_empty() || vertices.len() % 3 != 0 {
+            return Err(invalid("STL file has an incomplete triangle".to_string()));
+        }
+        let triangles = vertices.chunks(3).map(|v| Triangle { a: v[0], b: v[1], c: v[2] }).collect();
+        Ok(Mesh::new(triangles))
+    }
+
+    pub fn bounds(&self) -> (Vector3<f32>, Vector3<f32>) {
+        match self.nodes.first() {
+            Some(root) => (root.min, root.max),
+            None => ([0.0; 3], [0.0; 3]),
+        }
+    }
+
+    fn unsigned_distance_sq(&self, p: Vector3<f32>) -> f32 {
+        let mut best = f32::MAX;
+        let mut stack = vec![0];
+        while let Some(idx) = stack.pop() {
+            let node = &self.nodes[idx];
+            if box_distance_sq(node.min, node.max, p) >= best {
+                continue;
+            }
+            if node.count > 0 {
+                for t in &self.triangles[node.first..node.first + node.count] {
+                    let d = vmath::vec3_sub(p, t.closest_point(p));
+                    best = best.min(vmath::vec3_dot(d, d));
+                }
+            } else {
+                //Visit the closer child first, so the far one is more likely to get skipped
+                let (l, r) = (&self.nodes[node.left], &self.nodes[node.right]);
+                if box_distance_sq(l.min, l.max, p) < box_distance_sq(r.min, r.max, p) {
+                    stack.push(node.right);
+                    stack.push(node.left);
+                } else {
+                    stack.push(node.left);
+                    stack.push(node.right);
+                }
+            }
+        }
+        best
+    }
+
+    //Counts crossings along a ray, an odd count means we started inside the mesh
+    fn is_inside(&self, p: Vector3<f32>) -> bool {
+        //Slightly skewed, so the ray doesn't run exactly along the edges of axis aligned meshes
+        let direction = vmath::vec3_normalized([1.0, 0.000_37, 0.000_23]);
+        let mut crossings = 0;
+        let mut stack = vec![0];
+        while let Some(idx) = stack.pop() {
+            let node = &self.nodes[idx];
+            //The ray drifts a tiny bit in y and z, so allow for that when skipping nodes
+            let margin = (node.max[0] - p[0]).max(0.0) * 0.000_5 + 0.000_1;
+            if p[0] > node.max[0] || p[1] < node.min[1] - margin || p[1] > node.max[1] + margin || p[2] < node.min[2] - margin || p[2] > node.max[2] + margin {
+                continue;
+            }
+            if node.count > 0 {
+                for t in &self.triangles[node.first..node.first + node.count] {
+                    if t.intersects(p, direction) {
+                        crossings += 1;
+                    }
+                }
+            } else {
+                stack.push(node.left);
+                stack.push(node.right);
+            }
+        }
+        crossings % 2 == 1
+    }
+
+    pub fn get_distance(&self, p: Vector3<f32>) -> f32 {
+        if self.nodes.is_empty() {
+            return f32::MAX;
+        }
+
+        let (min, max) = self.bounds();
+        let bounds_distance = box_distance_sq(min, max, p).sqrt();
+        if bounds_distance > FAR_BOUNDS_DISTANCE {
+            return bounds_distance;
+        }
+
+        let d = self.unsigned_distance_sq(p).sqrt();
+        if bounds_distance <= 0.0 && self.is_inside(p) {
+            return -d;
+        }
+        d
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CUBE_OBJ: &str = "
+v -1 -1 -1
+v 1 -1 -1
+v 1 1 -1
+v -1 1 -1
+v -1 -1 1
+v 1 -1 1
+v 1 1 1
+v -1 1 1
+f 1 4 3 2
+f 5 6 7 8
+f 1 2 6 5
+f 4 8 7 3
+f 1 5 8 4
+f 2 3 7 6
+";
+
+    #[test]
+    fn obj_cube_distance() {
+        let mesh = Mesh::from_obj(CUBE_OBJ).unwrap();
+        assert_eq!(mesh.triangles.len(), 12);
+        assert!((mesh.get_distance([0.0, 0.0, 0.0]) + 1.0).abs() < 1e-4);
+        assert!((mesh.get_distance([0.5, 0.2, 0.0]) + 0.5).abs() < 1e-4);
+        assert!((mesh.get_distance([1.25, 0.0, 0.0]) - 0.25).abs() < 1e-4);
+        //Far away we only get the bounding box distance, which is exact here anyway
+        assert!((mesh.get_distance([0.0, 5.0, 0.0]) - 4.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn stl_round_trip() {
+        let obj = Mesh::from_obj(CUBE_OBJ).unwrap();
+
+        let mut binary = vec![0u8; 80];
+        binary.extend_from_slice(&(obj.triangles.len() as u32).to_le_bytes());
+        let mut ascii = String::from("solid cube\n");
+        for t in &obj.triangles {
+            binary.extend_from_slice(&[0u8; 12]);
+            ascii.push_str("facet normal 0 0 0\nouter loop\n");
+            for v in &[t.a, t.b, t.c] {
+                for x in v {
+                    binary.extend_from_slice(&x.to_le_bytes());
+                }
+                ascii.push_str(&format!("vertex {} {} {}\n", v[0], v[1], v[2]));
+            }
+            binary.extend_from_slice(&[0u8; 2]);
+            ascii.push_str("endloop\nendfacet\n");
+        }
+        ascii.push_str("endsolid cube\n");
+
+        for mesh in &[Mesh::from_stl(&binary).unwrap(), Mesh::from_stl(ascii.as_bytes()).unwrap()] {
+            assert_eq!(mesh.triangles.len(), 12);
+            assert!((mesh.get_distance([0.0, 0.0, 0.0]) + 1.0).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn obj_errors() {
+        assert!(Mesh::from_obj("v 0 0 0\nf 1 2 3\n").is_err());
+        assert!(Mesh::from_obj("v 0 0\n").is_err());
+    }
+}
@@ -2,3 +2,15 @@ pub mod distance_field;
 pub mod scene;
 pub mod camera;
 pub mod rotation;
+pub mod material;
+pub mod texture;
+pub mod modifier;
+pub mod mesh;
+pub mod volume;
+pub mod heightfield;
+pub mod fractal;
+pub mod expression;
+pub mod bvh;
+pub mod packet;
+pub mod scene_file;
+pub mod history;
@@ -0,0 +1,101 @@
+extern crate vecmath as vmath;
+use vmath::{
+    Vector3,
+};
+
+fn clamp(x: f32, a: f32, b: f32) -> f32 {
+    if x < a { return a };
+    if x > b { return b };
+    x
+}
+
+//Wraps the shape of an SDF. Modifiers are applied in the order they are added,
+//so the last one added wraps everything before it.
+//NOTE: Repetition assumes the shape fits inside one cell, otherwise the distance can overshoot.
+#[derive(Copy, Clone)]
+pub enum Modifier {
+    //Infinite copies every period units. Axes with a period of 0 aren't repeated
+    Repeat { period: Vector3<f32> },
+    //Like Repeat, but only count copies on each side of the original
+    RepeatLimited { period: Vector3<f32>, count: Vector3<f32> },
+    //Mirrors the positive side of the chosen axes onto the negative side
+    Mirror { axes: [bool; 3] },
+    //Twists around the y axis, in radians per unit of height
+    Twist { amount: f32 },
+    //Bends the x axis upwards around the z axis, in radians per unit along x
+    Bend { amount: f32 },
+    //Stretches the shape by cutting it open at the origin and inserting amount on each side
+    Elongate { amount: Vector3<f32> },
+    Round { radius: f32 },
+    //Turns the shape into a hollow shell
+    Onion { thickness: f32 },
+}
+
+impl Modifier {
+    //Moves the point before the wrapped shape is evaluated. Also returns a factor the wrapped
+    //distance has to be multiplied by, because twisting and bending stretch space and a plain
+    //distance would make the marcher overshoot.
+    pub fn apply_domain(&self, p: Vector3<f32>) -> (Vector3<f32>, f32) {
+        match *self {
+            Modifier::Repeat { period } => {
+                let mut q = p;
+                for i in 0..3 {
+                    if period[i] > 0.0 {
+                        q[i] = p[i] - period[i] * (p[i] / period[i]).round();
+                    }
+                }
+                (q, 1.0)
+            },
+            Modifier::RepeatLimited { period, count } => {
+                let mut q = p;
+                for i in 0..3 {
+                    if period[i] > 0.0 {
+                        q[i] = p[i] - period[i] * clamp((p[i] / period[i]).round(), -count[i], count[i]);
+                    }
+                }
+                (q, 1.0)
+            },
+            Modifier::Mirror { axes } => {
+                let mut q = p;
+                for i in 0..3 {
+                    if axes[i] {
+                        q[i] = p[i].abs();
+                    }
+                }
+                (q, 1.0)
+            },
+            Modifier::Twist { amount } => {
+                let angle = amount * p[1];
+                let (s, c) = angle.sin_cos();
+                let q = [c * p[0] - s * p[2], p[1], s * p[0] + c * p[2]];
+                let radius = (p[0] * p[0] + p[2] * p[2]).sqrt();
+                (q, 1.0 / (1.0 + amount.abs() * radius))
+            },
+            Modifier::Bend { amount } => {
+                let angle = amount * p[0];
+                let (s, c) = angle.sin_cos();
+                let q = [c * p[0] - s * p[1], s * p[0] + c * p[1], p[2]];
+                let radius = (p[0] * p[0] + p[1] * p[1]).sqrt();
+                (q, 1.0 / (1.0 + amount.abs() * radius))
+            },
+            Modifier::Elongate { amount } => {
+                let q = [
+                    p[0] - clamp(p[0], -amount[0], amount[0]),
+                    p[1] - clamp(p[1], -amount[1], amount[1]),
+                    p[2] - clamp(p[2], -amount[2], amount[2]),
+                ];
+                (q, 1.0)
+            },
+            Modifier::Round { .. } | Modifier::Onion { .. } => (p, 1.0),
+        }
+    }
+
+    //Changes the distance of the wrapped shape afterwards
+    pub fn apply_distance(&self, d: f32) -> f32 {
+        match *self {
+            Modifier::Round { radius } => d - radius,
+            Modifier::Onion { thickness } => d.abs() - thickness,
+            _ => d,
+        }
+    }
+}
@@ -0,0 +1,224 @@
+extern crate vecmath as vmath;
+use vmath::{
+    Vector3,
+};
+
+use std::ops::{Add, Sub, Mul, Div, Neg, Not};
+
+//Several rays marched together, one per lane. The lanes are plain arrays, the compiler turns the
+//loops over them into SIMD instructions for whatever the function they end up in is compiled for.
+//Everything here is inlined for that reason, see Scene::march_rays for where the width is picked.
+
+//How many rays to march at once on this CPU. 1 means no packets, every ray is marched on its own.
+pub fn lane_count() -> usize {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx") {
+            return 8;
+        }
+        if is_x86_feature_detected!("sse2") {
+            return 4;
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            return 4;
+        }
+    }
+    1
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Lanes<const N: usize>(pub [f32; N]);
+
+//Which lanes a comparison was true for
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Mask<const N: usize>(pub [bool; N]);
+
+//A vector per lane, stored per axis
+pub type Lanes3<const N: usize> = [Lanes<N>; 3];
+
+impl<const N: usize> Lanes<N> {
+    #[inline(always)]
+    pub fn splat(x: f32) -> Lanes<N> {
+        Lanes([x; N])
+    }
+
+    #[inline(always)]
+    pub fn map(self, f: impl Fn(f32) -> f32) -> Lanes<N> {
+        Lanes(std::array::from_fn(|i| f(self.0[i])))
+    }
+
+    #[inline(always)]
+    pub fn zip(self, other: Lanes<N>, f: impl Fn(f32, f32) -> f32) -> Lanes<N> {
+        Lanes(std::array::from_fn(|i| f(self.0[i], other.0[i])))
+    }
+
+    #[inline(always)]
+    pub fn abs(self) -> Lanes<N> {
+        self.map(f32::abs)
+    }
+
+    #[inline(always)]
+    pub fn sqrt(self) -> Lanes<N> {
+        self.map(f32::sqrt)
+    }
+
+    //Same as the min and max helpers of the distance functions, b wins only if it's strictly smaller
+    #[inline(always)]
+    pub fn min(self, other: Lanes<N>) -> Lanes<N> {
+        self.zip(other, |a, b| if b < a { b } else { a })
+    }
+
+    #[inline(always)]
+    pub fn max(self, other: Lanes<N>) -> Lanes<N> {
+        self.zip(other, |a, b| if b > a { b } else { a })
+    }
+
+    #[inline(always)]
+    pub fn clamp(self, lo: Lanes<N>, hi: Lanes<N>) -> Lanes<N> {
+        self.max(lo).min(hi)
+    }
+
+    #[inline(always)]
+    pub fn lt(self, other: Lanes<N>) -> Mask<N> {
+        Mask(std::array::from_fn(|i| self.0[i] < other.0[i]))
+    }
+
+    //Smallest lane
+    #[inline(always)]
+    pub fn reduce_min(self) -> f32 {
+        self.0.iter().fold(f32::MAX, |a, &b| a.min(b))
+    }
+}
+
+impl<const N: usize> Mask<N> {
+    #[inline(always)]
+    pub fn any(self) -> bool {
+        self.0.iter().any(|&b| b)
+    }
+
+    #[inline(always)]
+    pub fn all(self) -> bool {
+        self.0.iter().all(|&b| b)
+    }
+
+    #[inline(always)]
+    pub fn and(self, other: Mask<N>) -> Mask<N> {
+        Mask(std::array::from_fn(|i| self.0[i] && other.0[i]))
+    }
+
+    //Takes a where the mask is set and b everywhere else
+    #[inline(always)]
+    pub fn select(self, a: Lanes<N>, b: Lanes<N>) -> Lanes<N> {
+        Lanes(std::array::from_fn(|i| if self.0[i] { a.0[i] } else { b.0[i] }))
+    }
+}
+
+macro_rules! lane_op {
+    ($trait:ident, $fn:ident, $op:tt) => {
+        impl<const N: usize> $trait for Lanes<N> {
+            type Output = Lanes<N>;
+
+            #[inline(always)]
+            fn $fn(self, other: Lanes<N>) -> Lanes<N> {
+                self.zip(other, |a, b| a $op b)
+            }
+        }
+
+        impl<const N: usize> $trait<f32> for Lanes<N> {
+            type Output = Lanes<N>;
+
+            #[inline(always)]
+            fn $fn(self, other: f32) -> Lanes<N> {
+                self.map(|a| a $op other)
+            }
+        }
+    };
+}
+
+lane_op!(Add, add, +);
+lane_op!(Sub, sub, -);
+lane_op!(Mul, mul, *);
+lane_op!(Div, div, /);
+
+impl<const N: usize> Neg for Lanes<N> {
+    type Output = Lanes<N>;
+
+    #[inline(always)]
+    fn neg(self) -> Lanes<N> {
+        self.map(|a| -a)
+    }
+}
+
+impl<const N: usize> Not for Mask<N> {
+    type Output = Mask<N>;
+
+    #[inline(always)]
+    fn not(self) -> Mask<N> {
+        Mask(std::array::from_fn(|i| !self.0[i]))
+    }
+}
+
+//The vmath functions for packets, with the same order of operations so results match exactly
+
+#[inline(always)]
+pub fn splat3<const N: usize>(v: Vector3<f32>) -> Lanes3<N> {
+    [Lanes::splat(v[0]), Lanes::splat(v[1]), Lanes::splat(v[2])]
+}
+
+#[inline(always)]
+pub fn lane<const N: usize>(v: &Lanes3<N>, i: usize) -> Vector3<f32> {
+    [v[0].0[i], v[1].0[i], v[2].0[i]]
+}
+
+#[inline(always)]
+pub fn len2<const N: usize>(x: Lanes<N>, y: Lanes<N>) -> Lanes<N> {
+    (x * x + y * y).sqrt()
+}
+
+#[inline(always)]
+pub fn len3<const N: usize>(v: Lanes3<N>) -> Lanes<N> {
+    (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt()
+}
+
+#[inline(always)]
+pub fn dot3<const N: usize>(v: Lanes3<N>, w: Vector3<f32>) -> Lanes<N> {
+    v[0] * w[0] + v[1] * w[1] + v[2] * w[2]
+}
+
+#[inline(always)]
+pub fn sub3<const N: usize>(v: Lanes3<N>, w: Vector3<f32>) -> Lanes3<N> {
+    [v[0] - w[0], v[1] - w[1], v[2] - w[2]]
+}
+
+//Lanes where d is closer than closest take d and object i, like the scalar searches do
+#[inline(always)]
+pub fn keep_closest<const N: usize>(closest: &mut Lanes<N>, idx: &mut [i32; N], d: Lanes<N>, i: usize) {
+    let closer = d.lt(*closest);
+    *closest = closer.select(d, *closest);
+    for (idx, &closer) in idx.iter_mut().zip(closer.0.iter()) {
+        if closer {
+            *idx = i as i32;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lanes() {
+        let a = Lanes([1.0, -2.0, 3.0, -4.0]);
+        let b = Lanes::splat(0.5);
+        assert_eq!((a + b).0, [1.5, -1.5, 3.5, -3.5]);
+        assert_eq!(a.abs().max(Lanes::splat(2.0)).0, [2.0, 2.0, 3.0, 4.0]);
+        assert_eq!(a.lt(b), Mask([false, true, false, true]));
+        assert_eq!(a.lt(b).select(b, a).0, [1.0, 0.5, 3.0, 0.5]);
+        assert_eq!(len3([Lanes([3.0; 4]), Lanes([4.0; 4]), Lanes([0.0; 4])]).0, [5.0; 4]);
+        assert!(a.lt(Lanes::splat(4.0)).all() && !a.lt(Lanes::splat(-5.0)).any());
+        assert!([1, 4, 8].contains(&lane_count()));
+    }
+}
@@ -25,3 +25,33 @@ pub fn get_rotation_matrix(rotation: Vector3<f32>) -> Matrix3<f32> {
 
     return vmath::row_mat3_mul(vmath::row_mat3_mul(mat_y, mat_x), mat_z);
 }
+
+//Inverse of get_rotation_matrix, the angles in radians. When the x rotation is a quarter turn
+//y and z turn around the same axis, so all of it goes to y.
+pub fn get_rotation_angles(m: Matrix3<f32>) -> Vector3<f32> {
+    let x = (-m[1][2]).clamp(-1.0, 1.0).asin();
+    if x.cos().abs() < 1e-4 {
+        return [x, (-m[2][0]).atan2(m[0][0]), 0.0];
+    }
+    [x, m[0][2].atan2(m[2][2]), m[1][0].atan2(m[1][1])]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn angles_invert_matrix() {
+        for &rotation in &[[0.0, 0.0, 0.0], [0.3, -1.2, 2.5], [-1.0, 3.0, -0.1], [std::f32::consts::FRAC_PI_2, 0.4, 0.0]] {
+            let angles = get_rotation_angles(get_rotation_matrix(rotation));
+            let (a, b) = (get_rotation_matrix(rotation), get_rotation_matrix(angles));
+            for i in 0..3 {
+                for j in 0..3 {
+                    assert!((a[i][j] - b[i][j]).abs() < 1e-3, "{:?} came back as {:?}", rotation, angles);
+                }
+            }
+        }
+        let angles = get_rotation_angles(get_rotation_matrix([0.3, -1.2, 2.5]));
+        assert!((angles[0] - 0.3).abs() < 1e-4 && (angles[1] + 1.2).abs() < 1e-4 && (angles[2] - 2.5).abs() < 1e-4);
+    }
+}
@@ -6,16 +6,25 @@ use vmath::{
 use super::{
     distance_field,
     camera::Camera,
+    fractal::Quality,
+    bvh::Bvh,
+    packet::{self, Lanes, Lanes3, Mask},
 };
 
 use crate::rendering::{
     raymarching::Ray,
-    lighting::lambert,
+    raymarching::Radiance,
+    raymarching::Hit,
+    raymarching::Sample,
+    environment::Environment,
+    lighting::{reflect, refract, schlick},
+    debug_view::{self, DebugView},
 };
 
-use crossterm::style::Color;
-
-const MAX_STEPS: usize = 64;
+//Changes are remembered for this many revisions, renderers further behind than that start over
+const CHANGE_HISTORY: usize = 64;
+//The fixed normal epsilon used to be this, keep it as the smallest so close up normals stay sharp
+const MIN_NORMAL_EPSILON: f32 = 0.000_288_65;
 
 fn clamp(x: f32, a: f32, b: f32) -> f32 {
     if x < a { return a };
@@ -23,10 +32,69 @@ fn clamp(x: f32, a: f32, b: f32) -> f32 {
     return x;
 }
 
+//Where a lane of cast_packet is, the same state cast keeps for its ray
+#[derive(Copy, Clone)]
+struct CastLane {
+    omega: f32,
+    step: f32,
+    previous_radius: f32,
+    best: (f32, f32, i32, f32),
+    hit: Option<Hit>,
+}
+
+impl CastLane {
+    fn new(relaxation: f32) -> CastLane {
+        CastLane {
+            omega: relaxation,
+            step: 0.0,
+            previous_radius: 0.0,
+            best: (f32::MAX, 0.0, -1, 0.0),
+            hit: None,
+        }
+    }
+}
+
+//Smallest and largest corner of a box in world space
+pub type Region = (Vector3<f32>, Vector3<f32>);
+
 #[derive(Clone)]
 pub struct Scene {
     pub distance_fields: Vec<distance_field::SDF>,
     pub camera: Camera,
+
+    //Ambient occlusion, sampled along the normal at the hit point. 0 samples disables it
+    pub ao_samples: usize,
+    pub ao_strength: f32,
+    //Shifts the samples along the normal by this fraction of their spacing, 0..1. Averaging
+    //frames with different values gives the result of more samples.
+    pub ao_jitter: f32,
+
+    //How many times a ray may reflect or refract before we only use direct lighting
+    pub max_bounces: usize,
+
+    //Limits for every ray, rays that run out of steps use the closest point they got to
+    pub max_steps: usize,
+    pub far: f32,
+    //Hit threshold close to the camera. Further away the size of a pixel takes over
+    pub hit_epsilon: f32,
+    //Steps are multiplied by this while it's safe, 1 turns over-relaxation off
+    pub relaxation: f32,
+
+    pub environment: Environment,
+
+    //Use set_quality to change it, so the objects already in the scene follow
+    pub quality: Quality,
+
+    //Use set_debug_view to change it, so renderers know the frame changed
+    pub debug_view: DebugView,
+
+    //Built by build_bvh, None searches every object
+    bvh: Option<Bvh>,
+
+    //Goes up with every change made through the scene, see revision
+    revision: u64,
+    //The revisions of the latest changes, with a box around what changed. None if it could be anywhere.
+    changes: Vec<(u64, Option<Region>)>,
 }
 
 impl Scene {
@@ -34,24 +102,178 @@ impl Scene {
         Scene {
             distance_fields: Vec::new(),
             camera: Camera::new([0.0, 0.0, 0.0], 0.0, 0.0),
+
+            ao_samples: 5,
+            ao_strength: 3.0,
+            ao_jitter: 0.0,
+
+            max_bounces: 3,
+
+            max_steps: 64,
+            far: 64.0,
+            hit_epsilon: 0.01,
+            relaxation: 1.6,
+
+            environment: Environment::new(),
+
+            quality: Quality::Medium,
+
+            debug_view: DebugView::Shaded,
+
+            bvh: None,
+
+            revision: 0,
+            changes: Vec::new(),
         }
     }
 
-    pub fn push_sdf(&mut self, sdf: distance_field::SDF) -> usize {
+    //Changes whenever the objects change, so renderers know when what they saw is out of date.
+    //Changes made directly to distance_fields don't count, call mark_changed after those.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn mark_changed(&mut self) {
+        self.mark_region_changed(None);
+    }
+
+    fn mark_region_changed(&mut self, region: Option<Region>) {
+        self.revision += 1;
+        self.changes.push((self.revision, region));
+        if self.changes.len() > CHANGE_HISTORY {
+            self.changes.remove(0);
+        }
+    }
+
+    //For changes to a single object, with the box it had before. Everything inside either box changed.
+    fn mark_object_changed(&mut self, idx: usize, before: Option<Region>) {
+        let region = match (before, self.distance_fields[idx].get_bounds()) {
+            (Some(a), Some(b)) => Some((
+                [a.0[0].min(b.0[0]), a.0[1].min(b.0[1]), a.0[2].min(b.0[2])],
+                [a.1[0].max(b.1[0]), a.1[1].max(b.1[1]), a.1[2].max(b.1[2])],
+            )),
+            _ => None,
+        };
+        self.mark_region_changed(region);
+    }
+
+    //Boxes around everything that changed after revision, so renderers can keep what's outside them.
+    //None if that's not known, because something changed everywhere or it was too long ago.
+    pub fn changes_since(&self, revision: u64) -> Option<Vec<Region>> {
+        if revision > self.revision {
+            return None;
+        }
+        let changes: Vec<_> = self.changes.iter().filter(|(r, _)| *r > revision).collect();
+        if changes.len() as u64 != self.revision - revision {
+            return None;
+        }
+        changes.iter().map(|(_, region)| *region).collect()
+    }
+
+    pub fn push_sdf(&mut self, mut sdf: distance_field::SDF) -> usize {
+        self.mark_region_changed(sdf.get_bounds());
+        sdf.set_quality(self.quality);
         let idx = self.distance_fields.len();
         self.distance_fields.push(sdf);
         idx
     }
 
+    //Objects after idx move down by one, so a BVH is built again
+    pub fn remove_sdf(&mut self, idx: usize) -> distance_field::SDF {
+        let sdf = self.distance_fields.remove(idx);
+        self.mark_changed();
+        self.rebuild_bvh();
+        sdf
+    }
+
+    //Puts sdf at idx, moving the objects after it up by one
+    pub fn insert_sdf(&mut self, idx: usize, mut sdf: distance_field::SDF) {
+        sdf.set_quality(self.quality);
+        self.distance_fields.insert(idx, sdf);
+        self.mark_changed();
+        self.rebuild_bvh();
+    }
+
+    //Swaps the object at idx for sdf, returning the old one
+    pub fn replace_sdf(&mut self, idx: usize, mut sdf: distance_field::SDF) -> distance_field::SDF {
+        sdf.set_quality(self.quality);
+        let old = std::mem::replace(&mut self.distance_fields[idx], sdf);
+        self.mark_object_changed(idx, old.get_bounds());
+        self.refit_bvh();
+        old
+    }
+
+    pub fn set_quality(&mut self, quality: Quality) {
+        self.quality = quality;
+        self.mark_changed();
+        for sdf in &mut self.distance_fields {
+            sdf.set_quality(quality);
+        }
+    }
+
+    pub fn set_debug_view(&mut self, view: DebugView) {
+        self.debug_view = view;
+        self.mark_changed();
+    }
+
     pub fn update_rotation(&mut self, idx: usize, rotation: Vector3<f32>) {
+        let before = self.distance_fields[idx].get_bounds();
         self.distance_fields[idx].update_rotation(rotation);
+        self.mark_object_changed(idx, before);
+        self.refit_bvh();
+    }
+
+    pub fn update_scale(&mut self, idx: usize, scale: Vector3<f32>) {
+        let before = self.distance_fields[idx].get_bounds();
+        self.distance_fields[idx].set_scale(scale);
+        self.mark_object_changed(idx, before);
+        self.refit_bvh();
+    }
+
+    pub fn update_position(&mut self, idx: usize, position: Vector3<f32>) {
+        let before = self.distance_fields[idx].get_bounds();
+        self.distance_fields[idx].position = position;
+        self.mark_object_changed(idx, before);
+        self.refit_bvh();
+    }
+
+    //Speeds up distance queries in scenes with many objects. Objects pushed afterwards are still
+    //found, but searched one by one until this is called again. Changes made directly to
+    //distance_fields instead of through the scene need a new build too.
+    pub fn build_bvh(&mut self) {
+        self.mark_changed();
+        self.bvh = Some(Bvh::new(&self.distance_fields));
+    }
+
+    //Only if there is one already. Callers mark what they changed themselves, the BVH doesn't
+    //change what the scene looks like.
+    fn rebuild_bvh(&mut self) {
+        if self.bvh.is_some() {
+            self.bvh = Some(Bvh::new(&self.distance_fields));
+        }
+    }
+
+    fn refit_bvh(&mut self) {
+        if let Some(ref mut bvh) = self.bvh {
+            if !bvh.refit(&self.distance_fields) {
+                self.bvh = Some(Bvh::new(&self.distance_fields));
+            }
+        }
     }
 
     pub fn get_distance(&self, position: Vector3<f32>) -> (f32, i32) {
         let mut closest_distance = 4096.0;
         let mut idx = -1;
+        let mut first = 0;
 
-        for i in 0.. self.distance_fields.len() {
+        if let Some(ref bvh) = self.bvh {
+            let (dist, bvh_idx) = bvh.get_distance(&self.distance_fields, position);
+            closest_distance = dist;
+            idx = bvh_idx;
+            first = bvh.object_count.min(self.distance_fields.len());
+        }
+
+        for i in first.. self.distance_fields.len() {
             let dist = self.distance_fields[i].get_distance(position);
             //Need to write this dumb code, so rust doesn't shit itself.
             //Apparently std::cmp::min requires the Ord trait, which isn't implemented for any floats.
@@ -65,8 +287,31 @@ impl Scene {
         return (closest_distance, idx);
     }
 
-    pub fn get_normal(&self, position: Vector3<f32>) -> Vector3<f32> {
-        let e = [0.00028865, -0.00028865];
+    //get_distance for a packet of points, lanes that aren't active may get any distance back
+    #[inline(always)]
+    pub fn get_distance_packet<const N: usize>(&self, positions: &Lanes3<N>, active: Mask<N>) -> (Lanes<N>, [i32; N]) {
+        let mut closest_distance = Lanes::splat(4096.0);
+        let mut idx = [-1; N];
+        let mut first = 0;
+
+        if let Some(ref bvh) = self.bvh {
+            (closest_distance, idx) = bvh.get_distance_packet(&self.distance_fields, positions, active);
+            first = bvh.object_count.min(self.distance_fields.len());
+        }
+
+        for i in first.. self.distance_fields.len() {
+            let dist = self.distance_fields[i].get_distance_packet(positions, active);
+            packet::keep_closest(&mut closest_distance, &mut idx, dist, i);
+        }
+
+        (closest_distance, idx)
+    }
+
+    //Tetrahedral central differences. epsilon is the hit threshold at the point, so far away
+    //surfaces sample wider and get smoother normals instead of noise between pixels
+    pub fn get_normal(&self, position: Vector3<f32>, epsilon: f32) -> Vector3<f32> {
+        let h = MIN_NORMAL_EPSILON.max(epsilon * 0.1);
+        let e = [h, -h];
         let p1 = [e[0], e[1], e[1]];
         let p2 = [e[1], e[1], e[0]];
         let p3 = [e[1], e[0], e[1]];
@@ -86,69 +331,548 @@ impl Scene {
         );
     }
 
+    //Compares the distance field at a few points along the normal with the distance we stepped.
+    //If the field is closer than the step, something else is nearby and blocks ambient light.
+    pub fn get_ambient_occlusion(&self, position: Vector3<f32>, normal: Vector3<f32>) -> f32 {
+        if self.ao_samples == 0 {
+            return 1.0;
+        }
+
+        let mut occlusion = 0.0;
+        let mut falloff = 1.0;
+
+        for i in 0.. self.ao_samples {
+            let h = 0.05 + 0.25 * (i as f32 + self.ao_jitter) / (self.ao_samples as f32);
+            let sample_pos = vmath::vec3_add(position, vmath::vec3_scale(normal, h));
+            let d = self.get_distance(sample_pos).0;
+            occlusion += (h - d) * falloff;
+            falloff *= 0.85;
+        }
+
+        clamp(1.0 - self.ao_strength * occlusion / (self.ao_samples as f32), 0.0, 1.0)
+    }
+
+    //How much of the sun reaches a point, 0 in full shadow. Marches towards the sun, and how
+    //close the ray gets to anything on the way relative to how far it went gives a soft edge.
+    pub fn get_shadow(&self, position: Vector3<f32>, normal: Vector3<f32>) -> f32 {
+        let direction = self.environment.sun.direction;
+        if vmath::vec3_dot(normal, direction) <= 0.0 {
+            return 0.0;
+        }
+
+        let origin = vmath::vec3_add(position, vmath::vec3_scale(normal, self.hit_epsilon * 2.0));
+        let mut light = 1.0_f32;
+        let mut t = self.hit_epsilon * 2.0;
+        for _ in 0..self.max_steps {
+            if t >= self.far {
+                break;
+            }
+            let d = self.get_distance(vmath::vec3_add(origin, vmath::vec3_scale(direction, t))).0;
+            if d < self.hit_epsilon {
+                return 0.0;
+            }
+            light = light.min(8.0 * d / t);
+            t += d;
+        }
+        clamp(light, 0.0, 1.0)
+    }
+
     pub fn generate_ray(&self, term_size: (u16, u16), px: u16, py: u16) -> Ray {
-        let fc = ((term_size.0 - px) as f32, (term_size.1 - py) as f32);
+        self.generate_ray_at(term_size, px as f32, py as f32)
+    }
+
+    //Same as generate_ray, for any position on the screen rather than just whole cells
+    pub fn generate_ray_at(&self, term_size: (u16, u16), px: f32, py: f32) -> Ray {
+        let fc = (term_size.0 as f32 - px, term_size.1 as f32 - py);
         let p = ((-(term_size.0 as f32) + 2.0 * fc.0) / (term_size.1 as f32), (-(term_size.1 as f32) + 2.0 * fc.1) / (term_size.1 as f32));
-        let mut ray = Ray::new([0.0, 0.0, 0.0], vmath::vec3_normalized([p.0 * 0.5, p.1, 2.0]));
+        let mut ray = Ray::new(self.camera.eye, vmath::vec3_normalized([p.0 * 0.5, p.1, 2.0]));
+        ray.footprint = Ray::cell_footprint(term_size);
 
-        let r = self.camera.yaw / 180.0 * 3.14;
+        let r = self.camera.yaw_radians();
         let dx = ray.direction[0] * r.cos() - ray.direction[2] * r.sin();
         let dy = ray.direction[2] * r.cos() + ray.direction[0] * r.sin();
         ray.direction[0] = -dx;
         ray.direction[2] = dy;
 
-        return ray;
+        ray
+    }
+
+    //Sphere traces a ray until it hits something, without shading it.
+    //Steps are over-relaxed, they go further than the distance says, which saves steps along
+    //surfaces the ray runs parallel to. When the bounding spheres of two steps don't overlap
+    //anymore the step may have skipped a surface, so it's undone and we continue normally.
+    pub fn cast(&self, ray: &Ray) -> Hit {
+        let direction = vmath::vec3_normalized(ray.direction);
+        let at = |t: f32| vmath::vec3_add(ray.origin, vmath::vec3_scale(direction, t));
+
+        let mut omega = self.relaxation;
+        let mut t = 0.0;
+        let mut step = 0.0;
+        let mut previous_radius = 0.0;
+        //Where the distance was smallest compared to the hit threshold, used if we run out of steps
+        let mut best = (f32::MAX, 0.0, -1, 0.0);
+
+        for steps in 1..=self.max_steps {
+            let (radius, idx) = self.get_distance(at(t));
+            //Signed, landing inside something counts as skipping its surface
+            if omega > 1.0 && radius + previous_radius < step {
+                t -= step - previous_radius;
+                step = previous_radius;
+                omega = 1.0;
+                continue;
+            }
+
+            let epsilon = self.hit_epsilon.max(t * ray.footprint);
+            if radius < epsilon {
+                return Hit::new(at(t), t, idx, steps, epsilon);
+            }
+            if t > self.far {
+                return Hit::miss(t, steps);
+            }
+            if radius / epsilon < best.0 {
+                best = (radius / epsilon, t, idx, epsilon);
+            }
+
+            step = radius * omega;
+            previous_radius = radius;
+            t += step;
+        }
+
+        let (_, t, idx, epsilon) = best;
+        Hit::new(at(t), t, idx, self.max_steps, epsilon)
     }
 
-    pub fn march(&self, mut ray: Ray) -> (char, Color) {
-        let (mut dist, mut idx) = self.get_distance(ray.position);
+    //cast for N rays at once, each lane takes exactly the steps cast would take for its ray.
+    //Lanes that are done keep their last position and are masked out of the distance queries,
+    //the packet stops once every lane is done.
+    #[inline(always)]
+    fn cast_packet<const N: usize>(&self, rays: &[Ray]) -> [Hit; N] {
+        let directions: [Vector3<f32>; N] = std::array::from_fn(|i| vmath::vec3_normalized(rays[i].direction));
+        let origin: Lanes3<N> = std::array::from_fn(|axis| Lanes(std::array::from_fn(|i| rays[i].origin[axis])));
+        let direction: Lanes3<N> = std::array::from_fn(|axis| Lanes(std::array::from_fn(|i| directions[i][axis])));
+
+        let mut lanes = [CastLane::new(self.relaxation); N];
+        let mut t = Lanes::splat(0.0);
+        let mut active = Mask([true; N]);
+
+        for steps in 1..=self.max_steps {
+            let position = [origin[0] + direction[0] * t, origin[1] + direction[1] * t, origin[2] + direction[2] * t];
+            let (radius, idx) = self.get_distance_packet(&position, active);
+
+            for (i, lane) in lanes.iter_mut().enumerate() {
+                if !active.0[i] {
+                    continue;
+                }
+                let (radius, idx) = (radius.0[i], idx[i]);
+                let t = &mut t.0[i];
+                if lane.omega > 1.0 && radius + lane.previous_radius < lane.step {
+                    *t -= lane.step - lane.previous_radius;
+                    lane.step = lane.previous_radius;
+                    lane.omega = 1.0;
+                    continue;
+                }
+
+                let epsilon = self.hit_epsilon.max(*t * rays[i].footprint);
+                if radius < epsilon {
+                    lane.hit = Some(Hit::new(packet::lane(&position, i), *t, idx, steps, epsilon));
+                } else if *t > self.far {
+                    lane.hit = Some(Hit::miss(*t, steps));
+                } else {
+                    if radius / epsilon < lane.best.0 {
+                        lane.best = (radius / epsilon, *t, idx, epsilon);
+                    }
+                    lane.step = radius * lane.omega;
+                    lane.previous_radius = radius;
+                    *t += lane.step;
+                }
+                active.0[i] = lane.hit.is_none();
+            }
+
+            if !active.any() {
+                break;
+            }
+        }
+
+        std::array::from_fn(|i| {
+            lanes[i].hit.unwrap_or_else(|| {
+                let (_, t, idx, epsilon) = lanes[i].best;
+                let at = vmath::vec3_add(rays[i].origin, vmath::vec3_scale(directions[i], t));
+                Hit::new(at, t, idx, self.max_steps, epsilon)
+            })
+        })
+    }
+
+    //Only called once lane_count found AVX, the packet code gets inlined and compiled for 8 lanes
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[target_feature(enable = "avx")]
+    unsafe fn cast_packet_avx(&self, rays: &[Ray]) -> [Hit; 8] {
+        self.cast_packet::<8>(rays)
+    }
+
+    //march_counted for every ray, along with what it hit first. Primary rays are cast in packets as wide as the CPU allows,
+    //what's left over and all bounces are marched one at a time.
+    pub fn march_rays(&self, rays: &[Ray]) -> Vec<Sample> {
+        let lanes = packet::lane_count();
+        let mut result = Vec::with_capacity(rays.len());
+        let mut chunks = rays.chunks_exact(lanes);
+
+        for chunk in &mut chunks {
+            let hits = match lanes {
+                //Safe, lane_count only returns 8 if the CPU has AVX
+                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                8 => unsafe { self.cast_packet_avx(chunk) }.to_vec(),
+                4 => self.cast_packet::<4>(chunk).to_vec(),
+                _ => vec![self.cast(&chunk[0])],
+            };
+            for (ray, hit) in chunk.iter().zip(hits) {
+                let mut steps = hit.steps;
+                let normal = self.hit_normal(&hit);
+                let radiance = self.trace_hit(ray, hit, normal, 0, &mut steps);
+                result.push(Sample::new(radiance, hit, normal, steps));
+            }
+        }
+
+        for ray in chunks.remainder() {
+            let hit = self.cast(ray);
+            let mut steps = hit.steps;
+            let normal = self.hit_normal(&hit);
+            let radiance = self.trace_hit(ray, hit, normal, 0, &mut steps);
+            result.push(Sample::new(radiance, hit, normal, steps));
+        }
+        result
+    }
+
+    pub fn march(&self, ray: Ray) -> Radiance {
+        self.march_counted(ray).0
+    }
+
+    //Also returns how many distance field steps the ray and all its bounces took
+    pub fn march_counted(&self, ray: Ray) -> (Radiance, usize) {
         let mut steps = 0;
+        let radiance = self.trace(&ray, 0, &mut steps);
+        (radiance, steps)
+    }
+
+    fn trace(&self, ray: &Ray, depth: usize, steps: &mut usize) -> Radiance {
+        let hit = self.cast(ray);
+        *steps += hit.steps;
+        self.trace_hit(ray, hit, self.hit_normal(&hit), depth, steps)
+    }
+
+    //0 for misses
+    fn hit_normal(&self, hit: &Hit) -> Vector3<f32> {
+        match hit.object {
+            Some(_) => self.get_normal(hit.position, hit.epsilon),
+            None => [0.0; 3],
+        }
+    }
+
+    //Shades what the ray hit, and follows its reflections and refractions
+    fn trace_hit(&self, ray: &Ray, hit: Hit, normal: Vector3<f32>, depth: usize, steps: &mut usize) -> Radiance {
+        if depth == 0 && self.debug_view != DebugView::Shaded {
+            return self.debug_radiance(&hit, normal);
+        }
+
+        let direction = vmath::vec3_normalized(ray.direction);
+        let idx = match hit.object {
+            Some(idx) => idx,
+            None => return self.environment.background(direction),
+        };
+
+        let sdf = &self.distance_fields[idx];
+        let material = sdf.get_material(hit.position);
+
+        let local = self.shade(sdf, hit.position, normal, direction);
+
+        if depth >= self.max_bounces {
+            return self.environment.apply_fog(local, ray.origin, direction, hit.distance, self.far);
+        }
+
+        let mut result = local;
+
+        if material.reflectivity > 0.0 {
+            let reflected = self.trace_reflection(&hit, direction, normal, ray.footprint, depth, steps);
+            result = result.mix(reflected, material.reflectivity);
+        }
+
+        if material.transparency > 0.0 {
+            let cos_theta = -vmath::vec3_dot(direction, normal);
+            let fresnel = schlick(cos_theta, 1.0, material.ior);
+
+            let reflected = self.trace_reflection(&hit, direction, normal, ray.footprint, depth, steps);
+            let refracted = match refract(direction, normal, 1.0 / material.ior) {
+                Some(refracted_dir) => self.trace_inside(idx, &hit, refracted_dir, normal, ray.footprint, depth + 1, steps),
+                None => reflected,
+            };
+
+            let through = refracted.mix(reflected, fresnel);
+            result = result.mix(through, material.transparency);
+        }
+
+        self.environment.apply_fog(result, ray.origin, direction, hit.distance, self.far)
+    }
+
+    //What the camera ray shows in debug_view. Misses stay empty, except in the step count view,
+    //rays that miss everything are often the slowest.
+    fn debug_radiance(&self, hit: &Hit, normal: Vector3<f32>) -> Radiance {
+        let idx = match (hit.object, self.debug_view) {
+            (_, DebugView::Steps) => return debug_view::heatmap(hit.steps as f32 / self.max_steps as f32),
+            (Some(idx), _) => idx,
+            (None, _) => return Radiance::miss(),
+        };
+
+        match self.debug_view {
+            DebugView::Normals => debug_view::normal(normal),
+            DebugView::Depth => debug_view::grey(1.0 - hit.distance / self.far),
+            DebugView::Object => debug_view::object(idx),
+            DebugView::AmbientOcclusion => debug_view::grey(self.get_ambient_occlusion(hit.position, normal)),
+            DebugView::Shadow => debug_view::grey(self.get_shadow(hit.position, normal)),
+            _ => Radiance::miss(),
+        }
+    }
 
-        while dist > 0.1 && steps < MAX_STEPS {
-            if dist >= 64.0 {
-                return (' ', Color::Red);
+    //Direct lighting at a hit point, without any bounces
+    fn shade(&self, sdf: &distance_field::SDF, position: Vector3<f32>, normal: Vector3<f32>, direction: Vector3<f32>) -> Radiance {
+        let light_dir = vmath::vec3_neg(self.environment.sun.direction);
+        let ao = self.get_ambient_occlusion(position, normal);
+
+        let mut diffuse = clamp(vmath::vec3_dot(normal, vmath::vec3_neg(light_dir)), 0.0, 1.0);
+        //Faces turned away from the sun are dark anyway, no need to march towards it
+        if diffuse > 0.0 {
+            diffuse *= self.get_shadow(position, normal);
+        }
+        let albedo = sdf.get_albedo(position, normal);
+        let colour = vmath::vec3_scale(albedo, diffuse * ao);
+
+        let mut intensity = diffuse;
+        intensity *= clamp(vmath::vec3_dot(normal, vmath::vec3_neg(direction)), 0.0, 1.0);
+        intensity *= ao;
+
+        Radiance::new(colour, intensity)
+    }
+
+    //Bounce rays keep the footprint of the camera ray, so they don't get more precise than it
+    fn bounce_ray(&self, origin: Vector3<f32>, direction: Vector3<f32>, footprint: f32) -> Ray {
+        let mut ray = Ray::new(origin, direction);
+        ray.footprint = footprint;
+        ray
+    }
+
+    //How far bounce rays start from the surface, otherwise they immediately hit it again. The hit
+    //can be up to epsilon away from the surface, and the threshold of the bounce ray grows with
+    //its footprint as it leaves, so it has to start clear of both.
+    fn surface_offset(&self, epsilon: f32, footprint: f32) -> f32 {
+        2.0 * epsilon.max(self.hit_epsilon) / (1.0 - footprint.min(0.5))
+    }
+
+    fn trace_reflection(&self, hit: &Hit, direction: Vector3<f32>, normal: Vector3<f32>, footprint: f32, depth: usize, steps: &mut usize) -> Radiance {
+        let offset = self.surface_offset(hit.epsilon, footprint);
+        let origin = vmath::vec3_add(hit.position, vmath::vec3_scale(normal, offset));
+        self.trace(&self.bounce_ray(origin, reflect(direction, normal), footprint), depth + 1, steps)
+    }
+
+    //Marches a refracted ray through the inside of a single object until it exits again.
+    //Inside, the distance to the surface is the negated distance of that object.
+    #[allow(clippy::too_many_arguments)]
+    fn trace_inside(&self, idx: usize, hit: &Hit, direction: Vector3<f32>, normal: Vector3<f32>, footprint: f32, depth: usize, steps: &mut usize) -> Radiance {
+        let sdf = &self.distance_fields[idx];
+        let offset = self.surface_offset(hit.epsilon, footprint);
+        let origin = vmath::vec3_sub(hit.position, vmath::vec3_scale(normal, offset));
+        let mut ray = Ray::new(origin, direction);
+        let mut inside_steps = 0;
+
+        loop {
+            let dist = -sdf.get_distance(ray.position);
+            if dist <= self.hit_epsilon || inside_steps >= self.max_steps {
+                break;
             }
             ray.step(dist);
-            let (_dist, _idx) = self.get_distance(ray.position);
-            dist = _dist;
-            idx = _idx;
-            steps += 1;
-        }
-
-        // return ('x', Color::Red);
-
-        if idx >= 0 {
-            let sdf = &self.distance_fields[idx as usize];
-
-            let normal = self.get_normal(ray.position);
-            // println!("normal: {:?}", normal);
-
-            let color = lambert( Color::Rgb{r: sdf.colour[0], g: sdf.colour[1], b: sdf.colour[2]}, normal, vmath::vec3_normalized([0.25, -0.5, 0.5]));
-
-            let mut intensity = clamp(vmath::vec3_dot(normal, vmath::vec3_neg(vmath::vec3_normalized([0.25, -0.5, 0.5]))), 0.0, 1.0);
-            intensity *= clamp(vmath::vec3_dot(normal, vmath::vec3_neg(vmath::vec3_normalized(ray.direction))), 0.0, 1.0);
-            let mut value = ' ';
-            // let gradient_string = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789<>|,.-#+!$%&/()=?*'_:;";
-            let gradient_string = ":;1?$X%#@";
-            // let gradient_string = ".'`^\",:;Il!i><~+_-?][}{1)(|\\/tfjrxnuvczXYUJCLQ0OZmwqpdbkhao*#MW&8%B@$";
-            // let gradient_string = ":;=+*#%@";
-            let gradient: Vec<char> = gradient_string.chars().collect();
-            // let gradient = [':', ';', '1', '?', '$', 'X', '%', '#', '@'];
-            // let gradient = ['.', ':', ';', '+', '=', 'x', 'X', '$', '&'];
-            let gradient_idx = (intensity * (gradient.len() + 1) as f32) as usize;
-            value = gradient[gradient_idx];
-            // if intensity < 0.25 {
-            //     value = '=';
-            // } else if intensity < 0.5 {
-            //     value = '+';
-            // } else if intensity < 0.75 {
-            //     value = '&';
-            // }
-
-            return (value, color);
-        }
-        // return ('x', Color::Rgb{r: clamp(-normal[0] * 255.0, 0.0, 255.0) as u8, g: clamp(-normal[1] * 255.0, 0.0, 255.0) as u8, b: clamp(-normal[2] * 255.0, 0.0, 255.0) as u8});
-
-        return (' ', Color::Red);
+            inside_steps += 1;
+        }
+        *steps += inside_steps;
+
+        //The scene normal points out of the object, so flip it for rays coming from inside
+        let exit_normal = self.get_normal(ray.position, self.hit_epsilon);
+        let inside_normal = vmath::vec3_neg(exit_normal);
+        let exit_origin = vmath::vec3_add(ray.position, vmath::vec3_scale(exit_normal, self.surface_offset(self.hit_epsilon, footprint)));
+
+        match refract(direction, inside_normal, sdf.get_material(ray.position).ior) {
+            Some(exit_dir) => self.trace(&self.bounce_ray(exit_origin, exit_dir, footprint), depth, steps),
+            None => {
+                //Total internal reflection, keep bouncing around inside while we still have depth left
+                if depth >= self.max_bounces {
+                    return Radiance::miss();
+                }
+                let reflected = reflect(direction, inside_normal);
+                let bounce = Hit::new(ray.position, 0.0, idx as i32, 0, self.hit_epsilon);
+                self.trace_inside(idx, &bounce, reflected, exit_normal, footprint, depth + 1, steps)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::material::Material;
+
+    fn scene() -> Scene {
+        let mut scene = Scene::new();
+        scene.push_sdf(distance_field::SDF::new_plane(-1.0, [255, 255, 255]));
+        scene.push_sdf(distance_field::SDF::new_sphere([0.0, 0.0, 5.0], 1.0, [255, 0, 0]));
+        scene
+    }
+
+    #[test]
+    fn cast() {
+        let scene = scene();
+        let hit = scene.cast(&Ray::new([0.0, 0.0, 0.0], [0.0, 0.0, 1.0]));
+        assert_eq!(hit.object, Some(1));
+        assert!((hit.distance - 4.0).abs() <= scene.hit_epsilon);
+
+        let miss = scene.cast(&Ray::new([0.0, 0.0, 0.0], [0.0, 1.0, 0.0]));
+        assert_eq!(miss.object, None);
+        assert!(miss.distance > scene.far);
+    }
+
+    #[test]
+    fn ambient_occlusion() {
+        //A wall standing on the floor, the corner where they meet gets less ambient light
+        let mut scene = scene();
+        scene.push_sdf(distance_field::SDF::new_plane_normal([0.0, 0.0, 10.0], [0.0, 0.0, -1.0], [255, 255, 255]));
+        let up = [0.0, 1.0, 0.0];
+        let open = scene.get_ambient_occlusion([-4.0, -1.0, 2.0], up);
+        let crease = scene.get_ambient_occlusion([-4.0, -1.0, 9.95], up);
+        assert!(open > 0.99, "{}", open);
+        assert!(crease < open - 0.1, "{} isn't darker than {}", crease, open);
+
+        scene.ao_samples = 0;
+        assert_eq!(scene.get_ambient_occlusion([-4.0, -1.0, 9.95], up), 1.0);
+    }
+
+    #[test]
+    fn relaxation_saves_steps() {
+        //Grazing the floor is where plain sphere tracing takes the most steps
+        let mut scene = scene();
+        scene.max_steps = 1000;
+        let ray = Ray::new([0.0, 0.0, 0.0], vmath::vec3_normalized([1.0, -0.05, 0.0]));
+        let relaxed = scene.cast(&ray);
+        scene.relaxation = 1.0;
+        let plain = scene.cast(&ray);
+
+        assert_eq!(relaxed.object, Some(0));
+        assert_eq!(plain.object, Some(0));
+        //At a grazing angle the hit threshold stretches out along the ray
+        assert!((relaxed.distance - plain.distance).abs() < 2.0 * scene.hit_epsilon / 0.05);
+        assert!(relaxed.steps < plain.steps, "{} relaxed vs {} plain steps", relaxed.steps, plain.steps);
+    }
+
+    #[test]
+    fn footprint_grows_epsilon() {
+        let scene = scene();
+        let mut ray = Ray::new([0.0, 0.0, 0.0], [0.0, 0.0, 1.0]);
+        ray.footprint = 0.1;
+        let hit = scene.cast(&ray);
+        assert_eq!(hit.object, Some(1));
+        assert!(hit.epsilon > scene.hit_epsilon && hit.epsilon <= hit.distance * 0.1);
+    }
+
+    #[test]
+    fn packets_match_cast() {
+        use crate::engine::modifier::Modifier;
+        let mut scene = scene();
+        scene.push_sdf(distance_field::SDF::new_cube([2.0, 0.0, 6.0], [0.5, 0.5, 0.5], [0, 255, 0], [30.0, 45.0, 0.0]));
+        scene.push_sdf(distance_field::SDF::new_torus([-2.0, 0.5, 7.0], [0.8, 0.2], [0, 0, 255], [90.0, 0.0, 0.0]));
+        let mut capsule = distance_field::SDF::new_capsule([0.0, 1.5, 8.0], 0.3, 1.0, [255, 255, 0], [0.0, 0.0, 60.0]);
+        capsule.set_scale([1.0, 2.0, 0.5]);
+        scene.push_sdf(capsule);
+        scene.push_sdf(distance_field::SDF::new_ellipsoid([1.5, -0.5, 3.0], [0.3, 0.6, 0.4], [255, 0, 255], [0.0, 0.0, 0.0]));
+        //No packet version for these, they take the per lane fallback
+        let mut twisted = distance_field::SDF::new_round_box([-1.5, -0.5, 4.0], 0.3, 0.6, 0.05, [0, 255, 255], [0.0, 0.0, 0.0]);
+        twisted.add_modifier(Modifier::Twist { amount: 1.0 });
+        scene.push_sdf(twisted);
+        scene.push_sdf(distance_field::SDF::new_octahedron([0.0, -0.5, 2.5], 0.4, [255, 255, 255], [0.0, 0.0, 0.0]));
+
+        let term_size = (32, 16);
+        let rays: Vec<Ray> = (0..term_size.0 * term_size.1).map(|i| scene.generate_ray(term_size, i % term_size.0, i / term_size.0)).collect();
+        //The packet walks the BVH in a different order, and shapes that underestimate their distance
+        //can end up below their box distance, so with a BVH only the same objects get hit
+        let check = |scene: &Scene, exact: bool| {
+            for chunk in rays.chunks(8) {
+                let wide = scene.cast_packet::<8>(chunk);
+                let narrow = [scene.cast_packet::<4>(&chunk[..4]), scene.cast_packet::<4>(&chunk[4..])].concat();
+                for ((ray, a), b) in chunk.iter().zip(wide.iter()).zip(narrow.iter()) {
+                    let hit = scene.cast(ray);
+                    for packet_hit in [a, b] {
+                        assert_eq!(packet_hit.object, hit.object);
+                        if exact {
+                            assert_eq!((packet_hit.steps, packet_hit.distance), (hit.steps, hit.distance));
+                        }
+                    }
+                }
+            }
+        };
+        check(&scene, true);
+        scene.build_bvh();
+        check(&scene, false);
+
+        let marched = scene.march_rays(&rays);
+        assert_eq!(marched.len(), rays.len());
+        assert_eq!(marched[100].steps, scene.march_counted(scene.generate_ray(term_size, 100 % 32, 100 / 32)).1);
+    }
+
+    #[test]
+    fn debug_views() {
+        let mut scene = scene();
+        scene.environment.sun.direction = [0.0, 1.0, 0.0];
+        let revision = scene.revision();
+        scene.set_debug_view(DebugView::Normals);
+        assert!(scene.revision() > revision);
+
+        //The front of the sphere faces the camera
+        let radiance = scene.march(Ray::new([0.0, 0.0, 0.0], [0.0, 0.0, 1.0]));
+        assert!(vmath::vec3_len(vmath::vec3_sub(radiance.colour, [0.5, 0.5, 0.0])) < 0.01);
+
+        scene.set_debug_view(DebugView::Depth);
+        let radiance = scene.march(Ray::new([0.0, 0.0, 0.0], [0.0, 0.0, 1.0]));
+        assert!((radiance.intensity - (1.0 - 4.0 / scene.far)).abs() < 0.01);
+
+        //Misses only show up in the step count
+        let up = || Ray::new([0.0, 0.0, 0.0], [0.0, 1.0, 0.0]);
+        assert_eq!(scene.march(up()).coverage, 0.0);
+        scene.set_debug_view(DebugView::Steps);
+        assert_eq!(scene.march(up()).coverage, 1.0);
+
+        //The floor under the sphere is in its shadow, next to it isn't
+        scene.set_debug_view(DebugView::Shadow);
+        assert_eq!(scene.get_shadow([0.0, -1.0, 5.0], [0.0, 1.0, 0.0]), 0.0);
+        assert!(scene.march(Ray::new([3.0, 0.0, 5.0], [0.0, -1.0, 0.0])).intensity > 0.9);
+
+        //And the shaded view darkens it the same way
+        scene.set_debug_view(DebugView::Shaded);
+        let under = vmath::vec3_normalized([0.5, -1.0, 5.0]);
+        assert_eq!(scene.march(Ray::new([0.0, 0.0, 0.0], under)).intensity, 0.0);
+        assert!(scene.march(Ray::new([3.0, 0.0, 5.0], [0.0, -1.0, 0.0])).intensity > 0.5);
+    }
+
+    #[test]
+    fn glass_sphere() {
+        //A ray through the middle of a glass sphere comes out straight and shows what's behind it
+        let mut scene = Scene::new();
+        scene.environment.sun.direction = vmath::vec3_normalized([0.0, 1.0, -1.0]);
+        scene.push_sdf(distance_field::SDF::new_sphere([0.0, 0.0, 10.0], 2.0, [0, 255, 0]));
+        let ray = || {
+            let mut ray = Ray::new([0.0, 0.0, 0.0], [0.0, 0.0, 1.0]);
+            ray.footprint = 0.01;
+            ray
+        };
+        let behind = scene.march(ray());
+
+        let mut glass = distance_field::SDF::new_sphere([0.0, 0.0, 5.0], 1.0, [255, 255, 255]);
+        glass.set_material(Material::new_glass(1.0, 1.5));
+        scene.push_sdf(glass);
+        let through = scene.march(ray());
+        assert!(behind.colour[1] > 0.5);
+        assert!(vmath::vec3_len(vmath::vec3_sub(through.colour, behind.colour)) < 0.05, "{:?} {:?}", through.colour, behind.colour);
     }
 }
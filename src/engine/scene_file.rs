@@ -0,0 +1,314 @@
+extern crate vecmath as vmath;
+use vmath::{
+    Vector3,
+};
+
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::engine::distance_field::{SDF, SDF_Type};
+use crate::engine::expression::Expression;
+
+//A scene file has one object per line, its type followed by named values:
+//
+//  sphere position 2 0 5 size 1 1 1 rotation 0 0 0 scale 1 1 1 colour 255 0 0 material 0.5 0 1
+//
+//Values left out keep their defaults. Mandelbulbs also have a power,
+//material is reflectivity, transparency and ior. Lines starting with # are comments.
+//Shapes written in the expression language have their parameters and source last, the source
+//takes up the rest of the line:
+//
+//  expression position 0 1 5 param r 0.5 source smin(sphere(p, r), box(p, vec3(1)), 0.3)
+//
+//Meshes, volumes, heightfields and other custom shapes are loaded from elsewhere and aren't saved,
+//and neither are textures and modifiers. Both are noted in a comment and counted.
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn type_from_name(name: &str) -> Option<SDF_Type> {
+    Some(match name {
+        "sphere" => SDF_Type::SDF_Sphere,
+        "box" => SDF_Type::SDF_Box,
+        "round_box" => SDF_Type::SDF_RoundBox,
+        "torus" => SDF_Type::SDF_Torus,
+        "capped_torus" => SDF_Type::SDF_CappedTorus,
+        "link" => SDF_Type::SDF_Link,
+        "plane" => SDF_Type::SDF_Plane,
+        "capsule" => SDF_Type::SDF_Capsule,
+        "cylinder" => SDF_Type::SDF_Cylinder,
+        "capped_cylinder" => SDF_Type::SDF_CappedCylinder,
+        "cone" => SDF_Type::SDF_Cone,
+        "ellipsoid" => SDF_Type::SDF_Ellipsoid,
+        "octahedron" => SDF_Type::SDF_Octahedron,
+        "hex_prism" => SDF_Type::SDF_HexPrism,
+        "tri_prism" => SDF_Type::SDF_TriPrism,
+        "pyramid" => SDF_Type::SDF_Pyramid,
+        "mandelbulb" => SDF_Type::SDF_Mandelbulb { power: 8.0, iterations: 0 },
+        "menger_sponge" => SDF_Type::SDF_MengerSponge { iterations: 0 },
+        "sierpinski" => SDF_Type::SDF_Sierpinski { iterations: 0 },
+        _ => return None,
+    })
+}
+
+//None for the types that can't be saved
+fn type_name(sdf_type: &SDF_Type) -> Option<String> {
+    match *sdf_type {
+        SDF_Type::SDF_Custom(ref field) if field.expression().is_some() => Some(String::from("expression")),
+        SDF_Type::SDF_Mesh(_) | SDF_Type::SDF_Volume(_) | SDF_Type::SDF_Heightfield(_) | SDF_Type::SDF_Custom(_) => None,
+        _ => Some(sdf_type.name().replace(' ', "_")),
+    }
+}
+
+//Takes the next word off the front of line
+fn next_word<'a>(line: &mut &'a str) -> Option<&'a str> {
+    let trimmed = line.trim_start();
+    if trimmed.is_empty() {
+        return None;
+    }
+    let end = trimmed.find(char::is_whitespace).unwrap_or(trimmed.len());
+    *line = &trimmed[end..];
+    Some(&trimmed[..end])
+}
+
+fn vector<T: std::fmt::Display>(v: [T; 3]) -> String {
+    format!("{} {} {}", v[0], v[1], v[2])
+}
+
+//The objects that can be saved, one per line, how many were left out and how many lost their
+//texture or modifiers
+pub fn to_string(objects: &[SDF]) -> (String, usize, usize) {
+    let mut source = String::from("#terminal_raymarcher scene\n");
+    let mut skipped = 0;
+    let mut incomplete = 0;
+    for sdf in objects {
+        let name = match type_name(&sdf.sdf_type) {
+            Some(name) => name,
+            None => {
+                source.push_str(&format!("#{} left out\n", sdf.sdf_type.name()));
+                skipped += 1;
+                continue;
+            },
+        };
+
+        if sdf.material.texture.is_some() || !sdf.modifiers.is_empty() {
+            let texture = if sdf.material.texture.is_some() { "texture, " } else { "" };
+            source.push_str(&format!("#{} {}{} modifiers left out\n", name, texture, sdf.modifiers.len()));
+            incomplete += 1;
+        }
+        source.push_str(&format!("{} position {} size {}", name, vector(sdf.position), vector(sdf.size)));
+        if sdf.rotation.is_some() {
+            source.push_str(&format!(" rotation {}", vector(sdf.get_rotation())));
+        }
+        source.push_str(&format!(" scale {} colour {}", vector(sdf.scale), vector(sdf.colour)));
+        let material = &sdf.material;
+        source.push_str(&format!(" material {} {} {}", material.reflectivity, material.transparency, material.ior));
+        match sdf.sdf_type {
+            SDF_Type::SDF_Mandelbulb { power, .. } => source.push_str(&format!(" power {}", power)),
+            SDF_Type::SDF_Custom(ref field) => if let Some(expression) = field.expression() {
+                for (name, value) in expression.parameters() {
+                    source.push_str(&format!(" param {} {}", name, value));
+                }
+                source.push_str(&format!(" source {}", expression.source.trim()));
+            },
+            _ => {},
+        }
+        source.push('\n');
+    }
+    (source, skipped, incomplete)
+}
+
+pub fn parse(source: &str) -> Result<Vec<SDF>> {
+    let mut objects = Vec::new();
+
+    for (line_idx, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut rest = line;
+        let name = next_word(&mut rest).unwrap_or("");
+        let is_expression = name == "expression";
+        let sdf_type = if is_expression { None } else {
+            Some(type_from_name(name).ok_or_else(|| invalid(format!("line {}: unknown type '{}'", line_idx + 1, name)))?)
+        };
+
+        let mut values = std::collections::HashMap::new();
+        let mut parameters = Vec::new();
+        let mut expression_source = None;
+        while let Some(key) = next_word(&mut rest) {
+            let count = match key {
+                "position" | "size" | "rotation" | "scale" | "colour" | "material" => 3,
+                "power" => 1,
+                "param" if is_expression => {
+                    let parameter = next_word(&mut rest)
+                        .and_then(|name| Some((name, next_word(&mut rest)?.parse::<f32>().ok()?)))
+                        .ok_or_else(|| invalid(format!("line {}: param needs a name and a number", line_idx + 1)))?;
+                    parameters.push(parameter);
+                    continue;
+                },
+                "source" if is_expression => {
+                    expression_source = Some(rest.trim());
+                    break;
+                },
+                _ => return Err(invalid(format!("line {}: unknown value '{}'", line_idx + 1, key))),
+            };
+            let mut numbers = [0.0; 3];
+            for number in numbers.iter_mut().take(count) {
+                *number = next_word(&mut rest)
+                    .and_then(|s| s.parse().ok())
+                    .ok_or_else(|| invalid(format!("line {}: {} needs {} numbers", line_idx + 1, key, count)))?;
+            }
+            values.insert(key, numbers);
+        }
+
+        let mut sdf_type = match sdf_type {
+            Some(sdf_type) => sdf_type,
+            None => {
+                let source = expression_source.ok_or_else(|| invalid(format!("line {}: expression needs a source", line_idx + 1)))?;
+                let expression = Expression::parse(source, &parameters)
+                    .map_err(|error| invalid(format!("line {}: {}", line_idx + 1, error)))?;
+                SDF_Type::SDF_Custom(Arc::new(expression))
+            },
+        };
+        let can_rotate = !matches!(sdf_type, SDF_Type::SDF_Sphere | SDF_Type::SDF_Plane);
+
+        let get = |key: &str, default: Vector3<f32>| values.get(key).copied().unwrap_or(default);
+        if let SDF_Type::SDF_Mandelbulb { ref mut power, .. } = sdf_type {
+            *power = get("power", [8.0; 3])[0];
+        }
+        let colour = get("colour", [255.0; 3]);
+        let colour = [colour[0].clamp(0.0, 255.0) as u8, colour[1].clamp(0.0, 255.0) as u8, colour[2].clamp(0.0, 255.0) as u8];
+        let rotation = if can_rotate { Some(get("rotation", [0.0; 3])) } else { None };
+
+        let scale = get("scale", [1.0; 3]);
+        if !scale.iter().all(|&s| s > 0.0 && s.is_finite()) {
+            return Err(invalid(format!("line {}: scale has to be positive", line_idx + 1)));
+        }
+
+        let mut sdf = SDF::new(get("position", [0.0; 3]), get("size", [1.0; 3]), sdf_type, colour, rotation);
+        sdf.set_scale(scale);
+        let material = get("material", [0.0, 0.0, 1.0]);
+        sdf.material.reflectivity = material[0];
+        sdf.material.transparency = material[1];
+        sdf.material.ior = material[2];
+        objects.push(sdf);
+    }
+
+    Ok(objects)
+}
+
+//Returns how many objects couldn't be saved, and how many were saved without their texture or
+//modifiers
+pub fn save(objects: &[SDF], path: &Path) -> Result<(usize, usize)> {
+    let (source, skipped, incomplete) = to_string(objects);
+    fs::write(path, source)?;
+    Ok((skipped, incomplete))
+}
+
+pub fn load(path: &Path) -> Result<Vec<SDF>> {
+    parse(&fs::read_to_string(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::material::Material;
+    use crate::engine::modifier::Modifier;
+    use crate::engine::texture::{Pattern, Texture};
+
+    #[test]
+    fn round_trip() {
+        let mut torus = SDF::new_torus([-2.0, 0.0, 5.0], [1.0, 0.5], [0, 255, 0], [30.0, 45.0, 0.0]);
+        torus.set_scale([1.0, 2.0, 1.0]);
+        let mut sphere = SDF::new_sphere([2.0, 0.0, 5.0], 1.0, [255, 0, 0]);
+        sphere.set_material(Material::new_glossy(0.5));
+        let objects = vec![
+            SDF::new_plane(-1.0, [255, 255, 255]),
+            sphere,
+            torus,
+            SDF::new_round_box([0.0, 1.0, 3.0], 0.5, 0.25, 0.1, [10, 20, 30], [0.0, 90.0, 0.0]),
+            SDF::new_custom([0.0; 3], Arc::new(|p: Vector3<f32>| vmath::vec3_len(p) - 1.0), [0, 0, 0], [0.0; 3]),
+        ];
+
+        let (source, skipped, incomplete) = to_string(&objects);
+        assert_eq!((skipped, incomplete), (1, 0));
+        let loaded = parse(&source).unwrap();
+        assert_eq!(loaded.len(), 4);
+        for (a, b) in objects.iter().zip(loaded.iter()) {
+            assert_eq!(a.sdf_type.name(), b.sdf_type.name());
+            assert_eq!((a.position, a.size, a.scale, a.colour), (b.position, b.size, b.scale, b.colour));
+            assert_eq!((a.material.reflectivity, a.rotation.is_some()), (b.material.reflectivity, b.rotation.is_some()));
+            for p in &[[0.0, 0.0, 0.0], [-1.5, 0.3, 4.0], [0.2, 1.2, 3.1]] {
+                assert!((a.get_distance(*p) - b.get_distance(*p)).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn textures_and_modifiers() {
+        let mut floor = SDF::new_plane(-1.0, [255, 255, 255]);
+        floor.material.set_texture(Texture::new(Pattern::Checker, [0, 0, 0], 1.0));
+        let mut sphere = SDF::new_sphere([0.0, 0.0, 5.0], 1.0, [255, 0, 0]);
+        sphere.add_modifier(Modifier::Twist { amount: 0.5 });
+        sphere.add_modifier(Modifier::Twist { amount: 0.5 });
+        let objects = vec![floor, sphere, SDF::new_sphere([2.0, 0.0, 5.0], 1.0, [0, 255, 0])];
+
+        let (source, skipped, incomplete) = to_string(&objects);
+        assert_eq!((skipped, incomplete), (0, 2));
+        assert!(source.contains("#plane texture, 0 modifiers left out\n"));
+        assert!(source.contains("#sphere 2 modifiers left out\n"));
+        assert_eq!(parse(&source).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn expressions() {
+        let source = "expression position 0 1 5 rotation 0 45 0 param r 0.5 param source 2 source  smin(sphere(p, r), box(p, vec3(source * 0.25)), 0.3) ";
+        let loaded = parse(source).unwrap();
+        let expression = match loaded[0].sdf_type {
+            SDF_Type::SDF_Custom(ref field) => field.expression().unwrap(),
+            _ => panic!("not an expression"),
+        };
+        assert_eq!(expression.source, "smin(sphere(p, r), box(p, vec3(source * 0.25)), 0.3)");
+        assert_eq!(expression.parameters(), vec![("r", 0.5), ("source", 2.0)]);
+        assert!(loaded[0].rotation.is_some());
+
+        let (saved, skipped, _) = to_string(&loaded);
+        assert_eq!(skipped, 0);
+        let reloaded = parse(&saved).unwrap();
+        for p in &[[0.0, 1.0, 5.0], [0.4, 1.2, 4.5], [2.0, 0.0, 5.0]] {
+            assert!((loaded[0].get_distance(*p) - reloaded[0].get_distance(*p)).abs() < 1e-5);
+        }
+
+        for (source, message) in &[
+            ("expression position 0 0 0", "line 1: expression needs a source"),
+            ("expression param r source sphere(p, r)", "line 1: param needs a name and a number"),
+            ("expression source sphere(p, q)", "line 1: Unknown name 'q' at column 11"),
+            ("sphere source sphere(p, 1)", "line 1: unknown value 'source'"),
+        ] {
+            match parse(source) {
+                Err(error) => assert_eq!(error.to_string(), *message),
+                Ok(_) => panic!("{} parsed", source),
+            }
+        }
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse("# nothing\n\n").unwrap().is_empty());
+        assert!(parse("sphere size 1 2 3").unwrap()[0].size == [1.0, 2.0, 3.0]);
+        for source in &[
+            "teapot position 0 0 0", "sphere position 0 0", "sphere colour red", "sphere spin 1 2 3",
+            "sphere scale 1 0 1", "box scale -1 1 1", "torus scale nan 1 1",
+        ] {
+            match parse(source) {
+                Err(error) => assert!(error.to_string().starts_with("line 1:"), "{}", error),
+                Ok(_) => panic!("{} parsed", source),
+            }
+        }
+    }
+}
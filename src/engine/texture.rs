@@ -0,0 +1,259 @@
+extern crate vecmath as vmath;
+use vmath::{
+    Vector2, Vector3,
+};
+
+fn clamp(x: f32, a: f32, b: f32) -> f32 {
+    if x < a { return a };
+    if x > b { return b };
+    x
+}
+
+fn smooth(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+//Integer hash, so noise doesn't need a permutation table
+fn hash(x: i32, y: i32) -> u32 {
+    let mut h = (x as u32).wrapping_mul(0x8da6_b343) ^ (y as u32).wrapping_mul(0xd816_3841);
+    h = (h ^ (h >> 13)).wrapping_mul(0x5bd1_e995);
+    h ^ (h >> 15)
+}
+
+//Random value in 0..1 for a lattice point
+fn hash_value(x: i32, y: i32) -> f32 {
+    (hash(x, y) & 0xffff) as f32 / 65535.0
+}
+
+//Random unit gradient for a lattice point
+fn hash_gradient(x: i32, y: i32) -> Vector2<f32> {
+    let angle = hash_value(x, y) * 2.0 * std::f32::consts::PI;
+    [angle.cos(), angle.sin()]
+}
+
+fn value_noise(p: Vector2<f32>) -> f32 {
+    let (ix, iy) = (p[0].floor() as i32, p[1].floor() as i32);
+    let (fx, fy) = (p[0] - p[0].floor(), p[1] - p[1].floor());
+    let (u, v) = (smooth(fx), smooth(fy));
+
+    let a = lerp(hash_value(ix, iy), hash_value(ix + 1, iy), u);
+    let b = lerp(hash_value(ix, iy + 1), hash_value(ix + 1, iy + 1), u);
+    lerp(a, b, v)
+}
+
+//Returns roughly -1..1
+fn perlin_noise(p: Vector2<f32>) -> f32 {
+    let (ix, iy) = (p[0].floor() as i32, p[1].floor() as i32);
+    let (fx, fy) = (p[0] - p[0].floor(), p[1] - p[1].floor());
+    let (u, v) = (smooth(fx), smooth(fy));
+
+    let dot = |cx: i32, cy: i32| {
+        let g = hash_gradient(ix + cx, iy + cy);
+        g[0] * (fx - cx as f32) + g[1] * (fy - cy as f32)
+    };
+
+    let a = lerp(dot(0, 0), dot(1, 0), u);
+    let b = lerp(dot(0, 1), dot(1, 1), u);
+    lerp(a, b, v) * std::f32::consts::SQRT_2
+}
+
+//2D simplex noise, returns roughly -1..1
+fn simplex_noise(p: Vector2<f32>) -> f32 {
+    const F2: f32 = 0.366_025_4; //(sqrt(3) - 1) / 2
+    const G2: f32 = 0.211_324_87; //(3 - sqrt(3)) / 6
+
+    //Skew into simplex space to find which cell we're in
+    let s = (p[0] + p[1]) * F2;
+    let (i, j) = ((p[0] + s).floor() as i32, (p[1] + s).floor() as i32);
+    let t = (i + j) as f32 * G2;
+    let x0 = [p[0] - (i as f32 - t), p[1] - (j as f32 - t)];
+
+    let (i1, j1) = if x0[0] > x0[1] { (1, 0) } else { (0, 1) };
+    let x1 = [x0[0] - i1 as f32 + G2, x0[1] - j1 as f32 + G2];
+    let x2 = [x0[0] - 1.0 + 2.0 * G2, x0[1] - 1.0 + 2.0 * G2];
+
+    let corner = |x: Vector2<f32>, ci: i32, cj: i32| {
+        let t = 0.5 - x[0] * x[0] - x[1] * x[1];
+        if t < 0.0 {
+            return 0.0;
+        }
+        let g = hash_gradient(i + ci, j + cj);
+        t * t * t * t * (g[0] * x[0] + g[1] * x[1])
+    };
+
+    70.0 * (corner(x0, 0, 0) + corner(x1, i1, j1) + corner(x2, 1, 1))
+}
+
+#[derive(Copy, Clone)]
+pub enum Pattern {
+    Checker,
+    Stripes,
+    ValueNoise,
+    Perlin,
+    Simplex,
+    //Fractal brownian motion, several octaves of perlin noise
+    Fbm { octaves: u32 },
+}
+
+impl Pattern {
+    //Returns a value in 0..1
+    pub fn sample(&self, p: Vector2<f32>) -> f32 {
+        match self {
+            Pattern::Checker => {
+                let cell = p[0].floor() as i32 + p[1].floor() as i32;
+                if cell.rem_euclid(2) == 0 { 0.0 } else { 1.0 }
+            },
+            Pattern::Stripes => {
+                if (p[0].floor() as i32).rem_euclid(2) == 0 { 0.0 } else { 1.0 }
+            },
+            Pattern::ValueNoise => value_noise(p),
+            Pattern::Perlin => clamp(perlin_noise(p) * 0.5 + 0.5, 0.0, 1.0),
+            Pattern::Simplex => clamp(simplex_noise(p) * 0.5 + 0.5, 0.0, 1.0),
+            Pattern::Fbm { octaves } => {
+                let mut total = 0.0;
+                let mut amplitude = 0.5;
+                let mut frequency = 1.0;
+                let mut normalisation = 0.0;
+                for _ in 0.. *octaves {
+                    total += perlin_noise([p[0] * frequency, p[1] * frequency]) * amplitude;
+                    normalisation += amplitude;
+                    amplitude *= 0.5;
+                    frequency *= 2.0;
+                }
+                if normalisation <= 0.0 {
+                    return 0.5;
+                }
+                clamp(total / normalisation * 0.5 + 0.5, 0.0, 1.0)
+            },
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub enum TextureSpace {
+    World,
+    //Moves and rotates with the object
+    Object,
+}
+
+//A procedural pattern that blends between the SDF colour and a second colour
+#[derive(Copy, Clone)]
+pub struct Texture {
+    pub pattern: Pattern,
+    pub colour: Vector3<u8>,
+    pub scale: f32, //Size of one pattern cell, in world units
+    pub space: TextureSpace,
+}
+
+impl Texture {
+    pub fn new(pattern: Pattern, colour: Vector3<u8>, scale: f32) -> Texture {
+        Texture {
+            pattern,
+            colour,
+            scale,
+            space: TextureSpace::World,
+        }
+    }
+
+    //Projects the 2D pattern along the three axes and blends them by the normal,
+    //so surfaces without a uv parametrisation still get a texture without stretching
+    pub fn sample_triplanar(&self, position: Vector3<f32>, normal: Vector3<f32>) -> f32 {
+        let p = vmath::vec3_scale(position, 1.0 / self.scale);
+
+        let mut weights = [normal[0].abs().powi(4), normal[1].abs().powi(4), normal[2].abs().powi(4)];
+        let total = weights[0] + weights[1] + weights[2];
+        if total <= 0.0 {
+            return 0.0;
+        }
+        weights = vmath::vec3_scale(weights, 1.0 / total);
+
+        let mut value = 0.0;
+        //Axes without any weight are skipped, for flat surfaces this avoids sampling twice
+        if weights[0] > 0.001 { value += weights[0] * self.pattern.sample([p[1], p[2]]); }
+        if weights[1] > 0.001 { value += weights[1] * self.pattern.sample([p[2], p[0]]); }
+        if weights[2] > 0.001 { value += weights[2] * self.pattern.sample([p[0], p[1]]); }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::distance_field::SDF;
+
+    fn points() -> impl Iterator<Item = Vector2<f32>> {
+        (0..2000).map(|i| [(i as f32 * 0.137).sin() * 40.0, (i as f32 * 0.291).cos() * 40.0 + i as f32 * 0.01])
+    }
+
+    #[test]
+    fn noise_range() {
+        for pattern in &[Pattern::ValueNoise, Pattern::Perlin, Pattern::Simplex, Pattern::Fbm { octaves: 5 }] {
+            let (mut lowest, mut highest) = (1.0f32, 0.0f32);
+            for p in points() {
+                let v = pattern.sample(p);
+                assert!((0.0..=1.0).contains(&v));
+                lowest = lowest.min(v);
+                highest = highest.max(v);
+                //Smooth, so close points have close values
+                assert!((pattern.sample([p[0] + 0.001, p[1]]) - v).abs() < 0.05);
+            }
+            assert!(highest - lowest > 0.3, "{} to {}", lowest, highest);
+        }
+    }
+
+    #[test]
+    fn fbm() {
+        //A single octave is plain perlin noise, none is flat
+        for p in points().take(100) {
+            assert!((Pattern::Fbm { octaves: 1 }.sample(p) - Pattern::Perlin.sample(p)).abs() < 1e-6);
+            assert_eq!(Pattern::Fbm { octaves: 0 }.sample(p), 0.5);
+        }
+        //More octaves add detail, so neighbours differ more
+        let roughness = |octaves| points().map(|p| {
+            let pattern = Pattern::Fbm { octaves };
+            (pattern.sample([p[0] + 0.05, p[1]]) - pattern.sample(p)).abs()
+        }).sum::<f32>();
+        assert!(roughness(6) > roughness(1));
+    }
+
+    #[test]
+    fn triplanar() {
+        let texture = Texture::new(Pattern::Stripes, [0, 0, 0], 0.5);
+        //Facing up, the pattern runs over z and x. Stripes change with the first coordinate only.
+        let up = [0.0, 1.0, 0.0];
+        assert_eq!(texture.sample_triplanar([0.0, 0.0, 0.25], up), 0.0);
+        assert_eq!(texture.sample_triplanar([0.0, 0.0, 0.75], up), 1.0);
+        assert_eq!(texture.sample_triplanar([0.75, 0.0, 0.25], up), 0.0);
+        //Facing along x it runs over y and z
+        assert_eq!(texture.sample_triplanar([0.0, 0.75, 0.0], [1.0, 0.0, 0.0]), 1.0);
+
+        //A diagonal normal blends all three projections equally
+        let diagonal = vmath::vec3_normalized([1.0, 1.0, 1.0]);
+        let p = [0.75, 0.75, 0.25];
+        assert!((texture.sample_triplanar(p, diagonal) - 2.0 / 3.0).abs() < 1e-5);
+        assert_eq!(texture.sample_triplanar(p, [0.0; 3]), 0.0);
+    }
+
+    #[test]
+    fn albedo() {
+        let mut sdf = SDF::new_sphere([0.0, 0.0, 0.0], 1.0, [255, 255, 255]);
+        let up = [0.0, 1.0, 0.0];
+        assert_eq!(sdf.get_albedo([0.0, 1.0, 0.0], up), [1.0, 1.0, 1.0]);
+
+        sdf.material.set_texture(Texture::new(Pattern::Checker, [0, 0, 255], 1.0));
+        assert_eq!(sdf.get_albedo([0.5, 1.0, 0.5], up), [1.0, 1.0, 1.0]);
+        assert_eq!(sdf.get_albedo([1.5, 1.0, 0.5], up), [0.0, 0.0, 1.0]);
+
+        //In object space the pattern moves along with the object
+        let mut texture = Texture::new(Pattern::Checker, [0, 0, 255], 1.0);
+        texture.space = TextureSpace::Object;
+        sdf.material.set_texture(texture);
+        sdf.position = [1.0, 0.0, 0.0];
+        assert_eq!(sdf.get_albedo([1.5, 1.0, 0.5], up), [1.0, 1.0, 1.0]);
+        assert_eq!(sdf.get_albedo([2.5, 1.0, 0.5], up), [0.0, 0.0, 1.0]);
+    }
+}
@@ -0,0 +1,260 @@
+extern crate vecmath as vmath;
+use vmath::{
+    Vector3,
+};
+
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::thread;
+
+use super::{
+    distance_field::SDF,
+    scene::Scene,
+};
+
+const MAGIC: &[u8; 4] = b"TRVL";
+const VERSION: u8 = 1;
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+fn clamp(x: f32, a: f32, b: f32) -> f32 {
+    if x < a { return a };
+    if x > b { return b };
+    x
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+//A grid of precomputed distances. Sampling costs the same no matter how complex
+//the field was that got baked into it.
+#[derive(Clone)]
+pub struct Volume {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+    pub resolution: [usize; 3], //Samples along each axis, at least 2
+    pub distances: Vec<f32>, //x changes fastest, then y, then z
+}
+
+impl Volume {
+    //Samples field at every grid point between min and max. Slices are spread over threads,
+    //since baking a complicated scene at a high resolution takes a while.
+    pub fn bake<F: Fn(Vector3<f32>) -> f32 + Sync>(min: Vector3<f32>, max: Vector3<f32>, resolution: [usize; 3], field: F) -> Volume {
+        let resolution = [resolution[0].max(2), resolution[1].max(2), resolution[2].max(2)];
+        let mut distances = vec![0.0; resolution[0] * resolution[1] * resolution[2]];
+        let slice_size = resolution[0] * resolution[1];
+        let step = [
+            (max[0] - min[0]) / (resolution[0] - 1) as f32,
+            (max[1] - min[1]) / (resolution[1] - 1) as f32,
+            (max[2] - min[2]) / (resolution[2] - 1) as f32,
+        ];
+
+        let thread_count = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let slices_per_thread = resolution[2].div_ceil(thread_count);
+        let field = &field;
+
+        thread::scope(|s| {
+            for (chunk_idx, chunk) in distances.chunks_mut(slices_per_thread * slice_size).enumerate() {
+                s.spawn(move || {
+                    for (i, d) in chunk.iter_mut().enumerate() {
+                        let z = chunk_idx * slices_per_thread + i / slice_size;
+                        let y = (i % slice_size) / resolution[0];
+                        let x = i % resolution[0];
+                        *d = field([min[0] + x as f32 * step[0], min[1] + y as f32 * step[1], min[2] + z as f32 * step[2]]);
+                    }
+                });
+            }
+        });
+
+        Volume {
+            min,
+            max,
+            resolution,
+            distances,
+        }
+    }
+
+    pub fn bake_scene(scene: &Scene, min: Vector3<f32>, max: Vector3<f32>, resolution: [usize; 3]) -> Volume {
+        Volume::bake(min, max, resolution, |p| scene.get_distance(p).0)
+    }
+
+    pub fn bake_sdf(sdf: &SDF, min: Vector3<f32>, max: Vector3<f32>, resolution: [usize; 3]) -> Volume {
+        Volume::bake(min, max, resolution, |p| sdf.get_distance(p))
+    }
+
+    fn at(&self, x: usize, y: usize, z: usize) -> f32 {
+        self.distances[x + self.resolution[0] * (y + self.resolution[1] * z)]
+    }
+
+    //Trilinear interpolation between the eight closest samples. Outside the bounds the distance
+    //to the bounds is added on, so the baked region should fully contain the surface.
+    pub fn get_distance(&self, p: Vector3<f32>) -> f32 {
+        let mut outside = 0.0;
+        let mut cell = [0usize; 3];
+        let mut t = [0.0; 3];
+
+        for i in 0..3 {
+            let clamped = clamp(p[i], self.min[i], self.max[i]);
+            outside += (p[i] - clamped) * (p[i] - clamped);
+
+            let f = (clamped - self.min[i]) / (self.max[i] - self.min[i]) * (self.resolution[i] - 1) as f32;
+            let c = (f.floor() as usize).min(self.resolution[i] - 2);
+            cell[i] = c;
+            t[i] = f - c as f32;
+        }
+
+        let [x, y, z] = cell;
+        let c00 = lerp(self.at(x, y, z), self.at(x + 1, y, z), t[0]);
+        let c10 = lerp(self.at(x, y + 1, z), self.at(x + 1, y + 1, z), t[0]);
+        let c01 = lerp(self.at(x, y, z + 1), self.at(x + 1, y, z + 1), t[0]);
+        let c11 = lerp(self.at(x, y + 1, z + 1), self.at(x + 1, y + 1, z + 1), t[0]);
+        let d = lerp(lerp(c00, c10, t[1]), lerp(c01, c11, t[1]), t[2]);
+
+        d + outside.sqrt()
+    }
+
+    //Layout: magic, version, min, max, resolution, quantisation step, then one i16 per sample.
+    //16 bits is plenty for marching and halves the size compared to storing floats.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let largest = self.distances.iter().fold(0.0f32, |m, d| m.max(d.abs()));
+        let quantum = if largest > 0.0 { largest / i16::MAX as f32 } else { 1.0 };
+
+        let mut bytes = Vec::with_capacity(45 + self.distances.len() * 2);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        for v in self.min.iter().chain(self.max.iter()) {
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+        for r in &self.resolution {
+            bytes.extend_from_slice(&(*r as u32).to_le_bytes());
+        }
+        bytes.extend_from_slice(&quantum.to_le_bytes());
+        for d in &self.distances {
+            bytes.extend_from_slice(&((d / quantum).round() as i16).to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Volume> {
+        if bytes.len() < 45 || &bytes[0..4] != MAGIC {
+            return Err(invalid("Not a volume file"));
+        }
+        if bytes[4] != VERSION {
+            return Err(invalid("Unsupported volume file version"));
+        }
+
+        let read_f32 = |offset: usize| f32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]);
+        let read_u32 = |offset: usize| u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]) as usize;
+
+        let min = [read_f32(5), read_f32(9), read_f32(13)];
+        let max = [read_f32(17), read_f32(21), read_f32(25)];
+        let resolution = [read_u32(29), read_u32(33), read_u32(37)];
+        let quantum = read_f32(41);
+
+        if resolution.iter().any(|r| *r < 2) {
+            return Err(invalid("Volume resolution has to be at least 2 on every axis"));
+        }
+        if (0..3).any(|i| min[i].is_nan() || max[i].is_nan() || min[i] >= max[i]) {
+            return Err(invalid("Volume bounds are empty"));
+        }
+        //Infinite bounds would make every cell infinitely large and the lookups NaN
+        if min.iter().chain(max.iter()).any(|v| !v.is_finite()) {
+            return Err(invalid("Volume bounds have to be finite"));
+        }
+        if !quantum.is_finite() {
+            return Err(invalid("Volume quantisation step isn't a number"));
+        }
+        //The resolution comes straight from the file, so it can be far too big to fit
+        let length = resolution[0].checked_mul(resolution[1])
+            .and_then(|n| n.checked_mul(resolution[2]))
+            .and_then(|n| n.checked_mul(2))
+            .and_then(|n| n.checked_add(45))
+            .ok_or_else(|| invalid("Volume resolution is too large"))?;
+        if bytes.len() != length {
+            return Err(invalid("Volume file is truncated"));
+        }
+
+        let distances = bytes[45..].chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 * quantum).collect();
+        Ok(Volume {
+            min,
+            max,
+            resolution,
+            distances,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    pub fn load(path: &Path) -> Result<Volume> {
+        Volume::from_bytes(&fs::read(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sphere() -> Volume {
+        let sdf = SDF::new_sphere([0.0, 0.0, 0.0], 1.0, [255, 255, 255]);
+        Volume::bake_sdf(&sdf, [-2.0, -2.0, -2.0], [2.0, 2.0, 2.0], [33, 33, 33])
+    }
+
+    #[test]
+    fn sampling() {
+        let volume = sphere();
+        //Grid points are exact, in between the error is small for a smooth field
+        assert!((volume.get_distance([0.0, 0.0, 0.0]) + 1.0).abs() < 1e-4);
+        assert!((volume.get_distance([1.5, 0.0, 0.0]) - 0.5).abs() < 1e-4);
+        assert!((volume.get_distance([0.3, 0.7, -0.2]) - (0.62f32.sqrt() - 1.0)).abs() < 0.02);
+        //Outside the bounds
+        assert!((volume.get_distance([4.0, 0.0, 0.0]) - 3.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn round_trip() {
+        let volume = sphere();
+        let loaded = Volume::from_bytes(&volume.to_bytes()).unwrap();
+        assert_eq!(loaded.resolution, volume.resolution);
+        for (a, b) in volume.distances.iter().zip(loaded.distances.iter()) {
+            assert!((a - b).abs() < 1e-3);
+        }
+        assert!(Volume::from_bytes(&volume.to_bytes()[..100]).is_err());
+    }
+
+    #[test]
+    fn bad_headers() {
+        let header = |min: f32, max: f32, resolution: u32, quantum: f32| {
+            let mut bytes = MAGIC.to_vec();
+            bytes.push(VERSION);
+            for v in [min, min, min, max, max, max].iter() {
+                bytes.extend_from_slice(&v.to_le_bytes());
+            }
+            for _ in 0..3 {
+                bytes.extend_from_slice(&resolution.to_le_bytes());
+            }
+            bytes.extend_from_slice(&quantum.to_le_bytes());
+            bytes
+        };
+        let message = |bytes: Vec<u8>| match Volume::from_bytes(&bytes) {
+            Err(error) => error.to_string(),
+            Ok(_) => String::from("loaded"),
+        };
+
+        assert_eq!(message(header(-1.0, 1.0, u32::MAX, 1.0)), "Volume resolution is too large");
+        assert_eq!(message(header(1.0, 1.0, 2, 1.0)), "Volume bounds are empty");
+        assert_eq!(message(header(f32::NAN, 1.0, 2, 1.0)), "Volume bounds are empty");
+        assert_eq!(message(header(f32::NEG_INFINITY, 1.0, 2, 1.0)), "Volume bounds have to be finite");
+        assert_eq!(message(header(-1.0, f32::INFINITY, 2, 1.0)), "Volume bounds have to be finite");
+        assert_eq!(message(header(-1.0, 1.0, 2, f32::INFINITY)), "Volume quantisation step isn't a number");
+
+        let mut bytes = header(-1.0, 1.0, 2, 1.0);
+        bytes.extend_from_slice(&[0; 16]);
+        assert!(Volume::from_bytes(&bytes).is_ok());
+    }
+}
@@ -1,7 +1,7 @@
-pub const THREAD_COUNT: u16 = 8;
+pub const CAMERA_CLEARANCE: f32 = 0.5;
 
 use std::io::stdout;
-use std::thread;
+use std::time::{Duration, Instant};
 use std::sync::{Mutex, Arc};
 
 use crossterm::{
@@ -19,12 +19,19 @@ use engine::{
     distance_field::*,
     scene::Scene,
     camera::Camera,
+    fractal::Quality,
 };
 
 pub mod rendering;
 use rendering::{
     screen::Screen,
     debug_menu::DebugMenu,
+    render_pool::RenderPool,
+    frame_pacer::FramePacer,
+    frame_cache::FrameCache,
+    supersampling::{self, Supersampling},
+    outline::Outline,
+    profiler::FrameStats,
     raymarching::Ray,
 };
 
@@ -33,26 +40,6 @@ use vmath::{
     Vector3,
 };
 
-fn clamp(x: u16, a: u16, b: u16) -> u16 {
-    if x < a { return a };
-    if x > b { return b };
-    return x;
-}
-
-fn generate_ray(camera_yaw: f32, term_size: (u16, u16), px: u16, py: u16) -> Ray {
-    let fc = ((term_size.0 - px) as f32, (term_size.1 - py) as f32);
-    let p = ((-(term_size.0 as f32) + 2.0 * fc.0) / (term_size.1 as f32), (-(term_size.1 as f32) + 2.0 * fc.1) / (term_size.1 as f32));
-    let mut ray = Ray::new([0.0, 0.0, 0.0], vmath::vec3_normalized([p.0 * 0.5, p.1, 2.0]));
-
-    let r = camera_yaw / 180.0 * 3.14;
-    let dx = ray.direction[0] * r.cos() - ray.direction[2] * r.sin();
-    let dy = ray.direction[2] * r.cos() + ray.direction[0] * r.sin();
-    ray.direction[0] = -dx;
-    ray.direction[2] = dy;
-
-    return ray;
-}
-
 pub struct TerminalRaymarcher {
     pub scene_originator: Scene,
     pub screen_arc: Arc<Mutex<Screen>>,
@@ -60,6 +47,15 @@ pub struct TerminalRaymarcher {
     pub reader: AsyncReader,
     pub term_size: (u16, u16),
     pub camera: Camera,
+    pub render_pool: RenderPool,
+    pub frame_pacer: FramePacer,
+    pub frame_cache: FrameCache,
+    pub supersampling: Supersampling,
+    pub outline: Outline,
+    //Timings of the last frame, filled in by render and display
+    pub stats: FrameStats,
+    //Object picked with the mouse, outlined in Outline::selection_colour
+    pub selected: Option<usize>,
 }
 
 #[derive(Debug)]
@@ -82,6 +78,13 @@ impl TerminalRaymarcher {
             reader: input().read_async(),
             term_size: term_size,
             camera: Camera::new([0.0, 0.0, 0.0],0.0,0.0),
+            render_pool: RenderPool::new(RenderPool::default_thread_count()),
+            frame_pacer: FramePacer::new(30.0),
+            frame_cache: FrameCache::new(),
+            supersampling: Supersampling::RotatedGrid,
+            outline: Outline::new(),
+            stats: FrameStats::default(),
+            selected: None,
         })
     }
 
@@ -107,6 +110,56 @@ impl TerminalRaymarcher {
         self.scene_originator.update_rotation(idx, rotation);
     }
 
+    pub fn update_scale(&mut self, idx: usize, scale: Vector3<f32>) {
+        self.scene_originator.update_scale(idx, scale);
+    }
+
+    pub fn update_position(&mut self, idx: usize, position: Vector3<f32>) {
+        self.scene_originator.update_position(idx, position);
+    }
+
+    //Objects after idx move down by one
+    pub fn remove_sdf(&mut self, idx: usize) -> SDF {
+        self.scene_originator.remove_sdf(idx)
+    }
+
+    //Returns the object that was there
+    pub fn replace_sdf(&mut self, idx: usize, sdf: SDF) -> SDF {
+        self.scene_originator.replace_sdf(idx, sdf)
+    }
+
+    pub fn build_bvh(&mut self) {
+        self.scene_originator.build_bvh();
+    }
+
+    //Replaces the worker threads, waiting for the old ones to finish
+    pub fn set_thread_count(&mut self, thread_count: usize) {
+        self.render_pool = RenderPool::new(thread_count);
+    }
+
+    pub fn set_quality(&mut self, quality: Quality) {
+        self.scene_originator.set_quality(quality);
+    }
+
+    //Moves the camera relative to where it's facing, but never closer to a surface than
+    //CAMERA_CLEARANCE, so it slides up hills and doesn't end up inside the terrain
+    pub fn move_camera(&mut self, forward: f32, up: f32) {
+        let r = self.camera.yaw_radians();
+        let mut eye = self.camera.eye;
+        eye[0] += r.sin() * forward;
+        eye[2] += r.cos() * forward;
+        eye[1] += up;
+
+        for _ in 0..32 {
+            let (dist, _) = self.scene_originator.get_distance(eye);
+            if dist >= CAMERA_CLEARANCE {
+                break;
+            }
+            eye[1] += CAMERA_CLEARANCE - dist;
+        }
+        self.camera.eye = eye;
+    }
+
     pub fn get_object_count(&self) -> usize {
         self.scene_originator.distance_fields.len()
     }
@@ -151,54 +204,76 @@ impl TerminalRaymarcher {
         (*screen_handle).flush(self.term_size, (Color::Reset, Color::Reset));
     }
 
-    pub fn render(&self) -> Result<()> {
-        let scene = self.scene_originator.clone();
-
-        let mut thread_width = self.term_size.0 / THREAD_COUNT;
-        let mut handles = vec![];
+    pub fn render(&mut self) -> Result<()> {
+        self.frame_pacer.begin_frame(self.camera);
+        let resolution = self.frame_pacer.resolution(self.term_size);
+
+        //Extra rays per cell are the first thing to go when the pacer has to lower the resolution
+        let pattern = if self.frame_pacer.scale < 1.0 { Supersampling::None } else { self.supersampling };
+
+        //Nothing to do once a still frame has all its samples, but the screen still needs them,
+        //everything drawn over the last frame has to go
+        if let Some(jitter) = self.frame_cache.begin_frame(self.camera, &self.scene_originator, resolution, pattern) {
+            let mut scene = self.scene_originator.clone();
+            scene.camera = self.camera;
+            scene.max_steps = self.frame_pacer.max_steps(scene.max_steps);
+            scene.ao_jitter = jitter.ao;
+
+            //Jitter within the space each sample covers, so they don't wander into other cells
+            let offsets = pattern.offsets();
+            let spread = 1.0 / (offsets.len() as f32).sqrt();
+            let offsets = offsets.iter().map(|o| (o.0 + jitter.offset.0 * spread, o.1 + jitter.offset.1 * spread)).collect();
+            let traced = Instant::now();
+            let samples = self.render_pool.render(scene, resolution, offsets)?;
+            let elapsed = traced.elapsed();
+            self.stats.render = elapsed;
+            self.stats.utilisation = self.render_pool.busy_time().as_secs_f32() / (elapsed.as_secs_f32() * self.render_pool.thread_count() as f32).max(1e-9);
+            self.frame_cache.add_samples(&samples);
+        } else {
+            self.stats.render = Duration::from_secs(0);
+            self.stats.utilisation = 0.0;
+        }
 
-        for tx in 0..THREAD_COUNT {
-            let screen = Arc::clone(&self.screen_arc);
+        let post = Instant::now();
+        let samples = self.frame_cache.resolve();
+        let mut cells: Vec<_> = samples.iter().map(supersampling::to_cell).collect();
+        self.outline.apply(&samples, resolution, self.selected, &mut cells);
 
-            let camera_yaw = self.camera.yaw;
-            let scene_handle = scene.clone(); //Clone inside the thread loop, so we don't end up moving it
+        let screen = Arc::clone(&self.screen_arc);
+        let mut screen_handle = screen.lock().unwrap();
+        (*screen_handle).set_scaled(&cells, resolution);
+        self.stats.post_process = post.elapsed();
 
-            //This needs to here to ensure we actually fill the entire screen
-            if THREAD_COUNT * thread_width < self.term_size.0 {
-                thread_width += self.term_size.0 - THREAD_COUNT * thread_width;
-            }
+        //Only tracing gets cheaper at a lower resolution
+        self.frame_pacer.end_frame(self.stats.render);
+        Ok(())
+    }
 
-            let term_size = self.term_size;
-
-            let handle = thread::spawn(move || {
-                for px in clamp(tx * thread_width, 0, term_size.0) .. clamp(tx * thread_width + thread_width, 0, term_size.0) {
-                    for py in 0.. term_size.1 {
-                        let ray = generate_ray(camera_yaw, term_size, px, py);
-                        let ray_result = scene_handle.march(ray);
-                        let mut screen_handle = screen.lock().unwrap();
-                        (*screen_handle).set((px, py), ray_result);
-                    }
-                }
-            });
-            handles.push(handle);
-        }
+    //The ray rendering uses for a cell of the terminal
+    pub fn ray_through(&mut self, cell: (u16, u16)) -> Ray {
+        self.scene_originator.camera = self.camera;
+        self.scene_originator.generate_ray(self.term_size, cell.0, cell.1)
+    }
 
-        for handle in handles {
-            handle.join().unwrap();
+    //The object seen through a cell of the terminal
+    pub fn pick(&mut self, cell: (u16, u16)) -> Option<usize> {
+        if cell.0 >= self.term_size.0 || cell.1 >= self.term_size.1 {
+            return None;
         }
+        let ray = self.ray_through(cell);
+        self.scene_originator.cast(&ray).object
+    }
 
-        // let screen = Arc::clone(&self.screen_arc);
-        // let mut screen_handle = screen.lock().unwrap();
-        //
-        // (*screen_handle).render();
-
-        Ok(())
+    pub fn select(&mut self, selected: Option<usize>) {
+        self.selected = selected;
     }
 
-    pub fn display(&self) {
+    pub fn display(&mut self) {
+        let start = Instant::now();
         let screen = Arc::clone(&self.screen_arc);
         let mut screen_handle = screen.lock().unwrap();
 
-        (*screen_handle).render();
+        self.stats.bytes_written = (*screen_handle).render();
+        self.stats.output = start.elapsed();
     }
 }
@@ -4,14 +4,23 @@ use terminal_raymarcher::{
     Event,
     engine::{
         distance_field::SDF,
+        material::Material,
+        texture::{Texture, Pattern},
         scene::Scene,
+        scene_file,
+    },
+    rendering::{
+        debug_menu::DebugMenu,
+        editor::Editor,
+        supersampling::Supersampling,
     },
-    rendering::debug_menu::DebugMenu,
 };
 
 // use terminal_raymarcher::rendering::raymarching::Ray;
 use std::io::stdout;
 use std::thread;
+use std::env;
+use std::path::PathBuf;
 use std::time::SystemTime;
 use std::sync::{Mutex, Arc};
 
@@ -43,8 +52,6 @@ use vmath::{
     Vector3,
 };
 
-const THREAD_COUNT: u16 = 8;
-
 fn clamp(x: u16, a: u16, b: u16) -> u16 {
     if x < a { return a };
     if x > b { return b };
@@ -77,20 +84,66 @@ fn handle_input(tm: &mut TerminalRaymarcher, key: KeyEvent) {
     if key == KeyEvent::Char('a') {
         tm.camera.yaw -= 2.0;
     }
+    if key == KeyEvent::Char('w') {
+        tm.move_camera(0.25, 0.0);
+    }
+    if key == KeyEvent::Char('s') {
+        tm.move_camera(-0.25, 0.0);
+    }
+    if key == KeyEvent::Char('r') {
+        tm.move_camera(0.0, 0.25);
+    }
+    if key == KeyEvent::Char('f') {
+        tm.move_camera(0.0, -0.25);
+    }
+    if key == KeyEvent::Char('p') {
+        tm.frame_pacer.enabled = !tm.frame_pacer.enabled;
+    }
+    if key == KeyEvent::Char('c') {
+        tm.frame_cache.enabled = !tm.frame_cache.enabled;
+    }
+    if key == KeyEvent::Char('g') {
+        tm.supersampling = match tm.supersampling {
+            Supersampling::None => Supersampling::RotatedGrid,
+            Supersampling::RotatedGrid => Supersampling::Grid(3),
+            Supersampling::Grid(_) => Supersampling::None,
+        };
+    }
+    if key == KeyEvent::Char('v') {
+        let view = tm.scene_originator.debug_view.next();
+        tm.scene_originator.set_debug_view(view);
+    }
+    if key == KeyEvent::Char('o') {
+        tm.outline.enabled = !tm.outline.enabled;
+    }
+    if key == KeyEvent::Char('+') {
+        tm.frame_pacer.target_fps += 5.0;
+    }
+    if key == KeyEvent::Char('-') {
+        tm.frame_pacer.target_fps = (tm.frame_pacer.target_fps - 5.0).max(5.0);
+    }
 }
 
 //TODO: Look into this, for some reason I can only get it to work on linux
-fn handle_mouse(debug_menu: &mut DebugMenu, mouse: MouseEvent) {
-    if mouse == MouseEvent::Press(MouseButton::Left, 0, 1) ||
-        mouse == MouseEvent::Press(MouseButton::Left, 1, 1) ||
-        mouse == MouseEvent::Press(MouseButton::Left, 2, 1) {
-        debug_menu.folded = !debug_menu.folded;
+fn handle_mouse(tm: &mut TerminalRaymarcher, debug_menu: &mut DebugMenu, editor: &mut Editor, mouse: MouseEvent) {
+    if debug_menu.handle_mouse(mouse) || editor.handle_mouse(tm, mouse) {
+        return;
+    }
+    //Clicking the sky clears the selection
+    if let MouseEvent::Press(MouseButton::Left, x, y) = mouse {
+        let picked = tm.pick((x, y));
+        tm.select(picked);
     }
 }
 
 fn main() -> Result<()> {
     let term_size: (u16, u16) = terminal::size()?;
 
+    //A scene file to open and save to, the demo scene is used until it exists.
+    //Loaded before the terminal is taken over, so errors are readable.
+    let path = PathBuf::from(env::args().nth(1).unwrap_or_else(|| String::from("scene.txt")));
+    let loaded = if path.exists() { Some(scene_file::load(&path)?) } else { None };
+
     let mut tm = TerminalRaymarcher::new()?;
     tm.prepare()?;
 
@@ -119,18 +172,31 @@ fn main() -> Result<()> {
     // tm.render();
     // tm.flush(term_size, (Color::Reset, Color::Reset));
 
-    let plane = SDF::new_plane(-1.0, [255, 255, 255]);
-    tm.add_sdf(plane);
-    let sphere = SDF::new_sphere([2.0, 0.0, 5.0], 1.0, [255, 0, 0]);
-    tm.add_sdf(sphere);
-
     let mut rot_x = 0.0;
     let mut rot_y = 0.0;
     let mut rot_z = 0.0;
-    let torus = SDF::new_torus([-2.0, 0.0, 5.0], [1.0, 0.5], [0, 255, 0], [rot_x, rot_y, rot_z]);
-    let torus_idx = tm.add_sdf(torus);
+    let mut torus_idx = None;
+    if let Some(objects) = loaded {
+        for sdf in objects {
+            tm.add_sdf(sdf);
+        }
+    } else {
+        let mut plane = SDF::new_plane(-1.0, [255, 255, 255]);
+        let mut floor = Material::new_diffuse();
+        floor.set_texture(Texture::new(Pattern::Checker, [80, 80, 80], 1.0));
+        plane.set_material(floor);
+        tm.add_sdf(plane);
+        let mut sphere = SDF::new_sphere([2.0, 0.0, 5.0], 1.0, [255, 0, 0]);
+        sphere.set_material(Material::new_glossy(0.5));
+        tm.add_sdf(sphere);
+
+        let torus = SDF::new_torus([-2.0, 0.0, 5.0], [1.0, 0.5], [0, 255, 0], [rot_x, rot_y, rot_z]);
+        torus_idx = Some(tm.add_sdf(torus));
+    }
+    tm.build_bvh();
 
     let mut debug_menu = DebugMenu::new();
+    let mut editor = Editor::new(path);
     let mut deltatime = 0.0; //In seconds
     let header = "!== terminal_raymarcher v1.0 ";
 
@@ -142,19 +208,23 @@ fn main() -> Result<()> {
         while let Some(event) = tm.next_event() {
             match event {
                 Event::QuitGame => break 'main,
-                Event::HandleInput(key) => handle_input(&mut tm, key),
-                Event::HandleMouse(mouse) => handle_mouse(&mut debug_menu, mouse),
+                Event::HandleInput(key) if !editor.handle_key(&mut tm, key) => handle_input(&mut tm, key),
+                Event::HandleMouse(mouse) => handle_mouse(&mut tm, &mut debug_menu, &mut editor, mouse),
                 _ => {}
             }
         }
 
-        rot_x -= 100.5 * deltatime;
-        rot_y -= 20.5 * deltatime;
-        rot_z += 60.5 * deltatime;
-        tm.update_rotation(torus_idx, [rot_x, rot_y, rot_z]);
+        //The torus holds still while the scene is being edited, edits would move it around
+        if let (Some(idx), false, true) = (torus_idx, editor.active, editor.history.is_empty()) {
+            rot_x -= 100.5 * deltatime;
+            rot_y -= 20.5 * deltatime;
+            rot_z += 60.5 * deltatime;
+            tm.update_rotation(idx, [rot_x, rot_y, rot_z]);
+        }
 
         tm.render()?;
         debug_menu.render(&mut tm);
+        editor.render(&mut tm);
         let mut idx = 0;
         for c in header.chars() {
             tm.set((idx, 0), (c, Color::Red));
@@ -177,10 +247,17 @@ fn main() -> Result<()> {
 
         tm.display();
 
-        let deltatime_ms = start.elapsed().expect("Time went backwards!!").as_millis();
-        deltatime = (deltatime_ms as f32) / 1000.0;
+        let deltatime_us = start.elapsed().expect("Time went backwards!!").as_micros();
+        deltatime = (deltatime_us as f32) / 1_000_000.0;
         debug_menu.update_fps(1.0 / deltatime);
         debug_menu.update_obj_count(tm.get_object_count());
+        debug_menu.update_scale(tm.frame_pacer.scale);
+        debug_menu.update_samples(tm.frame_cache.samples());
+        debug_menu.update_view(tm.scene_originator.debug_view.name());
+        debug_menu.update_threads(tm.render_pool.thread_count());
+        debug_menu.update_camera(tm.camera);
+        debug_menu.update_profile(deltatime, tm.stats);
+        debug_menu.update_selection(tm.selected.map(|idx| (idx, &tm.scene_originator.distance_fields[idx])));
     }
 
     tm.quit()?;
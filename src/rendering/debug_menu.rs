@@ -1,13 +1,93 @@
 use crate::TerminalRaymarcher;
+use crate::engine::{camera::Camera, distance_field::SDF};
+use super::profiler::{Profiler, FrameStats};
 
 use crossterm::{
+    input::{MouseEvent, MouseButton},
     style::Color,
 };
 
+//Panels can't be resized smaller than this, the border and one line
+const MIN_PANEL_SIZE: (u16, u16) = (8, 3);
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PanelKind {
+    Stats,
+    //Frame time sparkline and min/avg/max/p99
+    Frame,
+    //Time spent in every part of a frame
+    Stages,
+    Camera,
+    //The picked object, shown even when the menu is folded
+    Inspector,
+}
+
+impl PanelKind {
+    pub fn title(&self) -> &'static str {
+        match *self {
+            PanelKind::Stats => "stats",
+            PanelKind::Frame => "frame",
+            PanelKind::Stages => "stages",
+            PanelKind::Camera => "camera",
+            PanelKind::Inspector => "inspector",
+        }
+    }
+}
+
+//A box of the overlay. The top row is its title bar, dragging it moves the panel, and dragging
+//the bottom right corner resizes it.
+#[derive(Copy, Clone, Debug)]
+pub struct Panel {
+    pub kind: PanelKind,
+    pub position: (u16, u16),
+    pub size: (u16, u16),
+}
+
+impl Panel {
+    pub fn new(kind: PanelKind, position: (u16, u16), size: (u16, u16)) -> Panel {
+        Panel {
+            kind,
+            position,
+            size,
+        }
+    }
+
+    fn on_title(&self, x: u16, y: u16) -> bool {
+        y == self.position.1 && x >= self.position.0 && x < self.position.0 + self.size.0
+    }
+
+    fn contains(&self, x: u16, y: u16) -> bool {
+        x >= self.position.0 && x < self.position.0 + self.size.0 && y >= self.position.1 && y < self.position.1 + self.size.1
+    }
+
+    fn on_corner(&self, x: u16, y: u16) -> bool {
+        x == self.position.0 + self.size.0 - 1 && y == self.position.1 + self.size.1 - 1
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Drag {
+    //Where in the panel it was grabbed
+    Move(u16, u16),
+    Resize,
+}
+
 pub struct DebugMenu {
     pub fps: f32,
     pub object_count: usize,
     pub term_size: (u16, u16),
+    pub scale: f32, //Resolution the frame pacer renders at, as a fraction of term_size
+    pub samples: u32, //Per pixel, from the frame cache
+    pub view: &'static str, //Name of the scene's debug view
+    pub threads: usize, //In the render pool
+    pub camera: Camera,
+    pub profiler: Profiler,
+    //Lines of the inspector, empty when nothing is selected
+    pub inspected: Vec<String>,
+
+    //Drawn in order, so the last one is on top. The first one has the button that folds the menu.
+    pub panels: Vec<Panel>,
+    dragging: Option<(usize, Drag)>,
 
     pub bg_col: Color,
     pub fg_col: Color,
@@ -21,6 +101,22 @@ impl DebugMenu {
             fps: 0.0,
             object_count: 0,
             term_size: (0,0),
+            scale: 1.0,
+            samples: 0,
+            view: "shaded",
+            threads: 0,
+            camera: Camera::new([0.0, 0.0, 0.0], 0.0, 0.0),
+            profiler: Profiler::new(),
+            inspected: Vec::new(),
+
+            panels: vec![
+                Panel::new(PanelKind::Stats, (0, 1), (14, 8)),
+                Panel::new(PanelKind::Frame, (0, 9), (30, 5)),
+                Panel::new(PanelKind::Stages, (0, 14), (22, 8)),
+                Panel::new(PanelKind::Camera, (0, 22), (22, 5)),
+                Panel::new(PanelKind::Inspector, (31, 1), (26, 8)),
+            ],
+            dragging: None,
 
             bg_col: Color::Rgb{r: 30, g: 20, b: 50},
             fg_col: Color::Rgb{r: 120, g: 0, b: 255},
@@ -35,44 +131,252 @@ impl DebugMenu {
         self.object_count = object_count;
     }
 
-    pub fn render(&self, tm: &mut TerminalRaymarcher) {
-        if self.folded {
-            tm.set((0,1), ('[', self.fg_col));
-            tm.set((1,1), ('+', self.fg_col));
-            tm.set((2,1), (']', self.fg_col));
-            for ix in 0..14 {
-                for iy in 1..6 {
-                    tm.set_bg((ix,iy), Color::Reset);
-                }
-            }
-            tm.set_bg((0,1), self.bg_col);
-            tm.set_bg((1,1), self.bg_col);
-            tm.set_bg((2,1), self.bg_col);
-
-        } else {
-            for ix in 0..14 {
-                for iy in 1..6 {
-                    tm.set_bg((ix,iy), self.bg_col);
-                }
-                tm.set((ix,1), ('-', self.fg_col));
-                tm.set((ix,5), ('-', self.fg_col));
+    pub fn update_scale(&mut self, scale: f32) {
+        self.scale = scale;
+    }
 
-                tm.set((0,1), ('[', self.fg_col));
-                tm.set((1,1), ('-', self.fg_col));
-                tm.set((2,1), (']', self.fg_col));
+    pub fn update_samples(&mut self, samples: u32) {
+        self.samples = samples;
+    }
 
-                for (i, c) in format!("fps: {}", (self.fps * 100.0).floor() / 100.0).chars().enumerate() {
-                    tm.set((i as u16, 2), (c, self.fg_col));
-                }
+    pub fn update_view(&mut self, view: &'static str) {
+        self.view = view;
+    }
+
+    pub fn update_threads(&mut self, threads: usize) {
+        self.threads = threads;
+    }
+
+    pub fn update_camera(&mut self, camera: Camera) {
+        self.camera = camera;
+    }
+
+    //frame_time in seconds, for the whole frame including input and the overlay
+    pub fn update_profile(&mut self, frame_time: f32, stats: FrameStats) {
+        self.profiler.add_frame(frame_time, stats);
+    }
+
+    pub fn update_selection(&mut self, selection: Option<(usize, &SDF)>) {
+        self.inspected = match selection {
+            Some((idx, sdf)) => {
+                let v = |v: [f32; 3]| format!("{:.2} {:.2} {:.2}", v[0], v[1], v[2]);
+                let rotation = sdf.get_rotation();
+                vec![
+                    format!("#{} {}", idx, sdf.sdf_type.name()),
+                    format!("pos {}", v(sdf.position)),
+                    format!("rot {:.0} {:.0} {:.0}", rotation[0], rotation[1], rotation[2]),
+                    format!("size {}", v(sdf.size)),
+                    format!("scale {}", v(sdf.scale)),
+                    format!("rgb {} {} {}", sdf.colour[0], sdf.colour[1], sdf.colour[2]),
+                ]
+            },
+            None => Vec::new(),
+        };
+    }
 
-                for (i, c) in format!("objs: {}", self.object_count).chars().enumerate() {
-                    tm.set((i as u16, 3), (c, self.fg_col));
+    fn is_visible(&self, panel: &Panel) -> bool {
+        match panel.kind {
+            PanelKind::Inspector => !self.inspected.is_empty(),
+            _ => !self.folded,
+        }
+    }
+
+    //Folds the menu with the button in the first panel, and moves and resizes panels.
+    //Returns whether the event was for the menu, clicks anywhere else are for the scene.
+    pub fn handle_mouse(&mut self, mouse: MouseEvent) -> bool {
+        match mouse {
+            MouseEvent::Press(MouseButton::Left, x, y) => {
+                let first = self.panels[0].position;
+                if y == first.1 && x >= first.0 && x < first.0 + 3 {
+                    self.folded = !self.folded;
+                    return true;
                 }
 
-                for (i, c) in format!("res: {};{}", self.term_size.0, self.term_size.1).chars().enumerate() {
-                    tm.set((i as u16, 4), (c, self.fg_col));
+                //Topmost first, and whatever is grabbed goes on top
+                let idx = match (0..self.panels.len()).rev().find(|&i| self.is_visible(&self.panels[i]) && self.panels[i].contains(x, y)) {
+                    Some(idx) => idx,
+                    None => return false,
+                };
+                let panel = self.panels[idx];
+                if panel.on_corner(x, y) || panel.on_title(x, y) {
+                    let drag = if panel.on_corner(x, y) { Drag::Resize } else { Drag::Move(x - panel.position.0, y - panel.position.1) };
+                    //The first panel keeps its place in the list, it has the fold button
+                    let idx = if idx == 0 { 0 } else {
+                        self.panels.remove(idx);
+                        self.panels.push(panel);
+                        self.panels.len() - 1
+                    };
+                    self.dragging = Some((idx, drag));
+                }
+                true
+            },
+            MouseEvent::Hold(x, y) => {
+                let (idx, drag) = match self.dragging {
+                    Some(dragging) => dragging,
+                    None => return false,
+                };
+                let panel = &mut self.panels[idx];
+                match drag {
+                    Drag::Move(dx, dy) => {
+                        //Row 0 is the header
+                        let max = (self.term_size.0.saturating_sub(panel.size.0), self.term_size.1.saturating_sub(panel.size.1).max(1));
+                        panel.position = (x.saturating_sub(dx).min(max.0), y.saturating_sub(dy).clamp(1, max.1));
+                    },
+                    Drag::Resize => {
+                        panel.size = (
+                            (x + 1).saturating_sub(panel.position.0).max(MIN_PANEL_SIZE.0),
+                            (y + 1).saturating_sub(panel.position.1).max(MIN_PANEL_SIZE.1),
+                        );
+                    },
                 }
+                true
+            },
+            MouseEvent::Release(_, _) => self.dragging.take().is_some(),
+            _ => false,
+        }
+    }
+
+    fn lines(&self, kind: PanelKind, width: usize) -> Vec<String> {
+        let ms = |t: f32| format!("{:.1}", t * 1000.0);
+        match kind {
+            PanelKind::Stats => vec![
+                format!("fps: {}", (self.fps * 100.0).floor() / 100.0),
+                format!("objs: {}", self.object_count),
+                format!("res: {};{}", self.term_size.0, self.term_size.1),
+                format!("scale: {}%", (self.scale * 100.0).round()),
+                format!("spp: {}", self.samples),
+                format!("view: {}", self.view),
+            ],
+            PanelKind::Frame => {
+                let (min, avg, max, p99) = self.profiler.summary();
+                vec![
+                    self.profiler.sparkline(width),
+                    format!("min {} avg {} ms", ms(min), ms(avg)),
+                    format!("max {} p99 {} ms", ms(max), ms(p99)),
+                ]
+            },
+            PanelKind::Stages => {
+                let last = &self.profiler.last;
+                vec![
+                    format!("render: {} ms", ms(last.render.as_secs_f32())),
+                    format!("post: {} ms", ms(last.post_process.as_secs_f32())),
+                    format!("output: {} ms", ms(last.output.as_secs_f32())),
+                    format!("stdout: {:.1} KB", last.bytes_written as f32 / 1024.0),
+                    format!("busy: {}%", (last.utilisation * 100.0).round()),
+                    format!("on {} threads", self.threads),
+                ]
+            },
+            PanelKind::Camera => vec![
+                format!("x {:.2} y {:.2}", self.camera.eye[0], self.camera.eye[1]),
+                format!("z {:.2}", self.camera.eye[2]),
+                format!("yaw {:.1} roll {:.1}", self.camera.yaw, self.camera.roll),
+            ],
+            PanelKind::Inspector => self.inspected.clone(),
+        }
+    }
+
+    //Clipped to the screen
+    fn put(&self, tm: &mut TerminalRaymarcher, pos: (u16, u16), value: char) {
+        if pos.0 < self.term_size.0 && pos.1 < self.term_size.1 {
+            tm.set(pos, (value, self.fg_col));
+            tm.set_bg(pos, self.bg_col);
+        }
+    }
+
+    fn render_panel(&self, tm: &mut TerminalRaymarcher, panel: &Panel, first: bool) {
+        let (x0, y0) = panel.position;
+        let (w, h) = panel.size;
+        for iy in 0..h {
+            for ix in 0..w {
+                let border = iy == 0 || iy == h - 1;
+                self.put(tm, (x0 + ix, y0 + iy), if border { '-' } else { ' ' });
+            }
+        }
+        self.put(tm, (x0 + w - 1, y0 + h - 1), '+');
+
+        let title = if first { format!("[-]{}", panel.kind.title()) } else { format!("[{}]", panel.kind.title()) };
+        for (i, c) in title.chars().take(w as usize).enumerate() {
+            self.put(tm, (x0 + i as u16, y0), c);
+        }
+
+        for (iy, line) in self.lines(panel.kind, w as usize).iter().take(h as usize - 2).enumerate() {
+            for (ix, c) in line.chars().take(w as usize).enumerate() {
+                self.put(tm, (x0 + ix as u16, y0 + 1 + iy as u16), c);
             }
         }
     }
+
+    pub fn render(&self, tm: &mut TerminalRaymarcher) {
+        if self.folded {
+            let (x, y) = self.panels[0].position;
+            for (i, c) in "[+]".chars().enumerate() {
+                self.put(tm, (x + i as u16, y), c);
+            }
+        }
+        for (i, panel) in self.panels.iter().enumerate() {
+            if self.is_visible(panel) {
+                self.render_panel(tm, panel, i == 0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drags_panels() {
+        let mut menu = DebugMenu::new();
+        menu.term_size = (80, 40);
+        menu.handle_mouse(MouseEvent::Press(MouseButton::Left, 1, 1));
+        assert!(!menu.folded);
+
+        //Grab the frame panel by its title, it comes to the top
+        menu.handle_mouse(MouseEvent::Press(MouseButton::Left, 5, 9));
+        menu.handle_mouse(MouseEvent::Hold(45, 12));
+        menu.handle_mouse(MouseEvent::Release(45, 12));
+        let panel = *menu.panels.last().unwrap();
+        assert_eq!((panel.kind, panel.position), (PanelKind::Frame, (40, 12)));
+
+        //Past the edge of the screen it stops
+        menu.handle_mouse(MouseEvent::Press(MouseButton::Left, 40, 12));
+        menu.handle_mouse(MouseEvent::Hold(79, 0));
+        assert_eq!(menu.panels.last().unwrap().position, (50, 1));
+        menu.handle_mouse(MouseEvent::Release(79, 0));
+
+        //Resizing from the corner, not below the smallest size
+        let corner = (50 + 30 - 1, 1 + 5 - 1);
+        menu.handle_mouse(MouseEvent::Press(MouseButton::Left, corner.0, corner.1));
+        menu.handle_mouse(MouseEvent::Hold(52, 2));
+        assert_eq!(menu.panels.last().unwrap().size, MIN_PANEL_SIZE);
+
+        //Holding without grabbing anything does nothing
+        assert!(menu.handle_mouse(MouseEvent::Release(52, 2)));
+        assert!(!menu.handle_mouse(MouseEvent::Hold(0, 30)));
+        assert_eq!(menu.panels.last().unwrap().position, (50, 1));
+
+        //Clicks outside the panels are for the scene, and so are clicks on the inspector
+        //while nothing is selected
+        assert!(!menu.handle_mouse(MouseEvent::Press(MouseButton::Left, 70, 30)));
+        assert!(menu.handle_mouse(MouseEvent::Press(MouseButton::Left, 5, 3)));
+        assert!(!menu.handle_mouse(MouseEvent::Press(MouseButton::Left, 35, 3)));
+    }
+
+    #[test]
+    fn inspects_selection() {
+        let mut menu = DebugMenu::new();
+        let sdf = SDF::new_torus([-2.0, 0.0, 5.0], [1.0, 0.5], [0, 255, 0], [30.0, 0.0, 0.0]);
+        menu.update_selection(Some((2, &sdf)));
+        assert_eq!(menu.inspected[0], "#2 torus");
+        assert_eq!(menu.inspected[1], "pos -2.00 0.00 5.00");
+        assert_eq!(menu.inspected[2], "rot 30 0 0");
+        assert_eq!(menu.inspected[5], "rgb 0 255 0");
+
+        //Shown while folded, and takes clicks
+        assert!(menu.folded);
+        assert!(menu.handle_mouse(MouseEvent::Press(MouseButton::Left, 35, 3)));
+        menu.update_selection(None);
+        assert!(menu.inspected.is_empty());
+    }
 }
@@ -0,0 +1,124 @@
+extern crate vecmath as vmath;
+use vmath::{
+    Vector3,
+};
+
+use super::raymarching::Radiance;
+
+fn clamp(x: f32, a: f32, b: f32) -> f32 {
+    if x < a { return a };
+    if x > b { return b };
+    x
+}
+
+//What the camera rays show instead of the lit scene, for finding out why something looks wrong
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DebugView {
+    Shaded,
+    //Surface normals, x y z as red green blue
+    Normals,
+    //Distance along the ray, white close to the camera and black at Scene::far
+    Depth,
+    //How many steps the camera ray took, as a heatmap up to Scene::max_steps
+    Steps,
+    //Every object in a different colour
+    Object,
+    AmbientOcclusion,
+    //Whether the sun reaches the surface, marched towards it
+    Shadow,
+}
+
+impl DebugView {
+    pub fn next(&self) -> DebugView {
+        match *self {
+            DebugView::Shaded => DebugView::Normals,
+            DebugView::Normals => DebugView::Depth,
+            DebugView::Depth => DebugView::Steps,
+            DebugView::Steps => DebugView::Object,
+            DebugView::Object => DebugView::AmbientOcclusion,
+            DebugView::AmbientOcclusion => DebugView::Shadow,
+            DebugView::Shadow => DebugView::Shaded,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DebugView::Shaded => "shaded",
+            DebugView::Normals => "normals",
+            DebugView::Depth => "depth",
+            DebugView::Steps => "steps",
+            DebugView::Object => "object",
+            DebugView::AmbientOcclusion => "ao",
+            DebugView::Shadow => "shadow",
+        }
+    }
+}
+
+//Greyscale of a value in 0..1, brighter glyphs for higher values
+pub fn grey(value: f32) -> Radiance {
+    let value = clamp(value, 0.0, 1.0);
+    Radiance::new([value; 3], value)
+}
+
+pub fn normal(normal: Vector3<f32>) -> Radiance {
+    Radiance::new([normal[0] * 0.5 + 0.5, normal[1] * 0.5 + 0.5, normal[2] * 0.5 + 0.5], 1.0)
+}
+
+//Blue through green and yellow to red for t in 0..1
+pub fn heatmap(t: f32) -> Radiance {
+    let t = clamp(t, 0.0, 1.0);
+    let colour = if t < 1.0 / 3.0 {
+        [0.0, t * 3.0, 1.0 - t * 3.0]
+    } else if t < 2.0 / 3.0 {
+        [t * 3.0 - 1.0, 1.0, 0.0]
+    } else {
+        [1.0, 3.0 - t * 3.0, 0.0]
+    };
+    Radiance::new(colour, t)
+}
+
+//Hues a golden angle apart, so neighbouring indices never look alike
+pub fn object(idx: usize) -> Radiance {
+    let hue = (idx as f32 * 0.618_034).fract() * 6.0;
+    let x = 1.0 - (hue % 2.0 - 1.0).abs();
+    let colour = match hue as u32 {
+        0 => [1.0, x, 0.0],
+        1 => [x, 1.0, 0.0],
+        2 => [0.0, 1.0, x],
+        3 => [0.0, x, 1.0],
+        4 => [x, 0.0, 1.0],
+        _ => [1.0, 0.0, x],
+    };
+    Radiance::new(colour, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cycles() {
+        let mut view = DebugView::Shaded;
+        let mut names = Vec::new();
+        for _ in 0..7 {
+            names.push(view.name());
+            view = view.next();
+        }
+        assert_eq!(view, DebugView::Shaded);
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), 7);
+    }
+
+    #[test]
+    fn colours() {
+        assert_eq!(heatmap(0.0).colour, [0.0, 0.0, 1.0]);
+        assert_eq!(heatmap(0.5).colour, [0.5, 1.0, 0.0]);
+        assert_eq!(heatmap(2.0).colour, [1.0, 0.0, 0.0]);
+        assert_eq!(normal([0.0, 1.0, -1.0]).colour, [0.5, 1.0, 0.0]);
+        for i in 0..16 {
+            assert!(object(i).colour != object(i + 1).colour);
+            assert!(object(i).colour.iter().all(|&c| (0.0..=1.0).contains(&c)));
+        }
+    }
+}
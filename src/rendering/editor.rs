@@ -0,0 +1,569 @@
+extern crate vecmath as vmath;
+use vmath::{
+    Vector3,
+};
+
+use std::path::PathBuf;
+
+use crate::TerminalRaymarcher;
+use crate::engine::{
+    distance_field::{SDF, SDF_Type},
+    history::{History, Edit},
+    scene_file,
+};
+
+use crossterm::{
+    input::{KeyEvent, MouseEvent, MouseButton},
+    style::Color,
+};
+
+const MOVE_STEP: f32 = 0.25;
+const ROTATE_STEP: f32 = 15.0; //Degrees
+const SCALE_STEP: f32 = 1.1;
+const MIN_SCALE: f32 = 0.05;
+//Mouse drags rotate and scale by this many steps per cell
+const DRAG_STEPS: f32 = 0.3;
+//New objects go this far in front of the camera
+const ADD_DISTANCE: f32 = 4.0;
+
+pub const PRIMITIVES: [&str; 11] = [
+    "sphere", "box", "round box", "torus", "capsule", "capped cylinder",
+    "cone", "ellipsoid", "octahedron", "pyramid", "mandelbulb",
+];
+
+//One of PRIMITIVES, about a unit in size
+pub fn new_primitive(idx: usize, position: Vector3<f32>) -> SDF {
+    let colour = [200, 200, 200];
+    let rotation = [0.0; 3];
+    match idx {
+        0 => SDF::new_sphere(position, 1.0, colour),
+        1 => SDF::new_cube(position, [0.75, 0.75, 0.75], colour, rotation),
+        2 => SDF::new_round_box(position, 0.75, 0.75, 0.15, colour, rotation),
+        3 => SDF::new_torus(position, [1.0, 0.4], colour, rotation),
+        4 => SDF::new_capsule(position, 0.5, 0.75, colour, rotation),
+        5 => SDF::new_capped_cylinder(position, 0.75, 0.75, colour, rotation),
+        6 => SDF::new_cone(position, 0.75, 1.5, colour, rotation),
+        7 => SDF::new_ellipsoid(position, [1.0, 0.6, 0.6], colour, rotation),
+        8 => SDF::new_octahedron(position, 1.0, colour, rotation),
+        9 => SDF::new_pyramid(position, 1.5, 1.5, colour, rotation),
+        _ => SDF::new_mandelbulb(position, 1.0, 8.0, colour, rotation),
+    }
+}
+
+//What arrows, page up/down and mouse drags do to the selected object
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Gizmo {
+    Move,
+    Rotate,
+    Scale,
+}
+
+impl Gizmo {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Gizmo::Move => "move",
+            Gizmo::Rotate => "rotate",
+            Gizmo::Scale => "scale",
+        }
+    }
+
+    //The part of an object the gizmo changes, rotation in degrees
+    pub fn get(&self, sdf: &SDF) -> Vector3<f32> {
+        match *self {
+            Gizmo::Move => sdf.position,
+            Gizmo::Rotate => sdf.get_rotation(),
+            Gizmo::Scale => sdf.scale,
+        }
+    }
+
+    //value changed along one axis by a number of steps, which can be fractions
+    pub fn step(&self, mut value: Vector3<f32>, axis: usize, steps: f32) -> Vector3<f32> {
+        match *self {
+            Gizmo::Move => value[axis] += steps * MOVE_STEP,
+            Gizmo::Rotate => value[axis] += steps * ROTATE_STEP,
+            Gizmo::Scale => value[axis] = (value[axis] * SCALE_STEP.powf(steps)).max(MIN_SCALE),
+        }
+        value
+    }
+
+    fn set(&self, tm: &mut TerminalRaymarcher, idx: usize, value: Vector3<f32>) {
+        match *self {
+            Gizmo::Move => tm.update_position(idx, value),
+            Gizmo::Rotate => tm.update_rotation(idx, value),
+            Gizmo::Scale => tm.update_scale(idx, value),
+        }
+    }
+}
+
+//Text fields for the numbers of an object, applied all at once
+pub struct Form {
+    //The object the form was opened for, which is what it applies to
+    pub idx: usize,
+    pub fields: Vec<(&'static str, String)>,
+    pub cursor: usize,
+}
+
+impl Form {
+    pub fn new(idx: usize, sdf: &SDF) -> Form {
+        let mut fields = vec![
+            ("x", sdf.position[0].to_string()),
+            ("y", sdf.position[1].to_string()),
+            ("z", sdf.position[2].to_string()),
+            ("size x", sdf.size[0].to_string()),
+            ("size y", sdf.size[1].to_string()),
+            (if matches!(sdf.sdf_type, SDF_Type::SDF_RoundBox) { "radius" } else { "size z" }, sdf.size[2].to_string()),
+            ("red", sdf.colour[0].to_string()),
+            ("green", sdf.colour[1].to_string()),
+            ("blue", sdf.colour[2].to_string()),
+            ("reflectivity", sdf.material.reflectivity.to_string()),
+        ];
+        if let SDF_Type::SDF_Mandelbulb { power, .. } = sdf.sdf_type {
+            fields.push(("power", power.to_string()));
+        }
+        Form {
+            idx,
+            fields,
+            cursor: 0,
+        }
+    }
+
+    pub fn handle_key(&mut self, key: KeyEvent) {
+        let count = self.fields.len();
+        match key {
+            KeyEvent::Up => self.cursor = (self.cursor + count - 1) % count,
+            KeyEvent::Down | KeyEvent::Tab => self.cursor = (self.cursor + 1) % count,
+            KeyEvent::Backspace => { self.fields[self.cursor].1.pop(); },
+            KeyEvent::Char(c) if c.is_ascii_digit() || c == '.' || c == '-' => self.fields[self.cursor].1.push(c),
+            _ => {},
+        }
+    }
+
+    //sdf with the values of the form, or the label of the first one that isn't a valid number
+    pub fn apply(&self, sdf: &SDF) -> Result<SDF, &'static str> {
+        let original = sdf;
+        let mut sdf = sdf.clone();
+        for (label, text) in &self.fields {
+            let value: f32 = text.trim().parse().map_err(|_| *label)?;
+            let colour = || if (0.0..=255.0).contains(&value) { Ok(value as u8) } else { Err(*label) };
+            //Sizes have to stay positive. Planes keep their normal in size, and parts of size a
+            //shape doesn't use are 0, those can stay that way.
+            let size = |axis: usize| {
+                let unused = value == 0.0 && original.size[axis] == 0.0;
+                if value > 0.0 || unused || matches!(original.sdf_type, SDF_Type::SDF_Plane) { Ok(value) } else { Err(*label) }
+            };
+            match *label {
+                "x" => sdf.position[0] = value,
+                "y" => sdf.position[1] = value,
+                "z" => sdf.position[2] = value,
+                "size x" => sdf.size[0] = size(0)?,
+                "size y" => sdf.size[1] = size(1)?,
+                "size z" | "radius" => sdf.size[2] = size(2)?,
+                "red" => sdf.colour[0] = colour()?,
+                "green" => sdf.colour[1] = colour()?,
+                "blue" => sdf.colour[2] = colour()?,
+                "reflectivity" => sdf.material.reflectivity = value.clamp(0.0, 1.0),
+                _ => if let SDF_Type::SDF_Mandelbulb { ref mut power, .. } = sdf.sdf_type {
+                    *power = value;
+                },
+            }
+        }
+        Ok(sdf)
+    }
+}
+
+//A mouse drag on the selected object, with the object as it was when it started
+struct Drag {
+    start: (u16, u16),
+    before: SDF,
+}
+
+//Adds, removes and changes objects of the scene, with undo. Edits go through the raymarcher so the
+//frame cache and BVH see them, and are drawn over the frame like the debug menu.
+pub struct Editor {
+    pub active: bool,
+    pub gizmo: Gizmo,
+    //Where S saves the scene
+    pub path: PathBuf,
+    pub history: History,
+    //Cursor in PRIMITIVES while the add menu is open
+    pub adding: Option<usize>,
+    pub form: Option<Form>,
+    drag: Option<Drag>,
+    //Result of the last thing done, shown in the status line
+    pub message: String,
+
+    pub bg_col: Color,
+    pub fg_col: Color,
+}
+
+impl Editor {
+    pub fn new(path: PathBuf) -> Editor {
+        Editor {
+            active: false,
+            gizmo: Gizmo::Move,
+            path,
+            history: History::new(),
+            adding: None,
+            form: None,
+            drag: None,
+            message: String::new(),
+
+            bg_col: Color::Rgb{r: 30, g: 20, b: 50},
+            fg_col: Color::Rgb{r: 120, g: 0, b: 255},
+        }
+    }
+
+    //Records an edit that was made, and selects what it left behind
+    fn record(&mut self, tm: &mut TerminalRaymarcher, edit: Edit) {
+        tm.select(edit.selection(true));
+        self.history.push(edit);
+    }
+
+    fn add(&mut self, tm: &mut TerminalRaymarcher, primitive: usize) {
+        let r = tm.camera.yaw_radians();
+        let eye = tm.camera.eye;
+        let position = [eye[0] + r.sin() * ADD_DISTANCE, eye[1], eye[2] + r.cos() * ADD_DISTANCE];
+        let sdf = new_primitive(primitive, position);
+        let idx = tm.add_sdf(sdf.clone());
+        self.record(tm, Edit::Add { idx, sdf });
+        self.message = format!("added {}", PRIMITIVES[primitive]);
+    }
+
+    fn nudge(&mut self, tm: &mut TerminalRaymarcher, idx: usize, axis: usize, steps: f32) {
+        let before = tm.scene_originator.distance_fields[idx].clone();
+        if self.gizmo == Gizmo::Rotate && before.rotation.is_none() {
+            self.message = format!("a {} can't rotate", before.sdf_type.name());
+            return;
+        }
+        let value = self.gizmo.step(self.gizmo.get(&before), axis, steps);
+        self.gizmo.set(tm, idx, value);
+        let after = tm.scene_originator.distance_fields[idx].clone();
+        self.record(tm, Edit::Replace { idx, before, after });
+    }
+
+    fn save(&mut self, tm: &TerminalRaymarcher) {
+        let objects = &tm.scene_originator.distance_fields;
+        self.message = match scene_file::save(objects, &self.path) {
+            Ok((0, 0)) => format!("saved {} objects to {}", objects.len(), self.path.display()),
+            Ok((skipped, incomplete)) => format!(
+                "saved {} objects to {}, {} left out, {} without textures or modifiers",
+                objects.len() - skipped, self.path.display(), skipped, incomplete,
+            ),
+            Err(error) => format!("couldn't save: {}", error),
+        };
+    }
+
+    //Returns whether the key was for the editor, the rest still move the camera
+    pub fn handle_key(&mut self, tm: &mut TerminalRaymarcher, key: KeyEvent) -> bool {
+        if !self.active {
+            if key == KeyEvent::Char('e') {
+                self.active = true;
+                return true;
+            }
+            return false;
+        }
+
+        //Open menus take every key
+        if let Some(cursor) = self.adding {
+            match key {
+                KeyEvent::Up => self.adding = Some((cursor + PRIMITIVES.len() - 1) % PRIMITIVES.len()),
+                KeyEvent::Down | KeyEvent::Tab => self.adding = Some((cursor + 1) % PRIMITIVES.len()),
+                KeyEvent::Enter => {
+                    self.adding = None;
+                    self.add(tm, cursor);
+                },
+                KeyEvent::Char('q') | KeyEvent::Char('n') => self.adding = None,
+                _ => {},
+            }
+            return true;
+        }
+        if let Some(form) = &mut self.form {
+            match key {
+                KeyEvent::Enter => {
+                    let idx = form.idx;
+                    let before = tm.scene_originator.distance_fields[idx].clone();
+                    match form.apply(&before) {
+                        Ok(after) => {
+                            tm.replace_sdf(idx, after.clone());
+                            self.record(tm, Edit::Replace { idx, before, after });
+                            self.form = None;
+                            self.message = String::from("applied");
+                        },
+                        Err(label) => self.message = format!("{} isn't valid", label),
+                    }
+                },
+                KeyEvent::Char('q') => self.form = None,
+                key => form.handle_key(key),
+            }
+            return true;
+        }
+
+        self.message.clear();
+        let selected = tm.selected;
+        match (key, selected) {
+            (KeyEvent::Char('e'), _) => {
+                self.active = false;
+                self.drag = None;
+            },
+            (KeyEvent::Char('n'), _) => self.adding = Some(0),
+            (KeyEvent::Char('x'), Some(idx)) | (KeyEvent::Delete, Some(idx)) => {
+                let sdf = tm.remove_sdf(idx);
+                self.message = format!("removed {}", sdf.sdf_type.name());
+                self.record(tm, Edit::Remove { idx, sdf });
+            },
+            (KeyEvent::Char('1'), _) => self.gizmo = Gizmo::Move,
+            (KeyEvent::Char('2'), _) => self.gizmo = Gizmo::Rotate,
+            (KeyEvent::Char('3'), _) => self.gizmo = Gizmo::Scale,
+            (KeyEvent::Left, Some(idx)) => self.nudge(tm, idx, 0, -1.0),
+            (KeyEvent::Right, Some(idx)) => self.nudge(tm, idx, 0, 1.0),
+            (KeyEvent::Down, Some(idx)) => self.nudge(tm, idx, 1, -1.0),
+            (KeyEvent::Up, Some(idx)) => self.nudge(tm, idx, 1, 1.0),
+            (KeyEvent::PageDown, Some(idx)) => self.nudge(tm, idx, 2, -1.0),
+            (KeyEvent::PageUp, Some(idx)) => self.nudge(tm, idx, 2, 1.0),
+            (KeyEvent::Char('i'), Some(idx)) => self.form = Some(Form::new(idx, &tm.scene_originator.distance_fields[idx])),
+            (KeyEvent::Char('z'), _) => {
+                match self.history.undo(&mut tm.scene_originator) {
+                    Some(edit) => tm.select(edit.selection(false)),
+                    None => self.message = String::from("nothing to undo"),
+                }
+            },
+            (KeyEvent::Char('y'), _) => {
+                match self.history.redo(&mut tm.scene_originator) {
+                    Some(edit) => tm.select(edit.selection(true)),
+                    None => self.message = String::from("nothing to redo"),
+                }
+            },
+            (KeyEvent::Char('S'), _) => self.save(tm),
+            _ => return false,
+        }
+        true
+    }
+
+    //Clicking picks an object, and dragging it moves, rotates or scales it with the gizmo.
+    //A whole drag is undone at once.
+    pub fn handle_mouse(&mut self, tm: &mut TerminalRaymarcher, mouse: MouseEvent) -> bool {
+        if !self.active {
+            return false;
+        }
+        //Open menus keep the selection they were opened for
+        if self.form.is_some() || self.adding.is_some() {
+            return true;
+        }
+        match mouse {
+            MouseEvent::Press(MouseButton::Left, x, y) => {
+                let picked = tm.pick((x, y));
+                tm.select(picked);
+                self.drag = picked.map(|idx| Drag {
+                    start: (x, y),
+                    before: tm.scene_originator.distance_fields[idx].clone(),
+                });
+                true
+            },
+            MouseEvent::Hold(x, y) => {
+                let (idx, drag) = match (tm.selected, &self.drag) {
+                    (Some(idx), Some(drag)) => (idx, drag),
+                    _ => return false,
+                };
+                let before = &drag.before;
+                let (dx, dy) = (x as f32 - drag.start.0 as f32, drag.start.1 as f32 - y as f32);
+                let value = match self.gizmo {
+                    //Keeps the object the same distance from the camera, under the mouse
+                    Gizmo::Move => {
+                        let distance = vmath::vec3_len(vmath::vec3_sub(before.position, tm.camera.eye));
+                        let from = tm.ray_through(drag.start).direction;
+                        let to = tm.ray_through((x, y)).direction;
+                        vmath::vec3_add(before.position, vmath::vec3_scale(vmath::vec3_sub(to, from), distance))
+                    },
+                    Gizmo::Rotate if before.rotation.is_none() => return true,
+                    Gizmo::Rotate => {
+                        let rotation = self.gizmo.step(before.get_rotation(), 1, dx * DRAG_STEPS);
+                        self.gizmo.step(rotation, 0, dy * DRAG_STEPS)
+                    },
+                    Gizmo::Scale => {
+                        let steps = (dx + dy) * DRAG_STEPS;
+                        (0..3).fold(before.scale, |scale, axis| self.gizmo.step(scale, axis, steps))
+                    },
+                };
+                self.gizmo.set(tm, idx, value);
+                true
+            },
+            MouseEvent::Release(_, _) => {
+                let (idx, drag) = match (tm.selected, self.drag.take()) {
+                    (Some(idx), Some(drag)) => (idx, drag),
+                    _ => return false,
+                };
+                let after = tm.scene_originator.distance_fields[idx].clone();
+                let before = drag.before;
+                if (before.position, before.rotation, before.scale) != (after.position, after.rotation, after.scale) {
+                    self.record(tm, Edit::Replace { idx, before, after });
+                }
+                true
+            },
+            _ => false,
+        }
+    }
+
+    //Clipped to the screen
+    fn put(&self, tm: &mut TerminalRaymarcher, pos: (i32, i32), value: char, colours: (Color, Color)) {
+        if pos.0 >= 0 && pos.1 >= 0 && pos.0 < tm.term_size.0 as i32 && pos.1 < tm.term_size.1 as i32 {
+            let pos = (pos.0 as u16, pos.1 as u16);
+            tm.set(pos, (value, colours.0));
+            tm.set_bg(pos, colours.1);
+        }
+    }
+
+    //Axis lines from the centre of the selected object, a world unit long
+    fn render_gizmo(&self, tm: &mut TerminalRaymarcher, sdf: &SDF) {
+        let camera = tm.camera;
+        let centre = match camera.project(tm.term_size, sdf.position) {
+            Some(centre) => centre,
+            None => return,
+        };
+        let axes = [('x', Color::Red), ('y', Color::Green), ('z', Color::Blue)];
+        for (axis, &(name, colour)) in axes.iter().enumerate() {
+            let mut end = sdf.position;
+            end[axis] += 1.0;
+            let end = match camera.project(tm.term_size, end) {
+                Some(end) => end,
+                None => continue,
+            };
+            let (dx, dy) = (end.0 - centre.0, end.1 - centre.1);
+            let steps = dx.abs().max(dy.abs()).ceil().max(1.0) as i32;
+            for i in 1..steps {
+                let t = i as f32 / steps as f32;
+                let pos = ((centre.0 + dx * t) as i32, (centre.1 + dy * t) as i32);
+                let glyph = if self.gizmo == Gizmo::Rotate { '·' } else { '•' };
+                self.put(tm, pos, glyph, (colour, Color::Reset));
+            }
+            let name = if self.gizmo == Gizmo::Scale { name.to_ascii_uppercase() } else { name };
+            self.put(tm, (end.0 as i32, end.1 as i32), name, (Color::White, colour));
+        }
+        self.put(tm, (centre.0 as i32, centre.1 as i32), '+', (Color::Yellow, Color::Reset));
+    }
+
+    //A box in the top right corner, with the line at the cursor highlighted
+    fn render_panel(&self, tm: &mut TerminalRaymarcher, title: &str, lines: &[String], cursor: usize) {
+        let width = lines.iter().map(|line| line.chars().count()).max().unwrap_or(0).max(title.len() + 2) as i32 + 2;
+        let x0 = tm.term_size.0 as i32 - width - 1;
+        let colours = (self.fg_col, self.bg_col);
+        for ix in 0..width {
+            self.put(tm, (x0 + ix, 1), '-', colours);
+            self.put(tm, (x0 + ix, lines.len() as i32 + 2), '-', colours);
+        }
+        for (i, c) in format!("[{}]", title).chars().enumerate() {
+            self.put(tm, (x0 + i as i32, 1), c, colours);
+        }
+        for (iy, line) in lines.iter().enumerate() {
+            let colours = if iy == cursor { (self.bg_col, self.fg_col) } else { colours };
+            let line: Vec<char> = line.chars().collect();
+            for ix in 0..width {
+                let c = if ix >= 1 && ix as usize <= line.len() { line[ix as usize - 1] } else { ' ' };
+                self.put(tm, (x0 + ix, iy as i32 + 2), c, colours);
+            }
+        }
+    }
+
+    pub fn render(&self, tm: &mut TerminalRaymarcher) {
+        if !self.active {
+            return;
+        }
+
+        if let Some(idx) = tm.selected {
+            let sdf = tm.scene_originator.distance_fields[idx].clone();
+            self.render_gizmo(tm, &sdf);
+        }
+
+        if let Some(cursor) = self.adding {
+            let lines: Vec<String> = PRIMITIVES.iter().map(|name| name.to_string()).collect();
+            self.render_panel(tm, "add", &lines, cursor);
+        } else if let Some(form) = &self.form {
+            let width = form.fields.iter().map(|field| field.0.len()).max().unwrap_or(0);
+            let lines: Vec<String> = form.fields.iter().map(|(label, text)| format!("{:>w$} {:<8}", label, text, w = width)).collect();
+            self.render_panel(tm, "object", &lines, form.cursor);
+        }
+
+        let help = if self.adding.is_some() {
+            "up/down pick, enter add, q close"
+        } else if self.form.is_some() {
+            "up/down field, type numbers, enter apply, q cancel"
+        } else {
+            "n add  x remove  1 move 2 rotate 3 scale  arrows pgup/pgdn  i edit  z undo  y redo  S save  e done"
+        };
+        let status = format!(" {} | {} | {}", self.gizmo.name(), help, self.message);
+        let y = tm.term_size.1 as i32 - 1;
+        let colours = (self.fg_col, self.bg_col);
+        let status: Vec<char> = status.chars().collect();
+        for x in 0..tm.term_size.0 as i32 {
+            self.put(tm, (x, y), status.get(x as usize).copied().unwrap_or(' '), colours);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gizmo_steps() {
+        let sdf = SDF::new_torus([0.0, 0.0, 5.0], [1.0, 0.5], [0, 255, 0], [0.0, 30.0, 0.0]);
+        assert_eq!(Gizmo::Move.step(Gizmo::Move.get(&sdf), 2, -2.0), [0.0, 0.0, 4.5]);
+
+        let rotation = Gizmo::Rotate.step(Gizmo::Rotate.get(&sdf), 1, 1.0);
+        assert!((rotation[1] - 45.0).abs() < 1e-3);
+
+        let scale = Gizmo::Scale.step(sdf.scale, 0, 1.0);
+        assert!((scale[0] - 1.1).abs() < 1e-6 && scale[1] == 1.0);
+        assert_eq!(Gizmo::Scale.step(sdf.scale, 0, -100.0)[0], MIN_SCALE);
+    }
+
+    #[test]
+    fn form_applies() {
+        let sdf = SDF::new_round_box([1.0, 2.0, 3.0], 0.5, 0.5, 0.1, [10, 20, 30], [0.0; 3]);
+        let mut form = Form::new(0, &sdf);
+        assert_eq!(form.fields[5], ("radius", String::from("0.1")));
+
+        //Retype x, then skip to red and clear it
+        form.handle_key(KeyEvent::Backspace);
+        for c in "-2.5".chars() {
+            form.handle_key(KeyEvent::Char(c));
+        }
+        form.handle_key(KeyEvent::Char('w'));
+        form.cursor = 6;
+        form.handle_key(KeyEvent::Backspace);
+        form.handle_key(KeyEvent::Backspace);
+        assert_eq!(form.apply(&sdf).err(), Some("red"));
+
+        form.handle_key(KeyEvent::Char('9'));
+        form.cursor = form.fields.iter().position(|f| f.0 == "radius").unwrap();
+        form.handle_key(KeyEvent::Char('5'));
+        let edited = form.apply(&sdf).ok().unwrap();
+        assert_eq!(edited.position, [-2.5, 2.0, 3.0]);
+        assert_eq!(edited.colour, [9, 20, 30]);
+        assert_eq!(edited.size, [0.5, 0.5, 0.15]);
+
+        form.fields[7].1 = String::from("300");
+        assert_eq!(form.apply(&sdf).err(), Some("green"));
+        form.fields[7].1 = String::from("20");
+
+        //Sizes stay positive, except for the parts a shape doesn't use
+        for text in &["0", "-1"] {
+            form.fields[3].1 = text.to_string();
+            assert_eq!(form.apply(&sdf).err(), Some("size x"));
+        }
+        let torus = SDF::new_torus([0.0; 3], [1.0, 0.5], [0, 0, 0], [0.0; 3]);
+        assert!(Form::new(0, &torus).apply(&torus).is_ok());
+        let plane = SDF::new_plane(-1.0, [0, 0, 0]);
+        assert!(Form::new(0, &plane).apply(&plane).is_ok());
+    }
+
+    #[test]
+    fn primitives() {
+        for (idx, name) in PRIMITIVES.iter().enumerate() {
+            let sdf = new_primitive(idx, [0.0, 0.0, 5.0]);
+            assert_eq!(sdf.sdf_type.name(), *name);
+            //Around a unit across, the torus has its hole in the middle
+            assert!(sdf.get_distance([0.0, 0.0, 5.0]) < 1.0);
+            assert!(sdf.get_distance([0.0, 0.0, 8.0]) > 1.0);
+        }
+    }
+}
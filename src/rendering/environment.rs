@@ -0,0 +1,217 @@
+extern crate vecmath as vmath;
+use vmath::{
+    Vector3,
+};
+
+use crate::rendering::raymarching::Radiance;
+
+fn clamp(x: f32, a: f32, b: f32) -> f32 {
+    if x < a { return a };
+    if x > b { return b };
+    x
+}
+
+fn mix(a: Vector3<f32>, b: Vector3<f32>, t: f32) -> Vector3<f32> {
+    vmath::vec3_add(vmath::vec3_scale(a, 1.0 - t), vmath::vec3_scale(b, t))
+}
+
+fn luminance(colour: Vector3<f32>) -> f32 {
+    0.2126 * colour[0] + 0.7152 * colour[1] + 0.0722 * colour[2]
+}
+
+#[derive(Copy, Clone)]
+pub enum Background {
+    Solid(Vector3<f32>),
+    //Vertical gradient, blended by the height of the ray direction
+    Gradient { horizon: Vector3<f32>, zenith: Vector3<f32> },
+}
+
+#[derive(Copy, Clone)]
+pub enum Fog {
+    None,
+    //Fog everywhere, thicker the further the ray travelled
+    Exponential { density: f32 },
+    //Fog that thins out with height, so it pools on the ground and leaves the sky clear
+    Height { density: f32, falloff: f32 },
+}
+
+#[derive(Copy, Clone)]
+pub struct Sun {
+    pub direction: Vector3<f32>, //Points towards the sun
+    pub colour: Vector3<f32>,
+    pub size: f32, //Angular radius of the disc, in degrees
+    pub visible: bool,
+}
+
+#[derive(Copy, Clone)]
+pub struct Environment {
+    pub background: Background,
+    pub sun: Sun,
+    pub fog: Fog,
+    //Draws the background with glyphs as well, instead of only colouring the cell background
+    pub background_glyphs: bool,
+}
+
+impl Default for Environment {
+    fn default() -> Environment {
+        Environment::new()
+    }
+}
+
+impl Environment {
+    pub fn new() -> Environment {
+        Environment {
+            background: Background::Gradient { horizon: [0.55, 0.6, 0.7], zenith: [0.1, 0.2, 0.45] },
+            sun: Sun {
+                direction: vmath::vec3_normalized([-0.25, 0.5, -0.5]),
+                colour: [1.0, 0.9, 0.6],
+                size: 4.0,
+                visible: true,
+            },
+            fog: Fog::Exponential { density: 0.03 },
+            background_glyphs: false,
+        }
+    }
+
+    pub fn background_colour(&self, direction: Vector3<f32>) -> Vector3<f32> {
+        let mut colour = match self.background {
+            Background::Solid(colour) => colour,
+            Background::Gradient { horizon, zenith } => mix(horizon, zenith, clamp(direction[1], 0.0, 1.0)),
+        };
+
+        if self.sun.visible {
+            let cos_angle = vmath::vec3_dot(direction, self.sun.direction);
+            let cos_size = (self.sun.size / 180.0 * std::f32::consts::PI).cos();
+            if cos_angle >= cos_size {
+                colour = self.sun.colour;
+            } else {
+                //Soft glow around the disc
+                let glow = clamp(cos_angle, 0.0, 1.0).powi(64) * 0.5;
+                colour = mix(colour, self.sun.colour, glow);
+            }
+        }
+
+        colour
+    }
+
+    //What a ray sees when it doesn't hit anything
+    pub fn background(&self, direction: Vector3<f32>) -> Radiance {
+        let colour = self.background_colour(direction);
+        if self.background_glyphs {
+            return Radiance::new(colour, luminance(colour));
+        }
+        Radiance {
+            colour,
+            intensity: 0.0,
+            coverage: 0.0,
+        }
+    }
+
+    //How much of the background shows through after travelling distance along the ray, 0..1
+    pub fn fog_amount(&self, origin: Vector3<f32>, direction: Vector3<f32>, distance: f32) -> f32 {
+        match self.fog {
+            Fog::None => 0.0,
+            Fog::Exponential { density } => 1.0 - (-density * distance).exp(),
+            Fog::Height { density, falloff } => {
+                //Integral of density * exp(-falloff * height) along the ray
+                let dy = direction[1] * falloff;
+                let base = density * (-origin[1] * falloff).exp();
+                let amount = if dy.abs() < 0.0001 {
+                    base * distance
+                } else {
+                    base * (1.0 - (-distance * dy).exp()) / dy
+                };
+                clamp(1.0 - (-amount).exp(), 0.0, 1.0)
+            }
+        }
+    }
+
+    //Blends a surface towards the background. Near the far limit everything fades out completely,
+    //so surfaces like the ground plane don't end in a hard edge.
+    pub fn apply_fog(&self, radiance: Radiance, origin: Vector3<f32>, direction: Vector3<f32>, distance: f32, far: f32) -> Radiance {
+        let edge = clamp((distance - far * 0.6) / (far * 0.4), 0.0, 1.0);
+        let edge = edge * edge * (3.0 - 2.0 * edge);
+        let amount = clamp(self.fog_amount(origin, direction, distance).max(edge), 0.0, 1.0);
+        if amount <= 0.0 {
+            return radiance;
+        }
+        let background = self.background(direction);
+        let mut fogged = radiance.mix(background, amount);
+        fogged.coverage = radiance.coverage * (1.0 - amount) + background.coverage * amount;
+        fogged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossterm::style::Color;
+
+    //Density integrated along the ray in small steps, to check the closed form against
+    fn integrate(density: f32, falloff: f32, origin: Vector3<f32>, direction: Vector3<f32>, distance: f32) -> f32 {
+        let steps = 10_000;
+        let dt = distance / steps as f32;
+        let amount: f32 = (0..steps).map(|i| {
+            let y = origin[1] + direction[1] * (i as f32 + 0.5) * dt;
+            density * (-falloff * y).exp() * dt
+        }).sum();
+        1.0 - (-amount).exp()
+    }
+
+    #[test]
+    fn height_fog() {
+        let mut environment = Environment::new();
+        environment.fog = Fog::Height { density: 0.2, falloff: 0.5 };
+        for direction in &[[0.0, 0.0, 1.0], vmath::vec3_normalized([0.0, 0.3, 1.0]), vmath::vec3_normalized([0.2, -0.4, 1.0])] {
+            let origin = [0.0, 1.0, 0.0];
+            let expected = integrate(0.2, 0.5, origin, *direction, 12.0);
+            let amount = environment.fog_amount(origin, *direction, 12.0);
+            assert!((amount - expected).abs() < 1e-3, "{} instead of {} along {:?}", amount, expected, direction);
+        }
+
+        //Looking up, the fog thins out and never gets thicker than up to infinity
+        let up = environment.fog_amount([0.0; 3], [0.0, 1.0, 0.0], 1000.0);
+        assert!((up - (1.0 - (-0.2f32 / 0.5).exp())).abs() < 1e-4);
+
+        environment.fog = Fog::Exponential { density: 0.1 };
+        assert!((environment.fog_amount([0.0; 3], [0.0, 0.0, 1.0], 10.0) - (1.0 - (-1.0f32).exp())).abs() < 1e-6);
+    }
+
+    #[test]
+    fn far_fade() {
+        let mut environment = Environment::new();
+        environment.fog = Fog::None;
+        environment.background = Background::Solid([0.0, 0.0, 1.0]);
+        environment.sun.visible = false;
+        let red = Radiance::new([1.0, 0.0, 0.0], 1.0);
+        let at = |distance: f32| environment.apply_fog(red, [0.0; 3], [0.0, 0.0, 1.0], distance, 100.0);
+
+        //Untouched up to 60% of far, gone at far, and half way between on the smoothstep
+        assert_eq!(at(59.0).colour, red.colour);
+        assert_eq!(at(59.0).coverage, 1.0);
+        assert!(vmath::vec3_len(vmath::vec3_sub(at(100.0).colour, [0.0, 0.0, 1.0])) < 1e-6);
+        assert_eq!(at(100.0).coverage, 0.0);
+        let half = at(80.0);
+        assert!((half.colour[0] - 0.5).abs() < 1e-5 && (half.coverage - 0.5).abs() < 1e-5);
+        assert!(at(70.0).colour[0] > 0.5 && at(90.0).colour[0] < 0.5);
+    }
+
+    #[test]
+    fn background_colours() {
+        //Covered cells keep the terminal background, misses show all of the colour
+        let mut radiance = Radiance::new([1.0, 0.5, 0.0], 1.0);
+        assert_eq!(radiance.to_bg(), Color::Reset);
+        radiance.coverage = 0.0;
+        assert_eq!(radiance.to_bg(), Color::Rgb { r: 255, g: 127, b: 0 });
+        radiance.coverage = 0.5;
+        assert_eq!(radiance.to_bg(), Color::Rgb { r: 127, g: 63, b: 0 });
+        radiance.colour = [2.0, -1.0, 0.5];
+        assert_eq!(radiance.to_bg(), Color::Rgb { r: 255, g: 0, b: 63 });
+
+        let mut environment = Environment::new();
+        environment.sun.visible = false;
+        let sky = environment.background([0.0, 1.0, 0.0]);
+        assert_eq!((sky.coverage, sky.intensity), (0.0, 0.0));
+        assert_eq!(sky.colour, [0.1, 0.2, 0.45]);
+    }
+}
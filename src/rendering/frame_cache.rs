@@ -0,0 +1,453 @@
+extern crate vecmath as vmath;
+use vmath::{
+    Vector3,
+};
+
+use crate::engine::{
+    camera::Camera,
+    scene::{Scene, Region},
+};
+use super::raymarching::{Radiance, Sample};
+use super::supersampling::Supersampling;
+
+//Camera moves bigger than this start over instead of reprojecting the previous frame
+const MAX_REPROJECT_DISTANCE: f32 = 0.5;
+const MAX_REPROJECT_YAW: f32 = 5.0;
+//How far the depth stored at a reprojected pixel may be from the depth it should have, relative
+//to that depth. Anything further was something else in the previous frame.
+const DEPTH_TOLERANCE: f32 = 0.05;
+//Surfaces this close to a changed object are redrawn too, for ambient occlusion and soft shadow edges
+const CHANGE_MARGIN: f32 = 0.5;
+
+//Low discrepancy sequence in 0..1, so sample positions spread out evenly whatever the count
+fn halton(mut index: u32, base: u32) -> f32 {
+    let mut f = 1.0;
+    let mut result = 0.0;
+    while index > 0 {
+        f /= base as f32;
+        result += f * (index % base) as f32;
+        index /= base;
+    }
+    result
+}
+
+//Slab test, for rays starting inside the box too
+fn ray_hits_box(origin: Vector3<f32>, direction: Vector3<f32>, min: Vector3<f32>, max: Vector3<f32>) -> bool {
+    let mut near = 0.0f32;
+    let mut far = f32::INFINITY;
+    for i in 0..3 {
+        let inverse = 1.0 / direction[i];
+        let (a, b) = ((min[i] - origin[i]) * inverse, (max[i] - origin[i]) * inverse);
+        near = near.max(a.min(b));
+        far = far.min(a.max(b));
+    }
+    near <= far
+}
+
+//What begin_frame learned about the changes to the scene, to find the pixels they could have changed
+#[derive(Clone, Default)]
+struct Changes {
+    regions: Vec<Region>,
+    sun: Vector3<f32>,
+    //Per object, whether it shows other objects in reflections or through it
+    bounces: Vec<bool>,
+}
+
+impl Changes {
+    //Whether the surface a sample hit may look different: it's close to a change, in the shadow of one,
+    //or shows what's around it. Nothing else changes, since rays that missed everything only see the sky.
+    fn affect(&self, sample: &Sample) -> bool {
+        let object = match sample.hit.object {
+            Some(object) if !self.regions.is_empty() => object,
+            _ => return false,
+        };
+        if self.bounces.get(object).copied().unwrap_or(true) {
+            return true;
+        }
+        self.regions.iter().any(|&(min, max)| {
+            let min = vmath::vec3_sub(min, [CHANGE_MARGIN; 3]);
+            let max = vmath::vec3_add(max, [CHANGE_MARGIN; 3]);
+            ray_hits_box(sample.hit.position, self.sun, min, max)
+        })
+    }
+}
+
+//Where the samples of a frame are taken
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Jitter {
+    pub offset: (f32, f32), //Within the cell, -0.5..0.5
+    pub ao: f32, //For Scene::ao_jitter
+}
+
+impl Jitter {
+    pub fn none() -> Jitter {
+        Jitter {
+            offset: (0.0, 0.0),
+            ao: 0.0,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Mode {
+    Reset,
+    Accumulate,
+    Reproject(Camera),
+}
+
+//Sum of the samples of a pixel
+#[derive(Copy, Clone)]
+struct Accumulated {
+    colour: Vector3<f32>,
+    intensity: f32,
+    coverage: f32,
+    hits: f32,
+    edge: [f32; 2],
+    weight: f32,
+    latest: Sample, //Hit and steps only come from the latest sample
+}
+
+impl Accumulated {
+    fn new(sample: &Sample) -> Accumulated {
+        Accumulated {
+            colour: sample.radiance.colour,
+            intensity: sample.radiance.intensity,
+            coverage: sample.radiance.coverage,
+            hits: sample.hits,
+            edge: sample.edge,
+            weight: 1.0,
+            latest: *sample,
+        }
+    }
+
+    fn add(&mut self, other: &Accumulated) {
+        self.colour = vmath::vec3_add(self.colour, other.colour);
+        self.intensity += other.intensity;
+        self.coverage += other.coverage;
+        self.hits += other.hits;
+        self.edge = [self.edge[0] + other.edge[0], self.edge[1] + other.edge[1]];
+        self.weight += other.weight;
+        self.latest = other.latest;
+    }
+
+    //Same average, but counting as at most max_weight samples
+    fn limited(&self, max_weight: f32) -> Accumulated {
+        let s = max_weight.min(self.weight) / self.weight;
+        Accumulated {
+            colour: vmath::vec3_scale(self.colour, s),
+            intensity: self.intensity * s,
+            coverage: self.coverage * s,
+            hits: self.hits * s,
+            edge: [self.edge[0] * s, self.edge[1] * s],
+            weight: self.weight * s,
+            latest: self.latest,
+        }
+    }
+
+    fn average(&self) -> Sample {
+        let w = 1.0 / self.weight;
+        Sample {
+            radiance: Radiance {
+                colour: vmath::vec3_scale(self.colour, w),
+                intensity: self.intensity * w,
+                coverage: self.coverage * w,
+            },
+            hits: self.hits * w,
+            edge: [self.edge[0] * w, self.edge[1] * w],
+            ..self.latest
+        }
+    }
+}
+
+//Keeps the rendered pixels between frames. While the camera and scene stay the same every frame
+//adds a jittered sample to every pixel, which smooths edges and ambient occlusion, until there are
+//enough and rendering stops. Small camera moves keep what's still visible, by looking up where
+//every pixel was in the previous frame using its depth. Objects that move only start over the
+//pixels around them.
+pub struct FrameCache {
+    pub enabled: bool,
+    pub max_samples: u32,
+    //Reprojected pixels count as at most this many samples, old ones would smear moving edges
+    pub max_history: f32,
+
+    mode: Mode,
+    camera: Option<Camera>,
+    revision: u64,
+    resolution: (u16, u16),
+    //Samples taken with different patterns don't mix
+    pattern: Supersampling,
+    changes: Changes, //Since the last frame
+    frames: u32, //Samples every pixel has at least, counting from the last reset or reprojection
+    sequence: u32, //Where the jitter is in its sequence
+    pixels: Vec<Accumulated>,
+}
+
+impl Default for FrameCache {
+    fn default() -> FrameCache {
+        FrameCache::new()
+    }
+}
+
+impl FrameCache {
+    pub fn new() -> FrameCache {
+        FrameCache {
+            enabled: true,
+            max_samples: 16,
+            max_history: 4.0,
+
+            mode: Mode::Reset,
+            camera: None,
+            revision: 0,
+            resolution: (0, 0),
+            pattern: Supersampling::None,
+            changes: Changes::default(),
+            frames: 0,
+            sequence: 0,
+            pixels: Vec::new(),
+        }
+    }
+
+    //Samples every pixel has, or 0 if the last frame was a reprojection
+    pub fn samples(&self) -> u32 {
+        if let Mode::Reproject(_) = self.mode { 0 } else { self.frames }
+    }
+
+    //Call before rendering a frame of the scene, with the rays per cell it's rendered with.
+    //Returns where to take this frame's samples, or None if the pixels are done and
+    //rendering can be skipped.
+    pub fn begin_frame(&mut self, camera: Camera, scene: &Scene, resolution: (u16, u16), pattern: Supersampling) -> Option<Jitter> {
+        let same_pixels = self.enabled && resolution == self.resolution && pattern == self.pattern;
+        let regions = if same_pixels { scene.changes_since(self.revision) } else { None };
+        let unchanged = regions.is_some();
+        let previous = self.camera;
+        self.camera = Some(camera);
+        self.revision = scene.revision();
+        self.resolution = resolution;
+        self.pattern = pattern;
+        self.changes = Changes {
+            regions: regions.unwrap_or_default(),
+            sun: scene.environment.sun.direction,
+            bounces: scene.distance_fields.iter().map(|sdf| sdf.material.reflectivity > 0.0 || sdf.material.transparency > 0.0).collect(),
+        };
+
+        self.mode = match previous {
+            Some(previous) if unchanged && previous == camera => Mode::Accumulate,
+            Some(previous) if unchanged && vmath::vec3_len(vmath::vec3_sub(previous.eye, camera.eye)) <= MAX_REPROJECT_DISTANCE
+                && (previous.yaw - camera.yaw).abs() <= MAX_REPROJECT_YAW => Mode::Reproject(previous),
+            _ => Mode::Reset,
+        };
+
+        match self.mode {
+            Mode::Accumulate if self.frames >= self.max_samples && self.changes.regions.is_empty() => None,
+            Mode::Accumulate => Some(Jitter {
+                offset: (halton(self.sequence, 2) - 0.5, halton(self.sequence, 3) - 0.5),
+                ao: halton(self.sequence, 5),
+            }),
+            //The first sample of a pixel goes through its middle, like without the cache
+            _ => Some(Jitter::none()),
+        }
+    }
+
+    //Adds the samples of the frame begin_frame was called for, ordered row by row
+    pub fn add_samples(&mut self, samples: &[Sample]) {
+        match self.mode {
+            Mode::Reset => {
+                self.pixels = samples.iter().map(Accumulated::new).collect();
+                self.frames = 1;
+            },
+            Mode::Accumulate => {
+                let mut started_over = false;
+                for (pixel, sample) in self.pixels.iter_mut().zip(samples.iter()) {
+                    if self.changes.affect(&pixel.latest) || self.changes.affect(sample) {
+                        *pixel = Accumulated::new(sample);
+                        started_over = true;
+                    } else if pixel.weight < self.max_samples as f32 {
+                        pixel.add(&Accumulated::new(sample));
+                    }
+                }
+                self.frames = if started_over { 1 } else { self.frames + 1 };
+            },
+            Mode::Reproject(previous) => {
+                let pixels = samples.iter().map(|sample| {
+                    let new = Accumulated::new(sample);
+                    match self.reprojected(previous, sample) {
+                        Some(old) => {
+                            let mut pixel = old.limited(self.max_history);
+                            pixel.add(&new);
+                            pixel
+                        },
+                        None => new,
+                    }
+                }).collect();
+                self.pixels = pixels;
+                self.frames = 1;
+            },
+        }
+        self.sequence = if let Mode::Accumulate = self.mode { self.sequence + 1 } else { 1 };
+    }
+
+    //The pixel of the previous frame that showed the same surface as this sample
+    fn reprojected(&self, previous: Camera, sample: &Sample) -> Option<&Accumulated> {
+        sample.hit.object?;
+        if self.changes.affect(sample) {
+            return None;
+        }
+        let (x, y) = previous.project(self.resolution, sample.hit.position)?;
+        let (x, y) = (x.round(), y.round());
+        if x < 0.0 || y < 0.0 || x >= self.resolution.0 as f32 || y >= self.resolution.1 as f32 {
+            return None;
+        }
+
+        let old = self.pixels.get(y as usize * self.resolution.0 as usize + x as usize)?;
+        let expected = vmath::vec3_len(vmath::vec3_sub(sample.hit.position, previous.eye));
+        if (old.latest.depth() - expected).abs() > DEPTH_TOLERANCE * expected || self.changes.affect(&old.latest) {
+            return None;
+        }
+        Some(old)
+    }
+
+    //The average of every pixel, row by row, with the hit of its latest sample
+    pub fn resolve(&self) -> Vec<Sample> {
+        self.pixels.iter().map(Accumulated::average).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{scene::Scene, distance_field::SDF};
+
+    const SIZE: (u16, u16) = (24, 12);
+
+    fn scene() -> Scene {
+        let mut scene = Scene::new();
+        scene.push_sdf(SDF::new_plane(-1.0, [255, 255, 255]));
+        scene.push_sdf(SDF::new_sphere([0.0, 0.0, 4.0], 1.0, [255, 0, 0]));
+        scene
+    }
+
+    fn render(scene: &Scene, jitter: Jitter) -> Vec<Sample> {
+        let mut scene = scene.clone();
+        scene.ao_jitter = jitter.ao;
+        let rays: Vec<_> = (0..SIZE.0 * SIZE.1).map(|i| {
+            scene.generate_ray_at(SIZE, (i % SIZE.0) as f32 + jitter.offset.0, (i / SIZE.0) as f32 + jitter.offset.1)
+        }).collect();
+        scene.march_rays(&rays)
+    }
+
+    #[test]
+    fn project_inverts_generate_ray() {
+        let mut scene = scene();
+        scene.camera = Camera::new([0.5, 0.2, -1.0], 30.0, 0.0);
+        for &(x, y) in &[(3.0, 2.0), (12.25, 6.5), (20.0, 11.0)] {
+            let ray = scene.generate_ray_at(SIZE, x, y);
+            let p = vmath::vec3_add(ray.origin, vmath::vec3_scale(ray.direction, 5.0));
+            let (px, py) = scene.camera.project(SIZE, p).unwrap();
+            assert!((px - x).abs() < 1e-3 && (py - y).abs() < 1e-3, "{:?} for {:?}", (px, py), (x, y));
+        }
+        assert!(scene.camera.project(SIZE, [0.5, 0.2, -5.0]).is_none());
+    }
+
+    #[test]
+    fn accumulates_while_still() {
+        let scene = scene();
+        let mut cache = FrameCache::new();
+        cache.max_samples = 4;
+
+        let mut jitters = Vec::new();
+        while let Some(jitter) = cache.begin_frame(scene.camera, &scene, SIZE, Supersampling::None) {
+            jitters.push(jitter);
+            cache.add_samples(&render(&scene, jitter));
+        }
+        assert_eq!(jitters.len(), 4);
+        assert_eq!(jitters[0], Jitter::none());
+        assert!(jitters[1] != jitters[2]);
+        assert_eq!(cache.samples(), 4);
+
+        //Anything changing in the scene starts over
+        let mut changed = scene.clone();
+        changed.mark_changed();
+        assert_eq!(cache.begin_frame(changed.camera, &changed, SIZE, Supersampling::None), Some(Jitter::none()));
+        cache.add_samples(&render(&changed, Jitter::none()));
+        assert_eq!(cache.samples(), 1);
+
+        //And so does taking a different number of rays per cell
+        cache.begin_frame(changed.camera, &changed, SIZE, Supersampling::None);
+        cache.add_samples(&render(&changed, Jitter::none()));
+        assert_eq!(cache.samples(), 2);
+        assert_eq!(cache.begin_frame(changed.camera, &changed, SIZE, Supersampling::RotatedGrid), Some(Jitter::none()));
+        cache.add_samples(&render(&changed, Jitter::none()));
+        assert_eq!(cache.samples(), 1);
+    }
+
+    #[test]
+    fn reprojects_small_moves() {
+        let mut scene = scene();
+        let mut cache = FrameCache::new();
+        for _ in 0..3 {
+            let jitter = cache.begin_frame(scene.camera, &scene, SIZE, Supersampling::None).unwrap();
+            cache.add_samples(&render(&scene, jitter));
+        }
+
+        scene.camera.eye[0] += 0.1;
+        assert_eq!(cache.begin_frame(scene.camera, &scene, SIZE, Supersampling::None), Some(Jitter::none()));
+        let samples = render(&scene, Jitter::none());
+        cache.add_samples(&samples);
+
+        //The sphere in the middle was visible before too, so it keeps its history
+        let middle = (SIZE.1 / 2) as usize * SIZE.0 as usize + (SIZE.0 / 2) as usize;
+        assert!(cache.pixels[middle].weight > 1.0);
+        //The sky has no depth to reproject with
+        assert_eq!(cache.pixels[0].weight, 1.0);
+        assert!(samples[0].hit.object.is_none());
+
+        //Big moves start over
+        scene.camera.eye[2] += 5.0;
+        cache.begin_frame(scene.camera, &scene, SIZE, Supersampling::None);
+        cache.add_samples(&render(&scene, Jitter::none()));
+        assert!(cache.pixels.iter().all(|p| p.weight == 1.0));
+    }
+
+    #[test]
+    fn moving_objects_keep_the_rest() {
+        let mut scene = scene();
+        scene.environment.sun.direction = [0.0, 1.0, 0.0];
+        let small = scene.push_sdf(SDF::new_sphere([-2.5, 0.0, 4.0], 0.3, [0, 255, 0]));
+        let mut cache = FrameCache::new();
+        cache.max_samples = 4;
+        while let Some(jitter) = cache.begin_frame(scene.camera, &scene, SIZE, Supersampling::None) {
+            cache.add_samples(&render(&scene, jitter));
+        }
+
+        let pixel = |x: u16, y: u16| (y as usize) * SIZE.0 as usize + x as usize;
+        let left = (0..SIZE.0).find(|&x| render(&scene, Jitter::none())[pixel(x, SIZE.1 / 2)].hit.object == Some(small)).unwrap();
+        scene.update_position(small, [-2.5, 0.1, 4.0]);
+
+        //Only the small sphere and what's around it start over, and they get more samples after
+        let jitter = cache.begin_frame(scene.camera, &scene, SIZE, Supersampling::None).unwrap();
+        assert!(jitter != Jitter::none());
+        cache.add_samples(&render(&scene, jitter));
+        assert_eq!(cache.pixels[pixel(left, SIZE.1 / 2)].weight, 1.0);
+        assert_eq!(cache.pixels[pixel(SIZE.0 / 2, SIZE.1 / 2)].weight, 4.0);
+        assert_eq!(cache.pixels[pixel(SIZE.0 - 1, SIZE.1 - 1)].weight, 4.0);
+        assert_eq!(cache.samples(), 1);
+        let mut frames = 0;
+        while let Some(jitter) = cache.begin_frame(scene.camera, &scene, SIZE, Supersampling::None) {
+            cache.add_samples(&render(&scene, jitter));
+            frames += 1;
+        }
+        assert_eq!((frames, cache.samples()), (3, 4));
+        assert!(cache.pixels.iter().all(|p| p.weight == 4.0));
+
+        //Glossy objects show everything around them
+        let mut glossy = SDF::new_sphere([0.0, 0.0, 4.0], 1.0, [255, 0, 0]);
+        glossy.material.reflectivity = 0.5;
+        scene.replace_sdf(1, glossy);
+        cache.begin_frame(scene.camera, &scene, SIZE, Supersampling::None);
+        cache.add_samples(&render(&scene, Jitter::none()));
+        scene.update_position(small, [-2.5, 0.0, 4.0]);
+        cache.begin_frame(scene.camera, &scene, SIZE, Supersampling::None);
+        cache.add_samples(&render(&scene, Jitter::none()));
+        assert_eq!(cache.pixels[pixel(SIZE.0 / 2, SIZE.1 / 2)].weight, 1.0);
+    }
+}
@@ -0,0 +1,138 @@
+use std::time::{Duration, Instant};
+
+use crate::engine::camera::Camera;
+
+fn clamp(x: f32, a: f32, b: f32) -> f32 {
+    if x < a { return a };
+    if x > b { return b };
+    x
+}
+
+//Keeps rendering at a target frame rate while the camera moves. Frames that take too long are
+//rendered at a lower resolution and stretched over the screen, and once the resolution can't go
+//lower rays get fewer steps. When the camera stops, the next frames go back to full quality.
+pub struct FramePacer {
+    pub enabled: bool,
+    pub target_fps: f32,
+    //Fraction of the screen resolution along each axis, and the lowest it may go
+    pub scale: f32,
+    pub min_scale: f32,
+    //Fraction of Scene::max_steps rays get, and the lowest it may go
+    pub step_scale: f32,
+    pub min_step_scale: f32,
+    //How long the camera has to stand still before quality is restored, key repeat has gaps
+    pub restore_after: Duration,
+
+    last_camera: Option<Camera>,
+    last_moved: Instant,
+}
+
+impl FramePacer {
+    pub fn new(target_fps: f32) -> FramePacer {
+        FramePacer {
+            enabled: true,
+            target_fps,
+            scale: 1.0,
+            min_scale: 0.25,
+            step_scale: 1.0,
+            min_step_scale: 0.5,
+            restore_after: Duration::from_millis(300),
+
+            last_camera: None,
+            last_moved: Instant::now(),
+        }
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.last_moved.elapsed() < self.restore_after
+    }
+
+    //Call before rendering a frame. Goes back to full quality if the camera stood still long enough
+    pub fn begin_frame(&mut self, camera: Camera) {
+        if self.last_camera != Some(camera) {
+            self.last_moved = Instant::now();
+            self.last_camera = Some(camera);
+        }
+        if !self.enabled || !self.is_moving() {
+            self.scale = 1.0;
+            self.step_scale = 1.0;
+        }
+    }
+
+    //Call after rendering a frame with how long it took. Only the time spent tracing rays counts,
+    //drawing to the terminal doesn't get cheaper at lower resolutions.
+    pub fn end_frame(&mut self, render_time: Duration) {
+        if !self.enabled || !self.is_moving() {
+            return;
+        }
+
+        let budget = 1.0 / self.target_fps;
+        let time = render_time.as_secs_f32().max(1e-6);
+        //Time goes up with the number of pixels, so with the square of the scale.
+        //Aim a bit under the budget and only move halfway, so it doesn't overshoot back and forth.
+        let wanted = self.scale * (0.9 * budget / time).sqrt();
+        let scale = self.scale + (wanted - self.scale) * 0.5;
+
+        if scale < self.min_scale {
+            self.step_scale = clamp(self.step_scale * scale / self.min_scale, self.min_step_scale, 1.0);
+        } else {
+            self.step_scale = clamp(self.step_scale * 1.25, self.min_step_scale, 1.0);
+        }
+        self.scale = clamp(scale, self.min_scale, 1.0);
+    }
+
+    //Size to render at for a screen of term_size
+    pub fn resolution(&self, term_size: (u16, u16)) -> (u16, u16) {
+        (
+            ((term_size.0 as f32 * self.scale).round() as u16).clamp(1, term_size.0.max(1)),
+            ((term_size.1 as f32 * self.scale).round() as u16).clamp(1, term_size.1.max(1)),
+        )
+    }
+
+    pub fn max_steps(&self, max_steps: usize) -> usize {
+        ((max_steps as f32 * self.step_scale) as usize).max(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moved(pacer: &mut FramePacer, i: usize) {
+        pacer.begin_frame(Camera::new([i as f32, 0.0, 0.0], 0.0, 0.0));
+    }
+
+    #[test]
+    fn scales_down_while_moving() {
+        let mut pacer = FramePacer::new(20.0);
+        for i in 0..50 {
+            moved(&mut pacer, i);
+            //Pretend a full resolution frame takes 0.2s
+            let time = 0.2 * pacer.scale * pacer.scale * pacer.step_scale;
+            pacer.end_frame(Duration::from_secs_f32(time));
+        }
+        let time = 0.2 * pacer.scale * pacer.scale * pacer.step_scale;
+        assert!(time < 0.05 && time > 0.035, "{} at scale {}", time, pacer.scale);
+        assert_eq!(pacer.step_scale, 1.0);
+        assert_eq!(pacer.resolution((200, 60)), ((200.0 * pacer.scale).round() as u16, (60.0 * pacer.scale).round() as u16));
+
+        //Too slow even at the lowest resolution, so steps go down too
+        for i in 50..100 {
+            moved(&mut pacer, i);
+            pacer.end_frame(Duration::from_secs_f32(10.0));
+        }
+        assert_eq!((pacer.scale, pacer.step_scale), (pacer.min_scale, pacer.min_step_scale));
+        assert_eq!(pacer.max_steps(64), 32);
+    }
+
+    #[test]
+    fn restores_when_still() {
+        let mut pacer = FramePacer::new(30.0);
+        pacer.restore_after = Duration::from_secs(0);
+        moved(&mut pacer, 0);
+        pacer.scale = 0.5;
+        moved(&mut pacer, 0);
+        assert_eq!(pacer.scale, 1.0);
+        assert_eq!(pacer.resolution((80, 24)), (80, 24));
+    }
+}
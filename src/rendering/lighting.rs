@@ -3,26 +3,64 @@ use vmath::{
     Vector3,
 };
 
-use crossterm::style::Color;
-
 fn clamp(x: f32, a: f32, b: f32) -> f32 {
     if x < a { return a };
     if x > b { return b };
     return x;
 }
 
-pub fn lambert(albedo: Color, normal: Vector3<f32>, light_dir: Vector3<f32>) -> Color {
-    let mut colour = albedo;
-
-    match colour {
-        Color::Rgb{r, g, b} => {
-            let intensity = clamp(vmath::vec3_dot(normal, vmath::vec3_neg(light_dir)), 0.0, 1.0);
-            colour = Color::Rgb{r: (r as f32 * intensity) as u8, g: (g as f32 * intensity) as u8, b: (b as f32 * intensity) as u8};
-        },
-        _ => {
-            return albedo;
-        }
+pub fn reflect(direction: Vector3<f32>, normal: Vector3<f32>) -> Vector3<f32> {
+    let d = 2.0 * vmath::vec3_dot(direction, normal);
+    vmath::vec3_sub(direction, vmath::vec3_scale(normal, d))
+}
+
+//Snell's law. eta is the ratio of the indices of refraction (from / to).
+//Returns None on total internal reflection.
+pub fn refract(direction: Vector3<f32>, normal: Vector3<f32>, eta: f32) -> Option<Vector3<f32>> {
+    let cos_i = -vmath::vec3_dot(direction, normal);
+    let k = 1.0 - eta * eta * (1.0 - cos_i * cos_i);
+    if k < 0.0 {
+        return None;
     }
+    Some(vmath::vec3_add(vmath::vec3_scale(direction, eta), vmath::vec3_scale(normal, eta * cos_i - k.sqrt())))
+}
+
+//Schlick's approximation of the fresnel term, the fraction of light that gets reflected
+pub fn schlick(cos_theta: f32, ior_from: f32, ior_to: f32) -> f32 {
+    let r0 = (ior_from - ior_to) / (ior_from + ior_to);
+    let r0 = r0 * r0;
+    r0 + (1.0 - r0) * (1.0 - clamp(cos_theta, 0.0, 1.0)).powi(5)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    return colour;
+    fn close(a: Vector3<f32>, b: Vector3<f32>) -> bool {
+        vmath::vec3_len(vmath::vec3_sub(a, b)) < 1e-5
+    }
+
+    #[test]
+    fn reflect_and_refract() {
+        let down = vmath::vec3_normalized([1.0, -1.0, 0.0]);
+        assert!(close(reflect(down, [0.0, 1.0, 0.0]), vmath::vec3_normalized([1.0, 1.0, 0.0])));
+
+        //Straight through at normal incidence, whatever the indices
+        assert!(close(refract([0.0, -1.0, 0.0], [0.0, 1.0, 0.0], 1.0 / 1.5).unwrap(), [0.0, -1.0, 0.0]));
+        //Snell's law, sin of the angle to the normal scales by eta
+        let refracted = refract(down, [0.0, 1.0, 0.0], 1.0 / 1.5).unwrap();
+        assert!((vmath::vec3_len(refracted) - 1.0).abs() < 1e-5);
+        assert!((refracted[0] - down[0] / 1.5).abs() < 1e-5);
+        //Leaving glass at 45 degrees is past the critical angle
+        assert!(refract(down, [0.0, 1.0, 0.0], 1.5).is_none());
+    }
+
+    #[test]
+    fn fresnel() {
+        //4% for glass head on, everything at grazing angles, nothing between equal indices
+        assert!((schlick(1.0, 1.0, 1.5) - 0.04).abs() < 1e-5);
+        assert!((schlick(0.0, 1.0, 1.5) - 1.0).abs() < 1e-5);
+        assert!(schlick(0.5, 1.0, 1.5) > schlick(0.9, 1.0, 1.5));
+        assert_eq!(schlick(1.0, 1.33, 1.33), 0.0);
+    }
 }
@@ -3,3 +3,12 @@ pub mod raymarching;
 pub mod lighting;
 pub mod text;
 pub mod debug_menu;
+pub mod environment;
+pub mod render_pool;
+pub mod frame_pacer;
+pub mod frame_cache;
+pub mod supersampling;
+pub mod outline;
+pub mod debug_view;
+pub mod profiler;
+pub mod editor;
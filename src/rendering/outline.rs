@@ -0,0 +1,206 @@
+extern crate vecmath as vmath;
+
+use crossterm::style::Color;
+
+use super::raymarching::Sample;
+use super::supersampling::{self, ASCII_LINES, BOX_LINES};
+
+const NEIGHBOURS: [(i32, i32); 8] = [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)];
+
+//Draws lines along silhouettes, where one object or part of one is in front of something else,
+//and along creases, where the normal turns sharply, using the depth, normal and object of every
+//cell. Lines follow the direction of the edge.
+pub struct Outline {
+    pub enabled: bool,
+    pub colour: Color,
+    //Neighbours on the same object further than this from the plane of a cell, relative to its
+    //depth, are behind it
+    pub depth_threshold: f32,
+    //Neighbours with normals at a smaller cosine than this are across a crease
+    pub crease_cos: f32,
+    //Box drawing characters instead of ASCII
+    pub box_drawing: bool,
+    //Around the selected object, drawn even with outlines disabled
+    pub selection_colour: Color,
+}
+
+impl Default for Outline {
+    fn default() -> Outline {
+        Outline::new()
+    }
+}
+
+impl Outline {
+    pub fn new() -> Outline {
+        Outline {
+            enabled: true,
+            colour: Color::White,
+            depth_threshold: 0.1,
+            crease_cos: 0.766, //40 degrees
+            box_drawing: false,
+            selection_colour: Color::Yellow,
+        }
+    }
+
+    //Whether there's an edge between cell and neighbour that cell should draw. Silhouettes are
+    //drawn by the cell in front, creases only by the first of the two cells in row order, so
+    //lines stay one cell wide.
+    fn is_edge(&self, cell: &Sample, neighbour: &Sample, later: bool) -> bool {
+        let (d, dn) = (cell.depth(), neighbour.depth());
+        if cell.hit.object.is_none() {
+            return false;
+        }
+        if neighbour.hit.object != cell.hit.object {
+            return d < dn;
+        }
+
+        let offset = vmath::vec3_dot(vmath::vec3_sub(neighbour.hit.position, cell.hit.position), cell.normal);
+        if offset.abs() > self.depth_threshold * d {
+            return d < dn;
+        }
+        later && vmath::vec3_dot(cell.normal, neighbour.normal) < self.crease_cos
+    }
+
+    fn glyph(&self, x: f32, y: f32) -> char {
+        supersampling::line_glyph(x, y, if self.box_drawing { &BOX_LINES } else { &ASCII_LINES })
+    }
+
+    //Overrides the cells on edges, both row by row at resolution. Cells of the selected object
+    //next to anything else get a line in the selection colour.
+    pub fn apply(&self, samples: &[Sample], resolution: (u16, u16), selected: Option<usize>, cells: &mut [(char, Color, Color)]) {
+        if !self.enabled && selected.is_none() {
+            return;
+        }
+
+        let (w, h) = (resolution.0 as i32, resolution.1 as i32);
+        for y in 0..h {
+            for x in 0..w {
+                let i = (y * w + x) as usize;
+                let cell = &samples[i];
+                let in_selection = selected.is_some() && cell.hit.object == selected;
+
+                //Sum of the directions to the edges, and the same as doubled angles, so opposite
+                //sides add up instead of cancelling out for lines only a cell thick. Glyphs go
+                //corner to corner of a cell, so this is in cells rather than screen space.
+                let mut direction = [0.0, 0.0];
+                let mut doubled = [0.0, 0.0];
+                let mut found = false;
+                let mut selection_edge = false;
+                for &(dx, dy) in NEIGHBOURS.iter() {
+                    let (nx, ny) = (x + dx, y + dy);
+                    if nx < 0 || ny < 0 || nx >= w || ny >= h {
+                        continue;
+                    }
+                    let neighbour = &samples[(ny * w + nx) as usize];
+                    let later = dy > 0 || (dy == 0 && dx > 0);
+                    let selection = in_selection && neighbour.hit.object != selected;
+                    selection_edge |= selection;
+                    if selection || (self.enabled && self.is_edge(cell, neighbour, later)) {
+                        let v = vmath::vec2_normalized([dx as f32, dy as f32]);
+                        direction = vmath::vec2_add(direction, v);
+                        doubled = vmath::vec2_add(doubled, [v[0] * v[0] - v[1] * v[1], 2.0 * v[0] * v[1]]);
+                        found = true;
+                    }
+                }
+                if !found {
+                    continue;
+                }
+
+                let glyph = if vmath::vec2_len(direction) > 1e-3 {
+                    self.glyph(direction[0], direction[1])
+                } else if vmath::vec2_len(doubled) > 1e-3 {
+                    let angle = doubled[1].atan2(doubled[0]) * 0.5;
+                    self.glyph(angle.cos(), angle.sin())
+                } else {
+                    //Edges all around, a single cell
+                    if self.box_drawing { '┼' } else { '+' }
+                };
+                cells[i].0 = glyph;
+                cells[i].1 = if selection_edge { self.selection_colour } else { self.colour };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rendering::raymarching::{Hit, Radiance};
+    use crate::engine::{scene::Scene, distance_field::SDF};
+
+    const SIZE: (u16, u16) = (32, 16);
+
+    fn blank(samples: &[Sample]) -> Vec<(char, Color, Color)> {
+        samples.iter().map(|_| (' ', Color::Reset, Color::Reset)).collect()
+    }
+
+    //Facing the camera at distance, object -1 for a miss
+    fn flat(object: i32, distance: f32) -> Sample {
+        let hit = if object >= 0 { Hit::new([0.0, 0.0, distance], distance, object, 1, 0.01) } else { Hit::miss(64.0, 1) };
+        Sample::new(Radiance::miss(), hit, if object >= 0 { [0.0, 0.0, -1.0] } else { [0.0; 3] }, 1)
+    }
+
+    #[test]
+    fn directions() {
+        //Left half hit, right half missed: the line is on the hit side, running down the screen
+        let samples: Vec<_> = (0..36).map(|i| flat(if i % 6 < 3 { 0 } else { -1 }, 2.0)).collect();
+        let mut cells = blank(&samples);
+        Outline::new().apply(&samples, (6, 6), None, &mut cells);
+        for y in 0..6 {
+            let row: String = cells[y * 6..y * 6 + 6].iter().map(|c| c.0).collect();
+            assert_eq!(row, "  |   ");
+        }
+
+        //Top half in front of the bottom half
+        let samples: Vec<_> = (0..36).map(|i| flat(i / 18, if i < 18 { 1.0 } else { 2.0 })).collect();
+        let mut cells = blank(&samples);
+        let mut outline = Outline::new();
+        outline.box_drawing = true;
+        outline.apply(&samples, (6, 6), None, &mut cells);
+        let row: String = cells[12..18].iter().map(|c| c.0).collect();
+        assert_eq!(row, "──────");
+        assert!(cells[18..].iter().all(|c| c.0 == ' '));
+        assert_eq!(cells[12].1, Color::White);
+    }
+
+    #[test]
+    fn outlines_scene() {
+        let mut scene = Scene::new();
+        scene.push_sdf(SDF::new_plane(-1.0, [255, 255, 255]));
+        scene.push_sdf(SDF::new_sphere([0.0, 0.0, 4.0], 1.0, [255, 0, 0]));
+        let rays: Vec<_> = (0..SIZE.0 * SIZE.1).map(|i| scene.generate_ray(SIZE, i % SIZE.0, i / SIZE.0)).collect();
+        let samples = scene.march_rays(&rays);
+        let mut cells = blank(&samples);
+        Outline::new().apply(&samples, SIZE, None, &mut cells);
+
+        //Just above the horizon the sphere gets a line on both sides and none in the middle
+        let w = SIZE.0 as usize;
+        let middle = (SIZE.1 as usize / 2 - 1) * w;
+        let row: Vec<_> = cells[middle..middle + w].iter().map(|c| c.0).collect();
+        let lines: Vec<_> = (0..w).filter(|&x| row[x] != ' ').collect();
+        assert!(lines.len() >= 2, "{:?}", row);
+        assert!(lines.iter().all(|&x| samples[middle + x].hit.object == Some(1)));
+        assert_eq!(row[w / 2], ' ');
+        //The flat floor far from the sphere has no edges
+        assert!(cells[(SIZE.1 as usize - 1) * w..].iter().take(4).all(|c| c.0 == ' '));
+    }
+
+    #[test]
+    fn selection() {
+        //A 2x2 object in the middle of another one at the same depth, with outlines off
+        let samples: Vec<_> = (0..36).map(|i| {
+            let (x, y) = (i % 6, i / 6);
+            flat(if (2..4).contains(&x) && (2..4).contains(&y) { 1 } else { 0 }, 2.0)
+        }).collect();
+        let mut outline = Outline::new();
+        outline.enabled = false;
+        let mut cells = blank(&samples);
+        outline.apply(&samples, (6, 6), None, &mut cells);
+        assert!(cells.iter().all(|c| c.0 == ' '));
+
+        outline.apply(&samples, (6, 6), Some(1), &mut cells);
+        let marked: Vec<_> = (0..36).filter(|&i| cells[i].0 != ' ').collect();
+        assert_eq!(marked, vec![14, 15, 20, 21]);
+        assert!(marked.iter().all(|&i| cells[i].1 == Color::Yellow));
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+//Block glyphs for the sparkline, from empty to full
+const BLOCKS: [char; 9] = [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+//Where the time of a frame went
+#[derive(Copy, Clone, Default, Debug, PartialEq)]
+pub struct FrameStats {
+    //Tracing rays, 0 for frames the frame cache skipped
+    pub render: Duration,
+    //Resolving the cache, picking glyphs and outlines
+    pub post_process: Duration,
+    //Writing the screen to the terminal
+    pub output: Duration,
+    pub bytes_written: usize,
+    //Fraction of the render pool's threads that were busy while rendering
+    pub utilisation: f32,
+}
+
+//Keeps the last frames to show how long they took
+pub struct Profiler {
+    pub history: usize,
+    frame_times: VecDeque<f32>, //In seconds, newest last
+    pub last: FrameStats,
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            history: 120,
+            frame_times: VecDeque::new(),
+            last: FrameStats::default(),
+        }
+    }
+
+    pub fn add_frame(&mut self, frame_time: f32, stats: FrameStats) {
+        self.frame_times.push_back(frame_time);
+        while self.frame_times.len() > self.history {
+            self.frame_times.pop_front();
+        }
+        self.last = stats;
+    }
+
+    //Min, average, max and 99th percentile of the frame times kept, all 0 before the first frame
+    pub fn summary(&self) -> (f32, f32, f32, f32) {
+        if self.frame_times.is_empty() {
+            return (0.0, 0.0, 0.0, 0.0);
+        }
+
+        let mut sorted: Vec<f32> = self.frame_times.iter().copied().collect();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        let n = sorted.len();
+        let p99 = sorted[((n as f32 * 0.99).ceil() as usize).clamp(1, n) - 1];
+        (sorted[0], sorted.iter().sum::<f32>() / n as f32, sorted[n - 1], p99)
+    }
+
+    //The newest frame times that fit in width, as block glyphs scaled to the slowest of them
+    pub fn sparkline(&self, width: usize) -> String {
+        let skip = self.frame_times.len().saturating_sub(width);
+        let times: Vec<f32> = self.frame_times.iter().skip(skip).copied().collect();
+        let max = times.iter().copied().fold(0.0, f32::max);
+        if max <= 0.0 {
+            return times.iter().map(|_| BLOCKS[0]).collect();
+        }
+        times.iter().map(|t| BLOCKS[((t / max * 8.0).ceil() as usize).min(8)]).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary() {
+        let mut profiler = Profiler::new();
+        assert_eq!(profiler.summary(), (0.0, 0.0, 0.0, 0.0));
+
+        profiler.history = 100;
+        for i in 0..150 {
+            profiler.add_frame(i as f32, FrameStats::default());
+        }
+        //Only the last 100 are kept
+        let (min, avg, max, p99) = profiler.summary();
+        assert_eq!((min, avg, max, p99), (50.0, 99.5, 149.0, 148.0));
+    }
+
+    #[test]
+    fn sparkline() {
+        let mut profiler = Profiler::new();
+        for &t in &[0.0, 0.5, 1.0, 0.25] {
+            profiler.add_frame(t, FrameStats::default());
+        }
+        assert_eq!(profiler.sparkline(10), " ▄█▂");
+        assert_eq!(profiler.sparkline(2), "█▂");
+    }
+}
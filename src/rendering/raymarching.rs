@@ -3,14 +3,20 @@ use vmath::{
     Vector3,
 };
 
-use crate::engine::{
-    scene::Scene,
-};
+use crossterm::style::Color;
+
+fn clamp(x: f32, a: f32, b: f32) -> f32 {
+    if x < a { return a };
+    if x > b { return b };
+    x
+}
 
 pub struct Ray {
     pub origin: Vector3<f32>,
     pub direction: Vector3<f32>,
     pub position: Vector3<f32>,
+    //Radius of the pixel the ray goes through, per unit of distance. 0 for an infinitely thin ray
+    pub footprint: f32,
 }
 
 impl Ray {
@@ -18,11 +24,154 @@ impl Ray {
         Ray {
             origin: origin,
             direction: direction,
-            position: [0.0; 3],
+            position: origin,
+            footprint: 0.0,
         }
     }
 
+    //The camera spreads 2 units of height over the rows at a distance of 2, so a cell is 1 / rows high
+    pub fn cell_footprint(term_size: (u16, u16)) -> f32 {
+        0.5 / term_size.1.max(1) as f32
+    }
+
     pub fn step(&mut self, distance: f32) {
         self.position = vmath::vec3_add(self.position, [self.direction[0] * distance, self.direction[1] * distance, self.direction[2] * distance]);
     }
 }
+
+//Where a ray ended up after sphere tracing
+#[derive(Copy, Clone)]
+pub struct Hit {
+    pub position: Vector3<f32>,
+    pub distance: f32, //Along the ray
+    pub object: Option<usize>, //None if the ray missed
+    pub steps: usize,
+    pub epsilon: f32, //Hit threshold at the hit point
+}
+
+impl Hit {
+    pub fn new(position: Vector3<f32>, distance: f32, object: i32, steps: usize, epsilon: f32) -> Hit {
+        Hit {
+            position,
+            distance,
+            object: if object >= 0 { Some(object as usize) } else { None },
+            steps,
+            epsilon,
+        }
+    }
+
+    pub fn miss(distance: f32, steps: usize) -> Hit {
+        Hit {
+            position: [0.0; 3],
+            distance,
+            object: None,
+            steps,
+            epsilon: 0.0,
+        }
+    }
+}
+
+//What a camera ray brought back, along with what it hit first
+#[derive(Copy, Clone)]
+pub struct Sample {
+    pub radiance: Radiance,
+    pub hit: Hit,
+    pub normal: Vector3<f32>, //At the hit, 0 if it missed
+    pub steps: usize, //Of the ray and all its bounces
+    //With several rays per cell, the fraction that hit something, and the direction from the
+    //middle of the cell towards where they hit, in cell widths. 0 for cells that aren't partly covered.
+    pub hits: f32,
+    pub edge: [f32; 2],
+}
+
+impl Sample {
+    pub fn new(radiance: Radiance, hit: Hit, normal: Vector3<f32>, steps: usize) -> Sample {
+        Sample {
+            radiance,
+            hit,
+            normal,
+            steps,
+            hits: if hit.object.is_some() { 1.0 } else { 0.0 },
+            edge: [0.0; 2],
+        }
+    }
+
+    //Infinite for misses
+    pub fn depth(&self) -> f32 {
+        if self.hit.object.is_some() { self.hit.distance } else { f32::INFINITY }
+    }
+}
+
+//The light coming back along a ray. Kept as floats so bounces can be blended
+//before we pick a glyph and a terminal colour for the cell.
+#[derive(Copy, Clone)]
+pub struct Radiance {
+    pub colour: Vector3<f32>, //Linear rgb, 0..1
+    pub intensity: f32, //Drives the glyph choice
+    pub coverage: f32, //0 means the ray missed everything
+}
+
+impl Radiance {
+    pub fn new(colour: Vector3<f32>, intensity: f32) -> Radiance {
+        Radiance {
+            colour,
+            intensity,
+            coverage: 1.0,
+        }
+    }
+
+    pub fn miss() -> Radiance {
+        Radiance {
+            colour: [0.0; 3],
+            intensity: 0.0,
+            coverage: 0.0,
+        }
+    }
+
+    //Linear blend, t = 0 returns self and t = 1 returns other. Keeps the coverage of self,
+    //since a bounce only changes what a surface looks like, not whether there is one
+    pub fn mix(&self, other: Radiance, t: f32) -> Radiance {
+        Radiance {
+            colour: vmath::vec3_add(vmath::vec3_scale(self.colour, 1.0 - t), vmath::vec3_scale(other.colour, t)),
+            intensity: self.intensity * (1.0 - t) + other.intensity * t,
+            coverage: self.coverage,
+        }
+    }
+
+    pub fn to_cell(&self) -> (char, Color) {
+        if self.coverage < 0.5 {
+            return (' ', self.to_colour());
+        }
+
+        // let gradient_string = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789<>|,.-#+!$%&/()=?*'_:;";
+        let gradient_string = ":;1?$X%#@";
+        // let gradient_string = ".'`^\",:;Il!i><~+_-?][}{1)(|\\/tfjrxnuvczXYUJCLQ0OZmwqpdbkhao*#MW&8%B@$";
+        // let gradient_string = ":;=+*#%@";
+        let gradient: Vec<char> = gradient_string.chars().collect();
+        let gradient_idx = (clamp(self.intensity, 0.0, 1.0) * (gradient.len() + 1) as f32) as usize;
+        let gradient_idx = gradient_idx.min(gradient.len() - 1);
+
+        (gradient[gradient_idx], self.to_colour())
+    }
+
+    //Background colour of the cell. Whatever isn't covered by a surface shows the environment.
+    pub fn to_bg(&self) -> Color {
+        if self.coverage >= 1.0 {
+            return Color::Reset;
+        }
+        let colour = vmath::vec3_scale(self.colour, 1.0 - self.coverage);
+        Color::Rgb {
+            r: (clamp(colour[0], 0.0, 1.0) * 255.0) as u8,
+            g: (clamp(colour[1], 0.0, 1.0) * 255.0) as u8,
+            b: (clamp(colour[2], 0.0, 1.0) * 255.0) as u8,
+        }
+    }
+
+    pub fn to_colour(&self) -> Color {
+        Color::Rgb {
+            r: (clamp(self.colour[0], 0.0, 1.0) * 255.0) as u8,
+            g: (clamp(self.colour[1], 0.0, 1.0) * 255.0) as u8,
+            b: (clamp(self.colour[2], 0.0, 1.0) * 255.0) as u8,
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use std::io::{Error, Result};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    mpsc::{channel, Receiver, Sender},
+};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::engine::scene::Scene;
+use super::raymarching::{Radiance, Sample, Hit};
+use super::supersampling;
+
+//Cells per tile. Small enough that threads finishing early can pick up work from the
+//expensive parts of the screen, big enough that grabbing a tile costs nothing in comparison
+pub const TILE_SIZE: (u16, u16) = (16, 8);
+
+#[derive(Copy, Clone)]
+struct Tile {
+    x: u16,
+    y: u16,
+    width: u16,
+    height: u16,
+}
+
+//Everything the workers need for one frame, shared between all of them
+struct Frame {
+    scene: Scene,
+    term_size: (u16, u16),
+    //Where in the cells the rays go through, 0 is the same spot as Scene::generate_ray.
+    //Cells with more than one get the average, see supersampling::combine
+    offsets: Vec<(f32, f32)>,
+    tiles: Vec<Tile>,
+    //Workers take the next tile from here until they run out, so faster tiles don't leave threads idle
+    next_tile: AtomicUsize,
+    //Nanoseconds all workers together spent on tiles
+    busy: AtomicU64,
+}
+
+//Finished tiles go back to render through this, or what went wrong if rendering one panicked.
+//Every worker gets its own, so once they're all done or gone render stops waiting.
+type Results = Sender<(usize, std::result::Result<Vec<Sample>, String>)>;
+type Job = (Arc<Frame>, Results);
+
+//Threads that stay alive between frames and render tiles of the screen.
+//Every tile is rendered into a buffer owned by its worker, and only the main thread puts them together.
+pub struct RenderPool {
+    workers: Vec<(Sender<Job>, thread::JoinHandle<()>)>,
+    //Of the last frame, see busy_time
+    busy: AtomicU64,
+}
+
+impl RenderPool {
+    pub fn new(thread_count: usize) -> RenderPool {
+        let workers = (0..thread_count.max(1)).map(|_| {
+            let (sender, receiver) = channel();
+            let handle = thread::spawn(move || RenderPool::work(receiver));
+            (sender, handle)
+        }).collect();
+
+        RenderPool {
+            workers,
+            busy: AtomicU64::new(0),
+        }
+    }
+
+    //One thread per core, or a single one if we can't find out how many there are
+    pub fn default_thread_count() -> usize {
+        thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+    }
+
+    //Workers that are still running. Panics while rendering are caught, so they only stop when
+    //something else in them breaks.
+    pub fn thread_count(&self) -> usize {
+        self.workers.iter().filter(|(_, handle)| !handle.is_finished()).count()
+    }
+
+    //How long the workers spent rendering the last frame, added up over all of them. Divided by
+    //the time the frame took and the thread count, it's how much of the pool was in use.
+    pub fn busy_time(&self) -> Duration {
+        Duration::from_nanos(self.busy.load(Ordering::Relaxed))
+    }
+
+    fn work(frames: Receiver<Job>) {
+        //Stops once the pool is dropped and the sender goes away
+        while let Ok((frame, results)) = frames.recv() {
+            loop {
+                let idx = frame.next_tile.fetch_add(1, Ordering::Relaxed);
+                let tile = match frame.tiles.get(idx) {
+                    Some(tile) => *tile,
+                    None => break,
+                };
+                let start = Instant::now();
+                //A panic in a custom field only loses the frame, the worker carries on with the next
+                let samples = panic::catch_unwind(AssertUnwindSafe(|| RenderPool::render_tile(&frame, tile)))
+                    .map_err(|payload| {
+                        let message = payload.downcast_ref::<&str>().map(|s| s.to_string()).or_else(|| payload.downcast_ref::<String>().cloned());
+                        message.unwrap_or_else(|| String::from("unknown panic"))
+                    });
+
+                //Before sending, render reads it once every tile is in
+                frame.busy.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+                if results.send((idx, samples)).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn render_tile(frame: &Frame, tile: Tile) -> Vec<Sample> {
+        //Neighbouring cells make coherent packets, so march the whole tile at once.
+        //Samples of the same cell are next to each other, they make even better ones.
+        let per_cell = frame.offsets.len();
+        let rays: Vec<_> = (0..tile.width as usize * tile.height as usize * per_cell).map(|i| {
+            let (cell, offset) = (i / per_cell, frame.offsets[i % per_cell]);
+            let (px, py) = (tile.x as usize + cell % tile.width as usize, tile.y as usize + cell / tile.width as usize);
+            frame.scene.generate_ray_at(frame.term_size, px as f32 + offset.0, py as f32 + offset.1)
+        }).collect();
+        if per_cell == 1 {
+            frame.scene.march_rays(&rays)
+        } else {
+            frame.scene.march_rays(&rays).chunks(per_cell).map(|cell| supersampling::combine(cell, &frame.offsets)).collect()
+        }
+    }
+
+    //Renders the scene from its own camera at the given resolution, with a ray through every
+    //offset of every cell, and returns once every tile is done. The samples are ordered row by row.
+    //Fails if rendering a tile panicked, rather than returning a frame with holes in it.
+    pub fn render(&self, scene: Scene, resolution: (u16, u16), offsets: Vec<(f32, f32)>) -> Result<Vec<Sample>> {
+        let mut tiles = Vec::new();
+        for y in (0..resolution.1).step_by(TILE_SIZE.1 as usize) {
+            for x in (0..resolution.0).step_by(TILE_SIZE.0 as usize) {
+                tiles.push(Tile {
+                    x,
+                    y,
+                    width: TILE_SIZE.0.min(resolution.0 - x),
+                    height: TILE_SIZE.1.min(resolution.1 - y),
+                });
+            }
+        }
+
+        let (sender, receiver) = channel();
+        let frame = Arc::new(Frame {
+            scene,
+            term_size: resolution,
+            offsets,
+            tiles,
+            next_tile: AtomicUsize::new(0),
+            busy: AtomicU64::new(0),
+        });
+        for (worker, _) in &self.workers {
+            //Workers that are gone don't take any tiles, the others still finish the frame
+            let _ = worker.send((Arc::clone(&frame), sender.clone()));
+        }
+        drop(sender);
+
+        let width = resolution.0 as usize;
+        let empty = Sample::new(Radiance::miss(), Hit::miss(0.0, 0), [0.0; 3], 0);
+        let mut samples = vec![empty; width * resolution.1 as usize];
+        for _ in 0..frame.tiles.len() {
+            //Dropping the receiver on the way out stops the workers after the tile they're on
+            let (idx, tile_samples) = match receiver.recv() {
+                Ok((idx, Ok(tile_samples))) => (idx, tile_samples),
+                Ok((_, Err(message))) => return Err(Error::other(format!("Rendering a tile panicked: {}", message))),
+                Err(_) => return Err(Error::other("Every render worker is gone")),
+            };
+            let tile = frame.tiles[idx];
+            for (i, sample) in tile_samples.into_iter().enumerate() {
+                let (x, y) = (tile.x as usize + i % tile.width as usize, tile.y as usize + i / tile.width as usize);
+                samples[y * width + x] = sample;
+            }
+        }
+        self.busy.store(frame.busy.load(Ordering::Relaxed), Ordering::Relaxed);
+        Ok(samples)
+    }
+}
+
+impl Drop for RenderPool {
+    fn drop(&mut self) {
+        for (sender, handle) in self.workers.drain(..) {
+            drop(sender);
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::distance_field::SDF;
+
+    fn scene() -> Scene {
+        let mut scene = Scene::new();
+        scene.push_sdf(SDF::new_plane(-1.0, [255, 255, 255]));
+        scene.push_sdf(SDF::new_sphere([0.5, 0.0, 5.0], 1.0, [255, 0, 0]));
+        scene
+    }
+
+    #[test]
+    fn reassembles_tiles() {
+        let pool = RenderPool::new(3);
+        let scene = scene();
+        //Neither side is a multiple of TILE_SIZE, so the last row and column of tiles are cut short
+        let resolution = (TILE_SIZE.0 * 2 + 5, TILE_SIZE.1 * 3 + 3);
+        let samples = pool.render(scene.clone(), resolution, vec![(0.0, 0.0)]).unwrap();
+        assert_eq!(samples.len(), resolution.0 as usize * resolution.1 as usize);
+
+        for py in 0..resolution.1 {
+            for px in 0..resolution.0 {
+                let expected = scene.march_rays(&[scene.generate_ray(resolution, px, py)])[0];
+                let sample = samples[py as usize * resolution.0 as usize + px as usize];
+                assert_eq!(sample.hit.object, expected.hit.object, "cell {};{}", px, py);
+                assert_eq!(sample.hit.distance, expected.hit.distance, "cell {};{}", px, py);
+            }
+        }
+    }
+
+    #[test]
+    fn survives_panics() {
+        let pool = RenderPool::new(2);
+        let mut scene = scene();
+        let working = scene.clone();
+        let field = Arc::new(|p: [f32; 3]| -> f32 {
+            if p[0] > 1.0 {
+                panic!("custom field broke");
+            }
+            ((p[0] + 2.0).powi(2) + p[1].powi(2) + (p[2] - 5.0).powi(2)).sqrt() - 1.0
+        });
+        scene.push_sdf(SDF::new_custom([0.0; 3], field, [0, 255, 0], [0.0; 3]));
+
+        //Fails the frame instead of leaving holes in it, every time, and the workers live through it
+        let resolution = (TILE_SIZE.0 * 3, TILE_SIZE.1 * 2);
+        for _ in 0..2 {
+            let error = pool.render(scene.clone(), resolution, vec![(0.0, 0.0)]).err().unwrap();
+            assert!(error.to_string().contains("custom field broke"), "{}", error);
+        }
+        assert_eq!(pool.thread_count(), 2);
+
+        //So the next frame without the broken field comes out whole
+        let samples = pool.render(working.clone(), resolution, vec![(0.0, 0.0)]).unwrap();
+        let ray = working.generate_ray(resolution, 0, resolution.1 - 1);
+        assert_eq!(samples[(resolution.1 as usize - 1) * resolution.0 as usize].hit.object, working.march_rays(&[ray])[0].hit.object);
+        assert!(samples.iter().any(|s| s.hit.object == Some(1)));
+    }
+}
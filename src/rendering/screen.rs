@@ -61,15 +61,29 @@ impl Screen {
         self.buffer[pos.1 as usize][pos.0 as usize].2 = bg_col;
     }
 
+    //Fills the screen with cells rendered at a different resolution, ordered row by row.
+    //Every cell of the screen takes the rendered cell it falls in.
+    pub fn set_scaled(&mut self, cells: &[(char, Color, Color)], resolution: (u16, u16)) {
+        let width = resolution.0 as usize;
+        for py in 0..self.size.1 {
+            let y = (py as usize * resolution.1 as usize / self.size.1 as usize) * width;
+            for px in 0..self.size.0 {
+                self.buffer[py as usize][px as usize] = cells[y + px as usize * width / self.size.0 as usize];
+            }
+        }
+    }
+
     //TODO: Error handling lol
     //NOTE: I made it ever so slightly faster on windows, by making it output the entire screen
     //      at once, however, on linux, this completely breaks everything
-    pub fn render(&self) {
+    //Returns how many bytes were written
+    pub fn render(&self) -> usize {
         // stdout()
         //     .execute(terminal::Clear(terminal::ClearType::All)).unwrap();
         stdout()
             .execute(cursor::MoveTo(0,0)).unwrap();
         // let mut s = String::new();
+        let mut bytes = 0;
         for y in 0.. self.size.1 {
             //Following commented line allows for very fast grayscale output, if the type if char and not (char, Color)
             // let mut s: String = self.buffer[y as usize].iter().collect();
@@ -83,10 +97,12 @@ impl Screen {
             // if y < self.size.1 - 1 {
             //     s.push('\n');
             // }
+            bytes += s.len();
             stdout()
                 .execute(Output(s)).unwrap();
         }
         // stdout()
         //     .execute(Output(s)).unwrap();
+        bytes
     }
 }
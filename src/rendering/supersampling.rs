@@ -0,0 +1,178 @@
+extern crate vecmath as vmath;
+
+use crossterm::style::Color;
+
+use super::raymarching::{Radiance, Sample};
+
+//Terminal cells are about twice as high as they are wide
+const CELL_ASPECT: f32 = 2.0;
+//Cells with less of their samples hitting than this are left empty, and those with more get a
+//regular glyph. Everything between gets a line along the edge.
+const EDGE_COVERAGE: (f32, f32) = (0.2, 0.8);
+//tan(22.5 degrees), edges closer than this to horizontal or vertical are drawn straight
+const STRAIGHT_EDGE: f32 = 0.414_213_56;
+
+//Vertical, horizontal and the two diagonal lines, in the order line_glyph takes them
+pub const ASCII_LINES: [char; 4] = ['|', '-', '/', '\\'];
+pub const BOX_LINES: [char; 4] = ['│', '─', '╱', '╲'];
+
+//How many rays go through each cell, and where
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Supersampling {
+    None,
+    //N by N samples spread evenly over the cell
+    Grid(u32),
+    //4 samples on a grid rotated so no two share a row or column, which catches near
+    //horizontal and vertical edges almost as well as 4x4
+    RotatedGrid,
+}
+
+impl Supersampling {
+    //Positions within the cell, -0.5..0.5 with 0 at the spot Scene::generate_ray uses
+    pub fn offsets(&self) -> Vec<(f32, f32)> {
+        match *self {
+            Supersampling::None => vec![(0.0, 0.0)],
+            Supersampling::Grid(n) => {
+                let n = n.max(1);
+                let step = 1.0 / n as f32;
+                (0..n * n).map(|i| (((i % n) as f32 + 0.5) * step - 0.5, ((i / n) as f32 + 0.5) * step - 0.5)).collect()
+            },
+            Supersampling::RotatedGrid => vec![(-0.125, -0.375), (0.375, -0.125), (0.125, 0.375), (-0.375, 0.125)],
+        }
+    }
+}
+
+//Combines the samples of one cell, taken at offsets. Colour and coverage are averaged, the hit
+//and normal are those of the closest one, so depth stays that of whatever is in front.
+pub fn combine(samples: &[Sample], offsets: &[(f32, f32)]) -> Sample {
+    let n = samples.len() as f32;
+    let mut colour = [0.0; 3];
+    let mut intensity = 0.0;
+    let mut coverage = 0.0;
+    let mut steps = 0;
+    let mut front = &samples[0];
+
+    let mut hits = 0.0;
+    let mut centre = (0.0, 0.0);
+    let mut covered = (0.0, 0.0);
+    for (sample, offset) in samples.iter().zip(offsets.iter()) {
+        colour = vmath::vec3_add(colour, sample.radiance.colour);
+        intensity += sample.radiance.intensity;
+        coverage += sample.radiance.coverage;
+        steps += sample.steps;
+
+        centre = (centre.0 + offset.0, centre.1 + offset.1);
+        if sample.hit.object.is_some() {
+            hits += 1.0;
+            covered = (covered.0 + offset.0, covered.1 + offset.1);
+            if front.hit.object.is_none() || sample.hit.distance < front.hit.distance {
+                front = sample;
+            }
+        }
+    }
+
+    //From the middle of all samples to the middle of the ones that hit
+    let edge = if hits > 0.0 && hits < n {
+        [covered.0 / hits - centre.0 / n, (covered.1 / hits - centre.1 / n) * CELL_ASPECT]
+    } else {
+        [0.0, 0.0]
+    };
+
+    Sample {
+        radiance: Radiance {
+            colour: vmath::vec3_scale(colour, 1.0 / n),
+            intensity: intensity / n,
+            coverage: coverage / n,
+        },
+        hit: front.hit,
+        normal: front.normal,
+        steps,
+        hits: hits / n,
+        edge,
+    }
+}
+
+//Glyph that follows the edge through a partly covered cell
+pub fn edge_glyph(hits: f32, edge: [f32; 2]) -> Option<char> {
+    if hits < EDGE_COVERAGE.0 || hits > EDGE_COVERAGE.1 || vmath::vec2_len(edge) < 1e-3 {
+        return None;
+    }
+
+    //edge points at the covered side, the line runs across it. Horizontal lines sit at the
+    //bottom of the cell when the surface is below.
+    let glyphs = if edge[1] > 0.0 { ['|', '_', '/', '\\'] } else { ASCII_LINES };
+    Some(line_glyph(edge[0], edge[1], &glyphs))
+}
+
+//Glyph from glyphs for a line across the direction of (x, y), rows going down the screen
+pub fn line_glyph(x: f32, y: f32, glyphs: &[char; 4]) -> char {
+    if y.abs() <= STRAIGHT_EDGE * x.abs() {
+        glyphs[0]
+    } else if x.abs() <= STRAIGHT_EDGE * y.abs() {
+        glyphs[1]
+    } else if x * y > 0.0 {
+        glyphs[2]
+    } else {
+        glyphs[3]
+    }
+}
+
+//Glyph, colour and background of a cell, with edge glyphs where the samples found one
+pub fn to_cell(sample: &Sample) -> (char, Color, Color) {
+    let radiance = &sample.radiance;
+    match edge_glyph(sample.hits, sample.edge) {
+        Some(glyph) => (glyph, radiance.to_colour(), radiance.to_bg()),
+        None => {
+            let (value, colour) = radiance.to_cell();
+            (value, colour, radiance.to_bg())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rendering::raymarching::Hit;
+
+    fn sample(hit: bool) -> Sample {
+        let radiance = if hit { Radiance::new([1.0, 0.0, 0.0], 0.5) } else { Radiance::miss() };
+        let hit = if hit { Hit::new([0.0; 3], 1.0, 0, 1, 0.01) } else { Hit::miss(64.0, 1) };
+        Sample { radiance, hit, normal: [0.0; 3], steps: 1, hits: 0.0, edge: [0.0; 2] }
+    }
+
+    #[test]
+    fn offsets() {
+        assert_eq!(Supersampling::None.offsets(), vec![(0.0, 0.0)]);
+        assert_eq!(Supersampling::Grid(2).offsets(), vec![(-0.25, -0.25), (0.25, -0.25), (-0.25, 0.25), (0.25, 0.25)]);
+        assert_eq!(Supersampling::Grid(3).offsets().len(), 9);
+        for offsets in [Supersampling::Grid(4).offsets(), Supersampling::RotatedGrid.offsets()] {
+            assert!(offsets.iter().all(|o| o.0.abs() < 0.5 && o.1.abs() < 0.5));
+        }
+    }
+
+    #[test]
+    fn edges() {
+        let offsets = Supersampling::Grid(2).offsets();
+        //Left column hits, so the edge is vertical
+        let cell = combine(&[sample(true), sample(false), sample(true), sample(false)], &offsets);
+        assert_eq!(cell.hits, 0.5);
+        assert_eq!(cell.radiance.coverage, 0.5);
+        assert_eq!(cell.steps, 4);
+        assert_eq!(edge_glyph(cell.hits, cell.edge), Some('|'));
+
+        //Bottom row hits
+        let cell = combine(&[sample(false), sample(false), sample(true), sample(true)], &offsets);
+        assert_eq!(to_cell(&cell).0, '_');
+
+        //Everything but the top right corner, a diagonal with the surface below and to the left
+        let offsets = Supersampling::Grid(3).offsets();
+        let hits: Vec<_> = offsets.iter().map(|o| o.0 - o.1 < 0.3).map(sample).collect();
+        let cell = combine(&hits, &offsets);
+        assert_eq!(edge_glyph(cell.hits, cell.edge), Some('\\'));
+
+        //Fully covered cells keep their regular glyph
+        let cell = combine(&[sample(true), sample(true), sample(true), sample(true)], &Supersampling::Grid(2).offsets());
+        assert_eq!(edge_glyph(cell.hits, cell.edge), None);
+        assert_eq!(to_cell(&cell).0, Radiance::new([1.0, 0.0, 0.0], 0.5).to_cell().0);
+    }
+}